@@ -1,31 +1,145 @@
 //! AdM integration that uses the remote-settings provided data.
 
 use anyhow::{anyhow, Context};
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use futures::StreamExt;
 use http::Uri;
 use lazy_static::lazy_static;
 use merino_settings::{providers::RemoteSettingsConfig, Settings};
 use merino_suggest::{
-    Proportion, SetupError, SuggestError, Suggestion, SuggestionProvider, SuggestionRequest,
-    SuggestionResponse,
+    HealthCheck, LanguageIdentifier, LocationGranularity, Proportion, SetupError, SuggestError,
+    Suggestion, SuggestionProvider, SuggestionRequest, SuggestionResponse,
 };
+use radix_trie::{Trie, TrieCommon};
 use remote_settings_client::client::FileStorage;
 use serde::Deserialize;
 use serde_json::Value;
 use serde_with::{serde_as, DisplayFromStr};
-use std::{collections::HashMap, convert::TryFrom, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    convert::TryFrom,
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
 use tokio::sync::OnceCell;
 
 lazy_static! {
     static ref NON_SPONSORED_IAB_CATEGORIES: Vec<&'static str> = vec!["5 - Education"];
 }
 
+/// A prefix-searchable index from keywords to the suggestions they match.
+/// The suggestions for each keyword are ordered from most to least
+/// specifically targeted.
+type KeywordIndex = Trie<String, Vec<Arc<TargetedSuggestion>>>;
+
+/// All of the suggestions that can be provided.
+#[derive(Debug, Default)]
+struct SuggestionIndex {
+    /// A separate keyword index for each locale that suggestions are provided for.
+    by_locale: HashMap<LanguageIdentifier, KeywordIndex>,
+
+    /// The most specific location targeting of any suggestion.
+    location_granularity: LocationGranularity,
+
+    /// When these suggestions were synced from Remote Settings, if they were.
+    synced_at: Option<Instant>,
+}
+
+/// A suggestion, along with the locations it should be provided in.
+#[derive(Debug)]
+struct TargetedSuggestion {
+    /// The suggestion to provide.
+    suggestion: Suggestion,
+
+    /// Where to provide the suggestion.
+    targeting: LocationTargeting,
+}
+
+/// The locations that a suggestion should be provided in. Empty lists don't
+/// restrict the locations at all.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct LocationTargeting {
+    /// ISO 3166-1 alpha-2 country codes, in uppercase.
+    countries: Vec<String>,
+
+    /// Pairs of country codes and ISO 3166-2 region codes, in uppercase.
+    regions: Vec<(String, String)>,
+}
+
+impl LocationTargeting {
+    /// Parse targeting from lists of country codes such as `US`, and of
+    /// country and region codes such as `US-OR`.
+    fn parse(countries: &[String], regions: &[String]) -> anyhow::Result<Self> {
+        let regions = regions
+            .iter()
+            .map(|code| match code.split_once('-') {
+                Some((country, region)) if !country.is_empty() && !region.is_empty() => {
+                    Ok((country.to_uppercase(), region.to_uppercase()))
+                }
+                _ => Err(anyhow!("Invalid region code {:?}", code)),
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            countries: countries.iter().map(|c| c.to_uppercase()).collect(),
+            regions,
+        })
+    }
+
+    /// Check if the location of `request` is targeted.
+    fn matches(&self, request: &SuggestionRequest) -> bool {
+        let country = request.country.as_deref();
+        let region = request.region.as_deref();
+
+        let country_matches = self.countries.is_empty()
+            || match country {
+                Some(country) => self
+                    .countries
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(country)),
+                None => false,
+            };
+        let region_matches = self.regions.is_empty()
+            || match (country, region) {
+                (Some(country), Some(region)) => self.regions.iter().any(|(c, r)| {
+                    c.eq_ignore_ascii_case(country) && r.eq_ignore_ascii_case(region)
+                }),
+                _ => false,
+            };
+
+        country_matches && region_matches
+    }
+
+    /// The location information needed to check this targeting.
+    fn granularity(&self) -> LocationGranularity {
+        if !self.regions.is_empty() {
+            LocationGranularity::Region
+        } else if !self.countries.is_empty() {
+            LocationGranularity::Country
+        } else {
+            LocationGranularity::None
+        }
+    }
+}
+
 /// Make suggestions based on data in Remote Settings
 #[derive(Default, Debug)]
 pub struct RemoteSettingsSuggester {
-    /// A map from keywords to suggestions that can be provided.
-    suggestions: HashMap<String, Arc<Suggestion>>,
+    /// Indexes from keywords to suggestions that can be provided. The whole
+    /// index is swapped out when a re-sync completes, so that in-flight
+    /// requests are never blocked.
+    suggestions: Arc<ArcSwap<SuggestionIndex>>,
+
+    /// The minimum length, in characters, a query must be to be matched as a
+    /// prefix of a keyword. Shorter queries only match keywords exactly.
+    min_prefix_length: usize,
+
+    /// The expected time between re-syncs. Suggestions that have not been
+    /// re-synced for twice this long are reported as unhealthy.
+    resync_interval: Duration,
 }
 
 /// A lazy version of the server settings for the default Remote Settings server.
@@ -34,44 +148,109 @@ static REMOTE_SETTINGS_SERVER_INFO: OnceCell<RemoteSettingsServerInfo> = OnceCel
 
 impl RemoteSettingsSuggester {
     /// Make and sync a new suggester.
+    ///
+    /// This also spawns a background task that re-syncs the suggestions every
+    /// `config.resync_interval`. The task stops once the suggester is dropped.
     pub async fn new_boxed(
         settings: &Settings,
         config: &RemoteSettingsConfig,
     ) -> Result<Box<Self>, SetupError> {
-        let mut provider = Self {
-            suggestions: HashMap::new(),
+        if config.resync_interval.is_zero() {
+            return Err(SetupError::InvalidConfiguration(anyhow!(
+                "Remote Settings resync_interval must be greater than zero"
+            )));
+        }
+
+        let provider = Self {
+            min_prefix_length: config.min_prefix_length,
+            resync_interval: config.resync_interval,
+            ..Self::default()
         };
         provider.sync(settings, config).await?;
+
+        {
+            let task_suggestions = Arc::downgrade(&provider.suggestions);
+            let task_settings = settings.clone();
+            let task_config = config.clone();
+            tokio::spawn(async move {
+                let mut timer = tokio::time::interval(task_config.resync_interval);
+                // The timer fires immediately, but we just synced, so wait
+                // one tick before starting the loop.
+                timer.tick().await;
+                loop {
+                    timer.tick().await;
+                    if !Self::resync(&task_suggestions, &task_settings, &task_config).await {
+                        break;
+                    }
+                }
+            });
+        }
+
         Ok(Box::new(provider))
     }
 
     /// Download suggestions from Remote Settings
     ///
     /// This must be called at least once before any suggestions will be provided
-    #[tracing::instrument(skip(self, settings))]
     pub async fn sync(
-        &mut self,
+        &self,
         settings: &Settings,
         config: &RemoteSettingsConfig,
     ) -> Result<(), SetupError> {
-        tracing::info!(
-            r#type = "adm.remote-settings.sync-start",
-            "Syncing quicksuggest records from Remote Settings"
-        );
-        let reqwest_client = reqwest::Client::new();
+        let suggestions = Self::fetch_suggestions(settings, config).await?;
+        self.suggestions.store(Arc::new(suggestions));
+        Ok(())
+    }
 
-        // Set up and sync a Remote Settings client for the quicksuggest collection.
-        std::fs::create_dir_all(&settings.remote_settings.storage_path)
+    /// Re-sync the suggestions pointed to by `suggestions`, if they still exist.
+    ///
+    /// If the sync fails, the previous suggestions are kept. Returns `false` if
+    /// the suggester that owns `suggestions` has been dropped, which means that
+    /// there is nothing left to re-sync.
+    ///
+    /// This is a selfless method so that it can be called from a spawned Tokio task.
+    async fn resync(
+        suggestions: &Weak<ArcSwap<SuggestionIndex>>,
+        settings: &Settings,
+        config: &RemoteSettingsConfig,
+    ) -> bool {
+        let suggestions = match suggestions.upgrade() {
+            Some(suggestions) => suggestions,
+            None => return false,
+        };
+
+        match Self::fetch_suggestions(settings, config).await {
+            Ok(new_suggestions) => suggestions.store(Arc::new(new_suggestions)),
+            Err(error) => tracing::error!(
+                r#type = "adm.remote-settings.resync-error",
+                %error,
+                "Could not re-sync suggestions from Remote Settings, keeping previous suggestions"
+            ),
+        }
+        true
+    }
+
+    /// Sync the Remote Settings `collection` into the file cache at
+    /// `storage_path`, and get its records.
+    ///
+    /// This blocks while doing IO, so it should not be called on the async
+    /// runtime.
+    fn fetch_records(
+        storage_path: PathBuf,
+        server: Option<String>,
+        collection: &str,
+    ) -> Result<Vec<SuggestRecord>, SetupError> {
+        std::fs::create_dir_all(&storage_path)
             .context("Creating RemoteSettings file cache")
             .map_err(SetupError::Io)?;
         let mut rs_client = {
             let mut rs_client_builder = remote_settings_client::Client::builder()
-                .collection_name(&config.collection)
+                .collection_name(collection)
                 .storage(Box::new(FileStorage {
-                    folder: settings.remote_settings.storage_path.clone(),
+                    folder: storage_path,
                     ..Default::default()
                 }));
-            if let Some(server) = &settings.remote_settings.server {
+            if let Some(server) = &server {
                 rs_client_builder = rs_client_builder.server_url(server);
             }
             rs_client_builder
@@ -80,21 +259,13 @@ impl RemoteSettingsSuggester {
                 .map_err(SetupError::InvalidConfiguration)?
         };
 
-        // `.sync()` blocks while doing IO
         rs_client
             .sync(None)
             .context("Syncing suggestions from remote settings")
             .map_err(SetupError::Network)?;
 
-        // Get the base URL to download attachments from.
-        let attachment_base_url = &REMOTE_SETTINGS_SERVER_INFO
-            .get_or_try_init(|| RemoteSettingsServerInfo::fetch(&reqwest_client))
-            .await?
-            .attachment_base_url()?;
-
-        // Get records from Remote Settings, and convert them into a schema instead of using JSON `Value`s.
-        let records: Vec<SuggestRecord> = rs_client
-            // `.get()` blocks while doing IO
+        // Convert the records into a schema instead of using JSON `Value`s.
+        rs_client
             .get()
             .context("Fetching records from remote settings")
             .map_err(SetupError::Network)?
@@ -106,7 +277,47 @@ impl RemoteSettingsSuggester {
             })
             .collect::<Result<_, <Value as serde::Deserializer>::Error>>()
             .context("Parsing suggestions records")
-            .map_err(SetupError::Format)?;
+            .map_err(SetupError::Format)
+    }
+
+    /// Download suggestions from Remote Settings, and build an index of
+    /// keyword to suggestion from them for each locale.
+    #[tracing::instrument(skip(settings))]
+    async fn fetch_suggestions(
+        settings: &Settings,
+        config: &RemoteSettingsConfig,
+    ) -> Result<SuggestionIndex, SetupError> {
+        let default_locales = config
+            .default_locales
+            .iter()
+            .map(|tag| tag.parse::<LanguageIdentifier>())
+            .collect::<Result<Vec<_>, _>>()
+            .context("Parsing default locales")
+            .map_err(SetupError::InvalidConfiguration)?;
+
+        tracing::info!(
+            r#type = "adm.remote-settings.sync-start",
+            "Syncing quicksuggest records from Remote Settings"
+        );
+        let reqwest_client = reqwest::Client::new();
+
+        // The Remote Settings client blocks while doing IO, so it is used on a
+        // thread where that won't hold up other tasks.
+        let storage_path = settings.remote_settings.storage_path.clone();
+        let server = settings.remote_settings.server.clone();
+        let collection = config.collection.clone();
+        let records = tokio::task::spawn_blocking(move || {
+            Self::fetch_records(storage_path, server, &collection)
+        })
+        .await
+        .context("Remote Settings sync task failed")
+        .map_err(SetupError::Network)??;
+
+        // Get the base URL to download attachments from.
+        let attachment_base_url = &REMOTE_SETTINGS_SERVER_INFO
+            .get_or_try_init(|| RemoteSettingsServerInfo::fetch(&reqwest_client))
+            .await?
+            .attachment_base_url()?;
 
         // Sort records by type
         let mut records_by_type: HashMap<&str, Vec<&SuggestRecord>> =
@@ -130,17 +341,44 @@ impl RemoteSettingsSuggester {
             })
             .collect();
 
-        // The suggestion options are stored in attachments instead of directly in the RS records.
-        let suggestion_attachment_metas: Vec<_> = records_by_type
-            .entry("data")
-            .or_default()
-            .iter()
-            .flat_map(|r| r.attachment.as_ref())
-            .collect();
+        // The suggestion options are stored in attachments instead of directly
+        // in the RS records. Each record may list the locales and locations it
+        // is for.
+        let mut suggestion_attachment_metas = Vec::new();
+        for record in records_by_type.entry("data").or_default().iter() {
+            let attachment_meta = match &record.attachment {
+                Some(attachment_meta) => attachment_meta,
+                None => continue,
+            };
+            let locales = if record.locales.is_empty() {
+                default_locales.clone()
+            } else {
+                match record
+                    .locales
+                    .iter()
+                    .map(|tag| tag.parse::<LanguageIdentifier>())
+                    .collect::<Result<Vec<_>, _>>()
+                {
+                    Ok(locales) => locales,
+                    Err(error) => {
+                        tracing::warn!(record_id = %record.id, %error, "Suggestion record has invalid locales");
+                        continue;
+                    }
+                }
+            };
+            let targeting = match LocationTargeting::parse(&record.countries, &record.regions) {
+                Ok(targeting) => targeting,
+                Err(error) => {
+                    tracing::warn!(record_id = %record.id, %error, "Suggestion record has invalid location targeting");
+                    continue;
+                }
+            };
+            suggestion_attachment_metas.push((attachment_meta, locales, targeting));
+        }
 
         // Download all the attachments concurrently
         let mut suggestion_attachments = futures::stream::FuturesUnordered::new();
-        for attachment_meta in suggestion_attachment_metas {
+        for (attachment_meta, locales, targeting) in suggestion_attachment_metas {
             let reqwest_client = &reqwest_client;
             let url = format!("{}{}", attachment_base_url, attachment_meta.location);
             suggestion_attachments.push(async move {
@@ -156,15 +394,22 @@ impl RemoteSettingsSuggester {
                     .await
                     .context("Parsing suggestions")
                     .map_err(SetupError::Format)?;
-                Result::<Vec<AdmSuggestion>, SetupError>::Ok(rv)
+                Result::<_, SetupError>::Ok((rv, locales, targeting))
             });
         }
 
-        // Convert the collection of adM suggestion attachments into a lookup
-        // table of keyword -> merino suggestion.
-        let mut suggestions = HashMap::new();
+        // Convert the collection of adM suggestion attachments into lookup
+        // tables of keyword -> merino suggestion for each locale.
+        let mut suggestions = SuggestionIndex {
+            synced_at: Some(Instant::now()),
+            ..SuggestionIndex::default()
+        };
         while let Some(attachment) = suggestion_attachments.next().await {
-            for adm_suggestion in attachment? {
+            let (adm_suggestions, locales, targeting) = attachment?;
+            suggestions.location_granularity = suggestions
+                .location_granularity
+                .max(targeting.granularity());
+            for adm_suggestion in adm_suggestions {
                 if adm_suggestion.keywords.is_empty() {
                     continue;
                 }
@@ -191,39 +436,53 @@ impl RemoteSettingsSuggester {
                     .expect("No keywords?")
                     .clone();
 
-                let merino_suggestion = Arc::new(Suggestion {
-                    id: adm_suggestion.id,
-                    title: adm_suggestion.title.clone(),
-                    url: adm_suggestion.url.clone(),
-                    impression_url: adm_suggestion.impression_url,
-                    click_url: adm_suggestion.click_url,
-                    full_keyword,
-                    provider: adm_suggestion.advertiser,
-                    is_sponsored: !NON_SPONSORED_IAB_CATEGORIES
-                        .contains(&adm_suggestion.iab_category.as_str()),
-                    icon: icon_url,
-                    score: Proportion::from(0.2),
+                let merino_suggestion = Arc::new(TargetedSuggestion {
+                    suggestion: Suggestion {
+                        id: adm_suggestion.id,
+                        title: adm_suggestion.title.clone(),
+                        url: adm_suggestion.url.clone(),
+                        impression_url: adm_suggestion.impression_url,
+                        click_url: adm_suggestion.click_url,
+                        full_keyword,
+                        provider: adm_suggestion.advertiser,
+                        is_sponsored: !NON_SPONSORED_IAB_CATEGORIES
+                            .contains(&adm_suggestion.iab_category.as_str()),
+                        icon: icon_url,
+                        score: Proportion::from(0.2),
+                    },
+                    targeting: targeting.clone(),
                 });
-                for keyword in adm_suggestion.keywords {
-                    suggestions.insert(keyword, merino_suggestion.clone());
+                for locale in &locales {
+                    let keyword_index = suggestions.by_locale.entry(locale.clone()).or_default();
+                    for keyword in &adm_suggestion.keywords {
+                        match keyword_index.get_mut(keyword) {
+                            Some(entries) => {
+                                entries.push(merino_suggestion.clone());
+                                entries.sort_by_key(|entry| Reverse(entry.targeting.granularity()));
+                            }
+                            None => {
+                                keyword_index
+                                    .insert(keyword.clone(), vec![merino_suggestion.clone()]);
+                            }
+                        }
+                    }
                 }
             }
         }
 
-        if suggestions.is_empty() {
+        if suggestions.by_locale.is_empty() {
             tracing::warn!(
                 r#type = "adm.remote-settings.empty",
                 "No suggestion records found on Remote Settings"
             );
         }
 
-        self.suggestions = suggestions;
         tracing::info!(
             r#type = "adm.remote-settings.sync-done",
             "Completed syncing quicksuggest records from Remote Settings"
         );
 
-        Ok(())
+        Ok(suggestions)
     }
 }
 
@@ -237,17 +496,79 @@ impl SuggestionProvider for RemoteSettingsSuggester {
         &self,
         request: SuggestionRequest,
     ) -> Result<SuggestionResponse, SuggestError> {
-        let suggestions = if request.accepts_english {
-            match self.suggestions.get(&request.query) {
-                Some(suggestion) => vec![suggestion.as_ref().clone()],
-                _ => vec![],
-            }
-        } else {
-            vec![]
+        let index = self.suggestions.load();
+        let suggestions = match request.preferred_locale(index.by_locale.keys()) {
+            Some(locale) => self
+                .lookup(&index.by_locale[locale], &request)
+                .into_iter()
+                .collect(),
+            None => vec![],
         };
 
         Ok(SuggestionResponse::new(suggestions))
     }
+
+    fn supported_locales(&self) -> Vec<LanguageIdentifier> {
+        self.suggestions.load().by_locale.keys().cloned().collect()
+    }
+
+    fn location_granularity(&self) -> LocationGranularity {
+        self.suggestions.load().location_granularity
+    }
+
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        let check = match self.suggestions.load().synced_at {
+            None => HealthCheck::error(self.name(), "Suggestions have never been synced"),
+            Some(synced_at) if synced_at.elapsed() > self.resync_interval * 2 => HealthCheck::warn(
+                self.name(),
+                format!(
+                    "Suggestions were last synced {} seconds ago",
+                    synced_at.elapsed().as_secs()
+                ),
+            ),
+            Some(_) => HealthCheck::ok(self.name()),
+        };
+        vec![check]
+    }
+}
+
+impl RemoteSettingsSuggester {
+    /// Find the suggestion in `keywords` that matches the query and location
+    /// of `request`, if any.
+    ///
+    /// A keyword that is exactly equal to the query is preferred. Otherwise, if
+    /// the query is at least `min_prefix_length` characters long, the shortest
+    /// keyword that the query is a prefix of is used, and the returned
+    /// suggestion's `full_keyword` is set to that keyword. Of the suggestions
+    /// for a keyword, the most specifically targeted one is used.
+    fn lookup(&self, keywords: &KeywordIndex, request: &SuggestionRequest) -> Option<Suggestion> {
+        let query = request.query.as_str();
+        let targeted = |entries: &Vec<Arc<TargetedSuggestion>>| {
+            entries
+                .iter()
+                .find(|entry| entry.targeting.matches(request))
+                .cloned()
+        };
+
+        if let Some(entry) = keywords.get(query).and_then(targeted) {
+            return Some(entry.suggestion.clone());
+        }
+
+        if query.is_empty() || query.chars().count() < self.min_prefix_length {
+            return None;
+        }
+
+        keywords
+            .get_raw_descendant(query)?
+            .iter()
+            .filter(|(keyword, _)| keyword.starts_with(query))
+            .filter_map(|(keyword, entries)| Some((keyword, targeted(entries)?)))
+            .min_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)))
+            .map(|(keyword, entry)| Suggestion {
+                full_keyword: keyword.clone(),
+                ..entry.suggestion.clone()
+            })
+    }
 }
 
 /// Remote Settings server info
@@ -319,6 +640,21 @@ struct SuggestRecord {
     /// The type of the record. Expected to be "data" or "icon".
     #[serde(rename = "type")]
     record_type: String,
+
+    /// The locales that the suggestions in a "data" record are for. If empty,
+    /// the configured default locales are used.
+    #[serde(default)]
+    locales: Vec<String>,
+
+    /// The countries, such as `US`, that the suggestions in a "data" record
+    /// should be provided in. If empty, they are provided in every country.
+    #[serde(default)]
+    countries: Vec<String>,
+
+    /// The regions, such as `US-OR`, that the suggestions in a "data" record
+    /// should be provided in. If empty, they are provided in every region.
+    #[serde(default)]
+    regions: Vec<String>,
 }
 
 /// The metadata of an attachment that might be associated with a Remote Settings record.
@@ -356,33 +692,83 @@ struct AdmSuggestion {
 mod tests {
     use super::*;
     use fake::{Fake, Faker};
-    use merino_suggest::{Suggestion, SuggestionProvider};
+    use merino_suggest::{HealthStatus, Suggestion, SuggestionProvider};
+
+    /// The locale that the test suggestions are provided for.
+    fn en() -> LanguageIdentifier {
+        "en".parse().unwrap()
+    }
+
+    /// Make a keyword index with a suggestion for each of `keywords`, with
+    /// titles that start with `title_prefix`.
+    fn index_with_keywords(title_prefix: &str, keywords: &[&str]) -> KeywordIndex {
+        let mut suggestions = Trie::new();
+        for keyword in keywords {
+            suggestions.insert(
+                keyword.to_string(),
+                vec![Arc::new(TargetedSuggestion {
+                    suggestion: Suggestion {
+                        title: format!("{} {}", title_prefix, keyword),
+                        full_keyword: keyword.to_string(),
+                        ..Faker.fake()
+                    },
+                    targeting: LocationTargeting::default(),
+                })],
+            );
+        }
+        suggestions
+    }
+
+    /// Make a suggester with the given keyword index for each locale.
+    fn suggester_with_locales(
+        indexes: Vec<(LanguageIdentifier, KeywordIndex)>,
+    ) -> RemoteSettingsSuggester {
+        let by_locale: HashMap<_, _> = indexes.into_iter().collect();
+        let location_granularity = by_locale
+            .values()
+            .flat_map(|keywords| keywords.values())
+            .flatten()
+            .map(|entry| entry.targeting.granularity())
+            .max()
+            .unwrap_or_default();
+        RemoteSettingsSuggester {
+            suggestions: Arc::new(ArcSwap::from_pointee(SuggestionIndex {
+                by_locale,
+                location_granularity,
+                synced_at: Some(Instant::now()),
+            })),
+            min_prefix_length: 3,
+            resync_interval: Duration::from_secs(60),
+        }
+    }
+
+    /// Make a suggester that has an English suggestion for each of `keywords`.
+    fn suggester_with_keywords(keywords: &[&str]) -> RemoteSettingsSuggester {
+        suggester_with_locales(vec![(en(), index_with_keywords("Title for", keywords))])
+    }
+
+    /// Look up `query` in the English suggestions of `rs_suggester`.
+    fn lookup_en(rs_suggester: &RemoteSettingsSuggester, query: &str) -> Option<Suggestion> {
+        rs_suggester.lookup(
+            &rs_suggester.suggestions.load().by_locale[&en()],
+            &request_with_locales(query, &["en"]),
+        )
+    }
+
+    /// Make a request for `query` that accepts `locales`.
+    fn request_with_locales(query: &str, locales: &[&str]) -> SuggestionRequest {
+        SuggestionRequest {
+            query: query.into(),
+            accepted_locales: locales.iter().map(|l| l.parse().unwrap()).collect(),
+            ..Faker.fake()
+        }
+    }
 
     #[actix_rt::test]
     async fn english_is_supported_example() -> anyhow::Result<()> {
-        let mut suggestions = HashMap::new();
-        suggestions.insert(
-            "sheep".to_string(),
-            Arc::new(Suggestion {
-                title: "Wikipedia - Sheep".to_string(),
-                url: Uri::from_static("https://en.wikipedia.org/wiki/Sheep"),
-                id: 1,
-                full_keyword: "sheep".to_string(),
-                impression_url: Uri::from_static("https://127.0.0.1"),
-                click_url: Uri::from_static("https://127.0.0.1"),
-                provider: "test".to_string(),
-                is_sponsored: false,
-                icon: Uri::from_static("https://en.wikipedia.org/favicon.ico"),
-                score: Proportion::zero(),
-            }),
-        );
-        let rs_suggester = RemoteSettingsSuggester { suggestions };
+        let rs_suggester = suggester_with_keywords(&["sheep"]);
 
-        let request = SuggestionRequest {
-            query: "sheep".into(),
-            accepts_english: true,
-            ..Faker.fake()
-        };
+        let request = request_with_locales("sheep", &["en-US"]);
 
         assert_eq!(
             rs_suggester
@@ -392,7 +778,7 @@ mod tests {
                 .iter()
                 .map(|s| &s.title)
                 .collect::<Vec<_>>(),
-            vec!["Wikipedia - Sheep"]
+            vec!["Title for sheep"]
         );
 
         Ok(())
@@ -400,32 +786,220 @@ mod tests {
 
     #[actix_rt::test]
     async fn english_is_unsupported_example() -> anyhow::Result<()> {
-        let mut suggestions = HashMap::new();
-        suggestions.insert(
-            "sheep".to_string(),
-            Arc::new(Suggestion {
-                title: "Wikipedia - Sheep".to_string(),
-                url: Uri::from_static("https://en.wikipedia.org/wiki/Sheep"),
-                id: 1,
+        let rs_suggester = suggester_with_keywords(&["sheep"]);
+
+        let request = request_with_locales("sheep", &["fr"]);
+
+        assert!(rs_suggester.suggest(request).await?.suggestions.is_empty());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn most_preferred_supported_locale_is_used() -> anyhow::Result<()> {
+        let rs_suggester = suggester_with_locales(vec![
+            (en(), index_with_keywords("English", &["sheep"])),
+            ("fr".parse()?, index_with_keywords("French", &["sheep"])),
+        ]);
+
+        let titles = |response: SuggestionResponse| -> Vec<String> {
+            response.suggestions.into_iter().map(|s| s.title).collect()
+        };
+
+        let request = request_with_locales("sheep", &["de", "fr-CA", "en"]);
+        assert_eq!(
+            titles(rs_suggester.suggest(request).await?),
+            vec!["French sheep"]
+        );
+
+        let request = request_with_locales("sheep", &["en-GB", "fr"]);
+        assert_eq!(
+            titles(rs_suggester.suggest(request).await?),
+            vec!["English sheep"]
+        );
+
+        let mut supported = rs_suggester.supported_locales();
+        supported.sort_by_key(ToString::to_string);
+        assert_eq!(supported, vec![en(), "fr".parse()?]);
+
+        Ok(())
+    }
+
+    /// Make a suggestion for "sheep" with `title`, targeted to `countries`
+    /// and `regions`.
+    fn targeted_sheep(
+        title: &str,
+        countries: &[&str],
+        regions: &[&str],
+    ) -> Arc<TargetedSuggestion> {
+        let to_strings = |codes: &[&str]| codes.iter().map(ToString::to_string).collect::<Vec<_>>();
+        Arc::new(TargetedSuggestion {
+            suggestion: Suggestion {
+                title: title.to_string(),
                 full_keyword: "sheep".to_string(),
-                impression_url: Uri::from_static("https://127.0.0.1"),
-                click_url: Uri::from_static("https://127.0.0.1"),
-                provider: "test".to_string(),
-                is_sponsored: false,
-                icon: Uri::from_static("https://en.wikipedia.org/favicon.ico"),
-                score: Proportion::zero(),
-            }),
+                ..Faker.fake()
+            },
+            targeting: LocationTargeting::parse(&to_strings(countries), &to_strings(regions))
+                .expect("bad targeting"),
+        })
+    }
+
+    /// Make a request for "sheep" from the given location.
+    fn sheep_request_from(country: Option<&str>, region: Option<&str>) -> SuggestionRequest {
+        SuggestionRequest {
+            country: country.map(ToString::to_string),
+            region: region.map(ToString::to_string),
+            ..request_with_locales("sheep", &["en"])
+        }
+    }
+
+    #[actix_rt::test]
+    async fn most_specifically_targeted_suggestion_is_used() -> anyhow::Result<()> {
+        let mut entries = vec![
+            targeted_sheep("Everywhere", &[], &[]),
+            targeted_sheep("United States", &["US"], &[]),
+            targeted_sheep("Oregon", &[], &["US-OR"]),
+        ];
+        entries.sort_by_key(|entry| Reverse(entry.targeting.granularity()));
+        let mut keywords = Trie::new();
+        keywords.insert("sheep".to_string(), entries);
+        let rs_suggester = suggester_with_locales(vec![(en(), keywords)]);
+
+        assert_eq!(
+            rs_suggester.location_granularity(),
+            LocationGranularity::Region
         );
-        let rs_suggester = RemoteSettingsSuggester { suggestions };
 
-        let request = SuggestionRequest {
-            query: "sheep".into(),
-            accepts_english: false,
-            ..Faker.fake()
-        };
+        for (country, region, expected) in &[
+            (Some("US"), Some("OR"), "Oregon"),
+            (Some("us"), Some("WA"), "United States"),
+            (Some("CA"), Some("ON"), "Everywhere"),
+            (None, None, "Everywhere"),
+        ] {
+            let response = rs_suggester
+                .suggest(sheep_request_from(*country, *region))
+                .await?;
+            assert_eq!(response.suggestions.len(), 1);
+            assert_eq!(&response.suggestions[0].title, expected, "{:?}", country);
+        }
 
-        assert!(rs_suggester.suggest(request).await?.suggestions.is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn targeted_suggestions_are_not_provided_elsewhere() -> anyhow::Result<()> {
+        let mut keywords = Trie::new();
+        keywords.insert(
+            "sheep".to_string(),
+            vec![targeted_sheep("United States", &["US"], &[])],
+        );
+        let rs_suggester = suggester_with_locales(vec![(en(), keywords)]);
+
+        assert_eq!(
+            rs_suggester.location_granularity(),
+            LocationGranularity::Country
+        );
+        let response = rs_suggester
+            .suggest(sheep_request_from(Some("CA"), None))
+            .await?;
+        assert!(response.suggestions.is_empty());
 
         Ok(())
     }
+
+    #[test]
+    fn location_targeting_is_parsed() {
+        let targeting =
+            LocationTargeting::parse(&["us".to_string()], &["us-or".to_string()]).unwrap();
+        assert_eq!(
+            targeting,
+            LocationTargeting {
+                countries: vec!["US".to_string()],
+                regions: vec![("US".to_string(), "OR".to_string())],
+            }
+        );
+
+        assert!(LocationTargeting::parse(&[], &["OR".to_string()]).is_err());
+        assert!(LocationTargeting::parse(&[], &["US-".to_string()]).is_err());
+    }
+
+    #[test]
+    fn prefix_matches_fill_in_full_keyword() {
+        let rs_suggester = suggester_with_keywords(&["sheepdog", "sheep", "shepherd"]);
+
+        let suggestion = lookup_en(&rs_suggester, "shee").expect("should match a keyword");
+        assert_eq!(suggestion.full_keyword, "sheep");
+        assert_eq!(suggestion.title, "Title for sheep");
+
+        let suggestion = lookup_en(&rs_suggester, "sheepd").expect("should match a keyword");
+        assert_eq!(suggestion.full_keyword, "sheepdog");
+        assert_eq!(suggestion.title, "Title for sheepdog");
+    }
+
+    #[test]
+    fn exact_matches_are_preferred() {
+        let rs_suggester = suggester_with_keywords(&["she", "sheep"]);
+
+        let suggestion = lookup_en(&rs_suggester, "she").expect("should match a keyword");
+        assert_eq!(suggestion.full_keyword, "she");
+    }
+
+    #[test]
+    fn short_prefixes_do_not_match() {
+        let rs_suggester = suggester_with_keywords(&["sheep"]);
+
+        assert!(lookup_en(&rs_suggester, "sh").is_none());
+        assert!(lookup_en(&rs_suggester, "").is_none());
+        assert!(lookup_en(&rs_suggester, "sheeps").is_none());
+        assert!(lookup_en(&rs_suggester, "goat").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn resync_stops_when_suggester_is_dropped() {
+        let settings = Settings::load_for_tests();
+        let rs_suggester = RemoteSettingsSuggester::default();
+        let task_suggestions = Arc::downgrade(&rs_suggester.suggestions);
+        drop(rs_suggester);
+
+        assert!(
+            !RemoteSettingsSuggester::resync(
+                &task_suggestions,
+                &settings,
+                &RemoteSettingsConfig::default()
+            )
+            .await
+        );
+    }
+
+    #[actix_rt::test]
+    async fn zero_resync_interval_is_rejected() {
+        let settings = Settings::load_for_tests();
+        let config = RemoteSettingsConfig {
+            resync_interval: Duration::ZERO,
+            ..RemoteSettingsConfig::default()
+        };
+        let result = RemoteSettingsSuggester::new_boxed(&settings, &config).await;
+        assert!(matches!(result, Err(SetupError::InvalidConfiguration(_))));
+    }
+
+    #[actix_rt::test]
+    async fn health_check_reports_sync_age() {
+        let rs_suggester = suggester_with_keywords(&["sheep"]);
+        let checks = rs_suggester.health_checks().await;
+        assert_eq!(checks, vec![HealthCheck::ok("AdmRemoteSettings")]);
+
+        let rs_suggester = RemoteSettingsSuggester {
+            resync_interval: Duration::from_millis(1),
+            ..suggester_with_keywords(&["sheep"])
+        };
+        std::thread::sleep(Duration::from_millis(5));
+        let checks = rs_suggester.health_checks().await;
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].status, HealthStatus::Warn);
+
+        let rs_suggester = RemoteSettingsSuggester::default();
+        let checks = rs_suggester.health_checks().await;
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].status, HealthStatus::Error);
+    }
 }
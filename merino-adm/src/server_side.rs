@@ -1,9 +1,166 @@
 //! AdM integration that uses adM's server-side API to retrieve suggestions to
 //! provide to Firefox.
 
+use anyhow::Context;
+use async_trait::async_trait;
 use http::Uri;
+use lazy_static::lazy_static;
+use merino_settings::providers::AdmServerSideConfig;
+use merino_suggest::{
+    device_info, LanguageIdentifier, LocationGranularity, Proportion, SetupError, SuggestError,
+    SuggestionProvider, SuggestionRequest, SuggestionResponse,
+};
 use serde_derive::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
+use std::convert::TryFrom;
+
+lazy_static! {
+    /// The locales that adM provides suggestions for.
+    static ref SUPPORTED_LOCALES: Vec<LanguageIdentifier> = vec![LanguageIdentifier::Locale {
+        language: "en".to_string(),
+        region: None,
+    }];
+}
+
+/// Make suggestions using adM's server-side suggestion API.
+pub struct AdmServerSideSuggester {
+    /// The HTTP client to use to call the API. It is configured with the
+    /// request timeout.
+    client: reqwest::Client,
+
+    /// The configuration to build requests from.
+    config: AdmServerSideConfig,
+
+    /// The base URL of the API, either from the config or for the partner.
+    base_url: Uri,
+
+    /// The score to give suggestions, since adM does not provide one.
+    score: Proportion,
+}
+
+impl AdmServerSideSuggester {
+    /// Create a boxed suggester from the provider config.
+    ///
+    /// # Errors
+    /// If the HTTP client cannot be created, the partner code can't be used in
+    /// a URL, or the score is not between 0 and 1.
+    pub fn new_boxed(config: &AdmServerSideConfig) -> Result<Box<Self>, SetupError> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("Building adM HTTP client")
+            .map_err(SetupError::InvalidConfiguration)?;
+
+        let base_url = match &config.endpoint_url {
+            Some(endpoint_url) => endpoint_url.clone(),
+            None => partner_base_url(&config.partner)
+                .with_context(|| format!("Invalid adM partner code {:?}", config.partner))
+                .map_err(SetupError::InvalidConfiguration)?,
+        };
+
+        let score = Proportion::try_from(config.score)
+            .context("Invalid adM suggestion score")
+            .map_err(SetupError::InvalidConfiguration)?;
+
+        Ok(Box::new(Self {
+            client,
+            config: config.clone(),
+            base_url,
+            score,
+        }))
+    }
+
+    /// Build the parameters for the adM API from a suggestion request.
+    ///
+    /// Returns `None` if the request does not have the location information
+    /// that the API requires, or if the query is too short to be sent.
+    fn endpoint_parameters(
+        &self,
+        request: &SuggestionRequest,
+    ) -> Option<SuggestionEndpointParameters> {
+        if request.query.chars().count() < 2 {
+            return None;
+        }
+        let country_code = request.country.clone()?;
+        let region_code = request.region.clone()?;
+        let dma_code = match (country_code.as_str(), &request.city) {
+            ("US", Some(_)) => request.dma.map(u32::from),
+            _ => None,
+        };
+
+        Some(SuggestionEndpointParameters {
+            partner: self.config.partner.clone(),
+            query_term: request.query.clone(),
+            api_version: self.config.api_version.clone(),
+            country_code,
+            region_code,
+            city: request.city.clone(),
+            dma_code,
+            form_factor: (&request.device_info.form_factor).into(),
+            os_family: (&request.device_info.os_family).into(),
+            max_paid_results: self.config.max_paid_results,
+            max_organic_results: self.config.max_organic_results,
+            sub1: self.config.sub1.clone(),
+            sub2: self.config.sub2.clone(),
+            sub3: None,
+            sub4: None,
+        })
+    }
+}
+
+#[async_trait]
+impl SuggestionProvider for AdmServerSideSuggester {
+    fn name(&self) -> String {
+        "AdmServerSide".into()
+    }
+
+    async fn suggest(
+        &self,
+        request: SuggestionRequest,
+    ) -> Result<SuggestionResponse, SuggestError> {
+        if request.preferred_locale(SUPPORTED_LOCALES.iter()).is_none() {
+            return Ok(SuggestionResponse::new(vec![]));
+        }
+
+        let params = match self.endpoint_parameters(&request) {
+            Some(params) => params,
+            None => {
+                tracing::debug!(
+                    r#type = "adm.server-side.skipped",
+                    "Request is missing information required by adM, not sending it"
+                );
+                return Ok(SuggestionResponse::new(vec![]));
+            }
+        };
+        let url = params
+            .url_with_base(&self.base_url)
+            .map_err(SuggestError::Internal)?;
+
+        let response: SuggestionEndpointResponse = self
+            .client
+            .get(url.to_string())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(request_error)?
+            .json()
+            .await
+            .context("Parsing adM suggestions")
+            .map_err(SuggestError::Internal)?;
+
+        Ok(SuggestionResponse::new(
+            response.into_suggestions(self.score),
+        ))
+    }
+
+    fn supported_locales(&self) -> Vec<LanguageIdentifier> {
+        SUPPORTED_LOCALES.clone()
+    }
+
+    fn location_granularity(&self) -> LocationGranularity {
+        LocationGranularity::City
+    }
+}
 
 /// Parameters for AdM Conducive API Instant Suggest endpoint, v4.7.21
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -95,22 +252,68 @@ pub enum OsFamily {
     Other,
 }
 
-impl From<SuggestionEndpointParameters> for Uri {
-    fn from(params: SuggestionEndpointParameters) -> Uri {
+impl From<&device_info::FormFactor> for FormFactor {
+    fn from(form_factor: &device_info::FormFactor) -> Self {
+        match form_factor {
+            device_info::FormFactor::Desktop => Self::Desktop,
+            device_info::FormFactor::Phone => Self::Phone,
+            device_info::FormFactor::Tablet => Self::Tablet,
+            device_info::FormFactor::Other => Self::Other,
+        }
+    }
+}
+
+impl From<&device_info::OsFamily> for OsFamily {
+    fn from(os_family: &device_info::OsFamily) -> Self {
+        match os_family {
+            device_info::OsFamily::Windows => Self::Windows,
+            device_info::OsFamily::MacOs => Self::Mac,
+            device_info::OsFamily::Linux => Self::Linux,
+            device_info::OsFamily::IOs => Self::Ios,
+            device_info::OsFamily::Android => Self::Android,
+            device_info::OsFamily::ChromeOs => Self::ChromeOs,
+            device_info::OsFamily::BlackBerry => Self::BlackBerry,
+            device_info::OsFamily::Other => Self::Other,
+        }
+    }
+}
+
+impl SuggestionEndpointParameters {
+    /// Build the URL to request suggestions with these parameters from the API
+    /// hosted at `base_url`.
+    ///
+    /// # Errors
+    /// If the parameters can't be encoded into a valid URL.
+    pub fn url_with_base(&self, base_url: &Uri) -> anyhow::Result<Uri> {
         let path_and_query = format!(
-            "/suggestionsp?{}",
-            serde_qs::to_string(&params).expect("Couldn't make URL params")
+            "{}/suggestionsp?{}",
+            base_url.path().trim_end_matches('/'),
+            serde_qs::to_string(self).context("Encoding adM URL parameters")?
         );
 
-        Uri::builder()
-            .scheme("https")
-            .authority(format!("{}.cpsp.ampfeed.com", params.partner).as_str())
-            .path_and_query(path_and_query)
-            .build()
-            .expect("Couldn't build URL")
+        let mut builder = Uri::builder().path_and_query(path_and_query);
+        if let Some(scheme) = base_url.scheme() {
+            builder = builder.scheme(scheme.clone());
+        }
+        if let Some(authority) = base_url.authority() {
+            builder = builder.authority(authority.clone());
+        }
+        builder.build().context("Building adM URL")
     }
 }
 
+/// The base URL of the production adM API for `partner`.
+///
+/// # Errors
+/// If `partner` can't be used as part of a host name.
+fn partner_base_url(partner: &str) -> Result<Uri, http::Error> {
+    Uri::builder()
+        .scheme("https")
+        .authority(format!("{}.cpsp.ampfeed.com", partner).as_str())
+        .path_and_query("/")
+        .build()
+}
+
 /// The response from the adM suggestion API.
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
@@ -145,6 +348,24 @@ pub struct PaidSuggestionsTextAdsResponse {
     ads: Vec<Suggestion>,
 }
 
+impl SuggestionEndpointResponse {
+    /// Convert the paid and organic suggestions in this response into Merino
+    /// suggestions with `score`. Paid suggestions are listed first.
+    fn into_suggestions(self, score: Proportion) -> Vec<merino_suggest::Suggestion> {
+        let original_query_term = self.original_query_term;
+        let paid = self
+            .paid_suggestions
+            .text_ads
+            .ads
+            .into_iter()
+            .map(|suggestion| suggestion.into_merino_suggestion(&original_query_term, true, score));
+        let organic = self.organic_suggestions.into_iter().map(|suggestion| {
+            suggestion.into_merino_suggestion(&original_query_term, false, score)
+        });
+        paid.chain(organic).collect()
+    }
+}
+
 /// A suggestion (paid or not) from the adM API.
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -182,10 +403,62 @@ pub struct Suggestion {
     advertiser_url: Uri,
 }
 
+impl Suggestion {
+    /// Convert this into a Merino suggestion.
+    ///
+    /// adM does not provide an ID for suggestions, so the ID is always 0. The
+    /// brand domain is used as the full keyword if it is available, since it is
+    /// intended to autocomplete the user's search term.
+    fn into_merino_suggestion(
+        self,
+        original_query_term: &str,
+        is_sponsored: bool,
+        score: Proportion,
+    ) -> merino_suggest::Suggestion {
+        let provider = self
+            .advertiser_url
+            .host()
+            .unwrap_or("adM")
+            .trim_start_matches("www.")
+            .to_string();
+
+        merino_suggest::Suggestion {
+            id: 0,
+            full_keyword: self
+                .brand_domain
+                .unwrap_or_else(|| original_query_term.to_string()),
+            title: self.title,
+            url: self.advertiser_url,
+            impression_url: self.impression_url,
+            click_url: self.click_url,
+            provider,
+            is_sponsored,
+            icon: self.image_url,
+            score,
+        }
+    }
+}
+
+/// Convert an error from requesting adM suggestions, keeping timeouts apart
+/// from other network errors.
+fn request_error(error: reqwest::Error) -> SuggestError {
+    let timed_out = error.is_timeout();
+    let error = anyhow::Error::new(error).context("Requesting adM suggestions");
+    if timed_out {
+        SuggestError::Timeout(error)
+    } else {
+        SuggestError::Network(error)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use httpmock::{Method::GET, MockServer};
+    use merino_suggest::device_info::DeviceInfo;
     use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::time::Duration;
 
     #[test]
     fn conducive_parameter_example() {
@@ -207,7 +480,9 @@ mod tests {
             sub4: Some("level4".into()),
         };
         assert_eq!(
-            Into::<Uri>::into(params),
+            params
+                .url_with_base(&partner_base_url("test_partner").expect("bad test partner"))
+                .expect("Could not build URL"),
             Uri::from_maybe_shared(concat!(
                 "https://test_partner.cpsp.ampfeed.com/suggestionsp",
                 "?partner=test_partner",
@@ -286,4 +561,173 @@ mod tests {
             }
         )
     }
+
+    /// Make a suggester that sends requests to `server`.
+    fn suggester_for(server: &MockServer) -> Box<AdmServerSideSuggester> {
+        AdmServerSideSuggester::new_boxed(&AdmServerSideConfig {
+            endpoint_url: Some(server.base_url().parse().expect("bad mock server URL")),
+            partner: "test_partner".into(),
+            sub1: "level1".into(),
+            timeout: Duration::from_millis(500),
+            score: 0.5,
+            ..AdmServerSideConfig::default()
+        })
+        .expect("Could not create suggester")
+    }
+
+    /// Make a request with all of the information adM requires.
+    fn us_request(query: &str) -> SuggestionRequest {
+        SuggestionRequest {
+            query: query.into(),
+            accepted_locales: vec!["en-US".parse().expect("bad locale")],
+            country: Some("US".into()),
+            region: Some("NY".into()),
+            city: Some("Albany".into()),
+            dma: Some(532),
+            device_info: DeviceInfo {
+                form_factor: device_info::FormFactor::Desktop,
+                os_family: device_info::OsFamily::MacOs,
+                browser: device_info::Browser::Firefox(90),
+            },
+        }
+    }
+
+    #[actix_rt::test]
+    async fn suggester_converts_adm_suggestions() -> anyhow::Result<()> {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/suggestionsp")
+                .query_param("partner", "test_partner")
+                .query_param("qt", "am")
+                .query_param("country-code", "US")
+                .query_param("region-code", "NY")
+                .query_param("city", "Albany")
+                .query_param("dma-code", "532")
+                .query_param("form-factor", "desktop")
+                .query_param("os-family", "macOS")
+                .query_param("sub1", "level1");
+            then.status(200).json_body(json!({
+                "originalQt": "am",
+                "organicSuggestions": [{
+                    "term": "Amsterdam - Wikipedia",
+                    "clickUrl": "https://example.com/click/organic",
+                    "imageUrl": "https://example.com/icon/organic.png",
+                    "impressionUrl": "https://example.com/impression/organic",
+                    "labelRequired": false,
+                    "brand": false,
+                    "brandDomain": null,
+                    "advertiserUrl": "https://en.wikipedia.org/wiki/Amsterdam"
+                }],
+                "paidSuggestions": {
+                    "textAds": {
+                        "ads": [{
+                            "term": "amazon.com - Huge Selection & Amazing Prices",
+                            "clickUrl": "https://example.com/click/paid",
+                            "imageUrl": "https://example.com/icon/paid.png",
+                            "impressionUrl": "https://example.com/impression/paid",
+                            "labelRequired": true,
+                            "brand": true,
+                            "brandDomain": "amazon.com",
+                            "advertiserUrl": "https://www.amazon.com/"
+                        }],
+                        "resultsCount": 1
+                    }
+                }
+            }));
+        });
+
+        let suggestions = suggester_for(&server)
+            .suggest(us_request("am"))
+            .await?
+            .suggestions;
+
+        mock.assert();
+        assert_eq!(
+            suggestions,
+            vec![
+                merino_suggest::Suggestion {
+                    id: 0,
+                    full_keyword: "amazon.com".into(),
+                    title: "amazon.com - Huge Selection & Amazing Prices".into(),
+                    url: Uri::from_static("https://www.amazon.com/"),
+                    impression_url: Uri::from_static("https://example.com/impression/paid"),
+                    click_url: Uri::from_static("https://example.com/click/paid"),
+                    provider: "amazon.com".into(),
+                    is_sponsored: true,
+                    icon: Uri::from_static("https://example.com/icon/paid.png"),
+                    score: Proportion::from(0.5),
+                },
+                merino_suggest::Suggestion {
+                    id: 0,
+                    full_keyword: "am".into(),
+                    title: "Amsterdam - Wikipedia".into(),
+                    url: Uri::from_static("https://en.wikipedia.org/wiki/Amsterdam"),
+                    impression_url: Uri::from_static("https://example.com/impression/organic"),
+                    click_url: Uri::from_static("https://example.com/click/organic"),
+                    provider: "en.wikipedia.org".into(),
+                    is_sponsored: false,
+                    icon: Uri::from_static("https://example.com/icon/organic.png"),
+                    score: Proportion::from(0.5),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let bad_partner = AdmServerSideConfig {
+            partner: "not a host/name".into(),
+            ..AdmServerSideConfig::default()
+        };
+        assert!(matches!(
+            AdmServerSideSuggester::new_boxed(&bad_partner),
+            Err(SetupError::InvalidConfiguration(_))
+        ));
+
+        let bad_score = AdmServerSideConfig {
+            partner: "test_partner".into(),
+            score: 1.5,
+            ..AdmServerSideConfig::default()
+        };
+        assert!(matches!(
+            AdmServerSideSuggester::new_boxed(&bad_score),
+            Err(SetupError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn requests_without_location_are_not_sent() -> anyhow::Result<()> {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/suggestionsp");
+            then.status(500);
+        });
+
+        let request = SuggestionRequest {
+            country: None,
+            ..us_request("amazon")
+        };
+        let response = suggester_for(&server).suggest(request).await?;
+
+        assert!(response.suggestions.is_empty());
+        assert_eq!(mock.hits(), 0);
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn slow_responses_time_out() {
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method(GET).path("/suggestionsp");
+            then.status(200).delay(Duration::from_secs(5));
+        });
+
+        let result = suggester_for(&server).suggest(us_request("amazon")).await;
+
+        assert!(matches!(result, Err(SuggestError::Timeout(_))));
+    }
 }
@@ -5,12 +5,32 @@ use dashmap::{mapref::entry::Entry, DashMap};
 use std::collections::hash_map::DefaultHasher;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Mutex,
+};
+
+/// When the map is over one of its limits, entries are evicted until it is at
+/// this fraction of the limit. Evicting in batches amortizes the cost of
+/// finding the least recently used entries.
+const EVICTION_TARGET_RATIO: f64 = 0.9;
+
+/// An estimate of the memory used by a value, used to enforce size limits.
+pub trait ApproximateSize {
+    /// The approximate number of bytes used by this value, including any heap
+    /// allocations it owns.
+    fn approximate_size(&self) -> usize;
+}
 
 /// A hashmap that assumes a large number of keys will map to a relatively smaller number of values.
 ///
 /// If a value is stored in the map that is already stored by another key, the
 /// refcount of the value will be incremented instead of duplicating it.
 ///
+/// The map can optionally be limited to a maximum number of keys and a maximum
+/// total size of values. These limits are enforced on insert by evicting the
+/// least recently used keys.
+///
 /// Uses DashMap internally
 #[derive(Debug, Default)]
 pub struct DedupedMap<K, M, V>
@@ -26,6 +46,21 @@ where
     /// Second layer of the map. The items stored in the cache, keyed by their
     /// hash.
     storage: DashMap<u64, MapValue<V>>,
+
+    /// The maximum number of pointers to store, if any.
+    max_pointers: Option<usize>,
+
+    /// The maximum total approximate size of stored values, if any.
+    max_storage_bytes: Option<usize>,
+
+    /// The current total approximate size of stored values.
+    storage_bytes: AtomicUsize,
+
+    /// A logical clock used to record when each pointer was last used.
+    clock: AtomicU64,
+
+    /// Held while evicting entries, so that only one eviction runs at a time.
+    eviction_lock: Mutex<()>,
 }
 
 /// The first layer of the map, it stores per-key metadata, and a hash entry for the second layer.
@@ -35,6 +70,8 @@ struct MapPointer<M: Debug> {
     meta: M,
     /// The hash of the content to retrieve from the storage.
     hash: u64,
+    /// The value of the map's clock when this pointer was last used.
+    last_used: AtomicU64,
 }
 
 /// The second layer of the map, a reference counted value.
@@ -42,21 +79,35 @@ struct MapPointer<M: Debug> {
 struct MapValue<V> {
     /// The stored value.
     value: V,
+    /// The approximate size of `value`.
+    size: usize,
     /// The number of pointers that are referring to this storage item.
     refcount: usize,
 }
 
 impl<K, M, V> DedupedMap<K, M, V>
 where
-    K: Eq + Hash + Debug,
+    K: Eq + Hash + Debug + Clone,
     M: Debug + Clone,
-    V: Hash + Debug + Clone,
+    V: Hash + Debug + Clone + ApproximateSize,
 {
     /// Create an empty map.
+    #[cfg(test)]
     pub fn new() -> Self {
+        Self::with_limits(None, None)
+    }
+
+    /// Create an empty map that holds at most `max_pointers` keys, and at most
+    /// `max_storage_bytes` of values.
+    pub fn with_limits(max_pointers: Option<usize>, max_storage_bytes: Option<usize>) -> Self {
         Self {
             storage: DashMap::new(),
             pointers: DashMap::new(),
+            max_pointers,
+            max_storage_bytes,
+            storage_bytes: AtomicUsize::new(0),
+            clock: AtomicU64::new(0),
+            eviction_lock: Mutex::new(()),
         }
     }
 
@@ -65,7 +116,10 @@ where
     /// If `value` is already in the map under a different key, its refcount will
     /// be incremented instead of storing another copy of `value`. The metadata
     /// data `meta` will be attached to this specific key, and not refcounted.
-    pub fn insert(&self, key: K, meta: M, value: V) {
+    ///
+    /// If the map is over its limits afterwards, the least recently used keys
+    /// will be evicted. Returns the number of keys that were evicted.
+    pub fn insert(&self, key: K, meta: M, value: V) -> usize {
         let mut hasher = DefaultHasher::new();
         value.hash(&mut hasher);
         let hash = hasher.finish();
@@ -80,11 +134,26 @@ where
                 occupied_entry.get_mut().refcount += 1;
             }
             Entry::Vacant(vacant_entry) => {
-                vacant_entry.insert(MapValue { value, refcount: 1 });
+                let size = value.approximate_size();
+                self.storage_bytes.fetch_add(size, Ordering::Relaxed);
+                vacant_entry.insert(MapValue {
+                    value,
+                    size,
+                    refcount: 1,
+                });
             }
         }
 
-        self.pointers.insert(key, MapPointer { meta, hash });
+        let pointer = MapPointer {
+            meta,
+            hash,
+            last_used: AtomicU64::new(self.tick()),
+        };
+        if let Some(replaced) = self.pointers.insert(key, pointer) {
+            self.release(replaced.hash);
+        }
+
+        self.evict_if_needed()
     }
 
     /// Remove the item associated with a key from the map.
@@ -93,29 +162,38 @@ where
     /// count of the storage item it points to will be decremented. If no more
     /// keys refer to the storage item, it will also be removed.
     pub fn remove(&self, key: K) {
-        if let Entry::Occupied(mut occupied_pointer_entry) = self.pointers.entry(key) {
-            let pointer = occupied_pointer_entry.get_mut();
-            match self.storage.entry(pointer.hash) {
-                Entry::Occupied(mut occupied_storage_entry) => {
-                    let item = occupied_storage_entry.get_mut();
-                    if item.refcount > 1 {
-                        item.refcount -= 1;
-                    } else {
-                        occupied_pointer_entry.remove();
-                    }
-                }
-                Entry::Vacant(_) => {
-                    tracing::error!("missing storage entry in memory cache")
+        if let Some((_key, pointer)) = self.pointers.remove(&key) {
+            self.release(pointer.hash);
+        }
+    }
+
+    /// Decrement the refcount of the storage item with `hash`, removing it if
+    /// no more keys refer to it.
+    fn release(&self, hash: u64) {
+        match self.storage.entry(hash) {
+            Entry::Occupied(mut occupied_storage_entry) => {
+                let item = occupied_storage_entry.get_mut();
+                if item.refcount > 1 {
+                    item.refcount -= 1;
+                } else {
+                    let item = occupied_storage_entry.remove();
+                    self.storage_bytes.fetch_sub(item.size, Ordering::Relaxed);
                 }
             }
+            Entry::Vacant(_) => {
+                tracing::error!("missing storage entry in memory cache")
+            }
         }
     }
 
     /// Get cloned copies of the metadata and value associated with `key`.
+    ///
+    /// This marks `key` as recently used.
     pub fn get(&self, key: &K) -> Option<(M, V)> {
         match self.pointers.get(key) {
             Some(pointer_ref) => match self.storage.get(&pointer_ref.hash) {
                 Some(storage_ref) => {
+                    pointer_ref.last_used.store(self.tick(), Ordering::Relaxed);
                     let meta = pointer_ref.meta.clone();
                     let value = storage_ref.value.clone();
                     Some((meta, value))
@@ -129,6 +207,57 @@ where
         }
     }
 
+    /// Advance the map's logical clock, returning the new time.
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Check if the map is over either of its limits.
+    fn is_over_limits(&self, ratio: f64) -> bool {
+        let over = |len: usize, max: Option<usize>| match max {
+            Some(max) => len as f64 > max as f64 * ratio,
+            None => false,
+        };
+        over(self.len_pointers(), self.max_pointers)
+            || over(self.size_storage_bytes(), self.max_storage_bytes)
+    }
+
+    /// If the map is over its limits, evict the least recently used keys until
+    /// it is comfortably under them. Returns the number of keys evicted.
+    fn evict_if_needed(&self) -> usize {
+        if !self.is_over_limits(1.0) {
+            return 0;
+        }
+
+        // If another thread is already evicting, let it do the work.
+        let _guard = match self.eviction_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return 0,
+        };
+
+        let mut candidates: Vec<(u64, K)> = self
+            .pointers
+            .iter()
+            .map(|entry| {
+                (
+                    entry.value().last_used.load(Ordering::Relaxed),
+                    entry.key().clone(),
+                )
+            })
+            .collect();
+        candidates.sort_unstable_by_key(|(last_used, _)| *last_used);
+
+        let mut evicted = 0;
+        for (_, key) in candidates {
+            if !self.is_over_limits(EVICTION_TARGET_RATIO) {
+                break;
+            }
+            self.remove(key);
+            evicted += 1;
+        }
+        evicted
+    }
+
     /// Fetches the total number of storage items in the map.
     ///
     /// This will be at most `self.len_pointers()`.
@@ -143,6 +272,11 @@ where
         self.pointers.len()
     }
 
+    /// Fetches the total approximate size of the storage items in the map.
+    pub fn size_storage_bytes(&self) -> usize {
+        self.storage_bytes.load(Ordering::Relaxed)
+    }
+
     /// Retain elements based on the result of a predicate.
     ///
     /// If the predicate function returns:
@@ -174,7 +308,8 @@ where
                             if item.refcount > 1 {
                                 item.refcount -= 1;
                             } else {
-                                occupied_entry.remove();
+                                let item = occupied_entry.remove();
+                                self.storage_bytes.fetch_sub(item.size, Ordering::Relaxed);
                             }
                             false
                         }
@@ -193,7 +328,7 @@ where
     }
 
     /// Checks if the map contains a specific key.
-    #[cfg_attr(not(test), allow(dead_code))]
+    #[cfg(test)]
     pub fn contains_key(&self, key: &K) -> bool {
         self.pointers.contains_key(key)
     }
@@ -212,7 +347,25 @@ pub enum ControlFlow<C> {
 mod tests {
     use std::collections::HashSet;
 
-    use super::{ControlFlow, DedupedMap};
+    use super::{ApproximateSize, ControlFlow, DedupedMap};
+
+    impl ApproximateSize for &str {
+        fn approximate_size(&self) -> usize {
+            self.len()
+        }
+    }
+
+    impl ApproximateSize for () {
+        fn approximate_size(&self) -> usize {
+            0
+        }
+    }
+
+    impl ApproximateSize for u32 {
+        fn approximate_size(&self) -> usize {
+            std::mem::size_of::<u32>()
+        }
+    }
 
     #[test]
     fn test_retain() {
@@ -270,4 +423,68 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_remove_releases_storage() {
+        let map = DedupedMap::<&str, (), &str>::new();
+        map.insert("a", (), "shared");
+        map.insert("b", (), "shared");
+        assert_eq!(map.len_storage(), 1);
+        assert_eq!(map.size_storage_bytes(), 6);
+
+        map.remove("a");
+        assert_eq!(map.len_pointers(), 1);
+        assert_eq!(map.len_storage(), 1);
+        assert!(!map.contains_key(&"a"));
+
+        map.remove("b");
+        assert_eq!(map.len_pointers(), 0);
+        assert_eq!(map.len_storage(), 0);
+        assert_eq!(map.size_storage_bytes(), 0);
+    }
+
+    #[test]
+    fn test_reinsert_releases_old_value() {
+        let map = DedupedMap::<&str, (), &str>::new();
+        map.insert("a", (), "old");
+        map.insert("a", (), "new!");
+        assert_eq!(map.len_pointers(), 1);
+        assert_eq!(map.len_storage(), 1);
+        assert_eq!(map.size_storage_bytes(), 4);
+    }
+
+    #[test]
+    fn test_max_pointers_evicts_least_recently_used() {
+        let map = DedupedMap::<u32, (), u32>::with_limits(Some(10), None);
+        for i in 0..10 {
+            assert_eq!(map.insert(i, (), i), 0);
+        }
+
+        // Use 0, so that 1 and 2 are the least recently used keys.
+        assert!(map.get(&0).is_some());
+
+        // Going over the limit evicts down to 90% of it.
+        assert_eq!(map.insert(10, (), 10), 2);
+        assert_eq!(map.len_pointers(), 9);
+        assert!(map.contains_key(&0));
+        assert!(!map.contains_key(&1));
+        assert!(!map.contains_key(&2));
+        assert!(map.contains_key(&10));
+    }
+
+    #[test]
+    fn test_max_bytes_evicts_least_recently_used() {
+        let map = DedupedMap::<u32, (), &str>::with_limits(None, Some(20));
+        map.insert(1, (), "aaaaa");
+        map.insert(2, (), "bbbbb");
+        map.insert(3, (), "ccccc");
+        map.insert(4, (), "ddddd");
+        assert_eq!(map.size_storage_bytes(), 20);
+
+        assert_eq!(map.insert(5, (), "eeeee"), 2);
+        assert_eq!(map.size_storage_bytes(), 15);
+        assert!(!map.contains_key(&1));
+        assert!(!map.contains_key(&2));
+        assert!(map.contains_key(&5));
+    }
 }
@@ -1,28 +1,88 @@
 //! Data types specific to caching.
 
-use merino_suggest::SuggestionRequest;
+use merino_suggest::{LanguageIdentifier, LocationGranularity, SuggestionRequest};
 
 /// An object that can generate a cache key for itself.
 pub trait CacheKey {
     /// Generate a cache key for this object. Two objects that have the same
     /// cache key should be functionally identical.
     ///
+    /// Only location information up to `location_granularity` is included in
+    /// the key, so that objects that differ only in location details that
+    /// don't matter share a key. Likewise, locales are included as the locale
+    /// out of `supported_locales` that they resolve to.
+    ///
     /// Cache keys should make it clear that they are cache keys, and specify the
     /// type of object they refer to. They should also include a version
     /// indicator. For example: `cache:req:v1:d1bc8d3ba4afc7e1`. Excessively long
     /// key lengths should be avoided. 100 bytes is a good upper bound.
-    fn cache_key(&self) -> String;
+    fn cache_key(
+        &self,
+        location_granularity: LocationGranularity,
+        supported_locales: &[LanguageIdentifier],
+    ) -> String;
+}
+
+/// Add `field` to `hasher`, prefixed with its length so that the boundaries
+/// between fields are unambiguous.
+fn hash_field(hasher: &mut blake3::Hasher, field: &[u8]) {
+    hasher.update(&(field.len() as u64).to_be_bytes());
+    hasher.update(field);
 }
 
 impl CacheKey for SuggestionRequest {
-    fn cache_key(&self) -> String {
+    fn cache_key(
+        &self,
+        location_granularity: LocationGranularity,
+        supported_locales: &[LanguageIdentifier],
+    ) -> String {
         let mut hasher = blake3::Hasher::new();
-        hasher.update(self.query.as_bytes());
-        hasher.update(&[self.accepts_english as u8]);
-        hasher.update(self.device_info.to_string().as_bytes());
+        hash_field(&mut hasher, self.query.as_bytes());
+
+        // Each accepted locale is keyed by the supported locale it resolves
+        // to, so requests that differ only in locales the provider treats the
+        // same share a key. Providers that support any locale get the accepted
+        // locale itself, since they may use it.
+        let mut resolved_locales: Vec<&LanguageIdentifier> = Vec::new();
+        for accepted in &self.accepted_locales {
+            let resolved = match accepted.best_match(supported_locales) {
+                Some(LanguageIdentifier::Wildcard) => accepted,
+                Some(supported) => supported,
+                None => continue,
+            };
+            if !resolved_locales.contains(&resolved) {
+                resolved_locales.push(resolved);
+            }
+        }
+        hasher.update(&(resolved_locales.len() as u64).to_be_bytes());
+        for locale in resolved_locales {
+            hash_field(&mut hasher, locale.to_string().as_bytes());
+        }
+
+        hash_field(&mut hasher, self.device_info.to_string().as_bytes());
+
+        if location_granularity >= LocationGranularity::Country {
+            hash_field(
+                &mut hasher,
+                self.country.as_deref().unwrap_or_default().as_bytes(),
+            );
+        }
+        if location_granularity >= LocationGranularity::Region {
+            hash_field(
+                &mut hasher,
+                self.region.as_deref().unwrap_or_default().as_bytes(),
+            );
+        }
+        if location_granularity >= LocationGranularity::City {
+            hash_field(&mut hasher, &self.dma.unwrap_or_default().to_be_bytes());
+            hash_field(
+                &mut hasher,
+                self.city.as_deref().unwrap_or_default().as_bytes(),
+            );
+        }
 
         let hash = hasher.finalize().to_hex();
-        format!("req:v3:{}", hash)
+        format!("req:v6:{}", hash)
     }
 }
 
@@ -32,10 +92,13 @@ mod tests {
     use fake::{Fake, Faker};
     use merino_suggest::{
         device_info::{Browser, DeviceInfo, FormFactor, OsFamily},
-        SuggestionRequest, FIREFOX_TEST_VERSIONS,
+        LanguageIdentifier, LocationGranularity, SuggestionRequest, FIREFOX_TEST_VERSIONS,
     };
     use proptest::prelude::*;
 
+    /// Supported locales for a provider that supports every locale.
+    const ANY_LOCALE: &[LanguageIdentifier] = &[LanguageIdentifier::Wildcard];
+
     /// This test provides a fixed input, and expects a certain cache key to be
     /// produced. This alerts us to any time the cache algorithm changes. If this
     /// is an expected change, you should increment the version number in the
@@ -44,7 +107,10 @@ mod tests {
     fn it_works() {
         let req = SuggestionRequest {
             query: "arbitrary".into(),
-            accepts_english: true,
+            accepted_locales: vec![LanguageIdentifier::Locale {
+                language: "en".into(),
+                region: Some("us".into()),
+            }],
             country: Some("US".into()),
             region: Some("OR".into()),
             dma: Some(820_u16),
@@ -56,23 +122,137 @@ mod tests {
             },
         };
         assert_eq!(
-            req.cache_key(),
-            "req:v3:3096f07f8bce1cd4d39f2ea5544cd58e5c2ec94d3b491004a83257bb48c5fa45",
+            req.cache_key(LocationGranularity::City, ANY_LOCALE),
+            "req:v6:51de101c6f5827e8912ad2e5ec25f5edda8a5b375090722a0bd46b49392ba4ed",
         );
     }
 
     #[test]
-    fn hash_uses_accepts_english_as_input() {
+    fn hash_uses_accepted_locales_as_input() {
+        let req1: SuggestionRequest = Faker.fake();
+        let req2 = SuggestionRequest {
+            accepted_locales: vec![LanguageIdentifier::Locale {
+                language: "en".into(),
+                region: None,
+            }],
+            ..req1.clone()
+        };
+        let req3 = SuggestionRequest {
+            accepted_locales: vec![LanguageIdentifier::Locale {
+                language: "fr".into(),
+                region: None,
+            }],
+            ..req1
+        };
+
+        assert_ne!(
+            req2.cache_key(LocationGranularity::None, ANY_LOCALE),
+            req3.cache_key(LocationGranularity::None, ANY_LOCALE)
+        );
+    }
+
+    #[test]
+    fn hash_uses_resolved_locales() -> anyhow::Result<()> {
+        let supported: Vec<LanguageIdentifier> = vec!["en".parse()?, "fr".parse()?];
+        let request_with = |locales: &[&str]| -> anyhow::Result<SuggestionRequest> {
+            Ok(SuggestionRequest {
+                accepted_locales: locales
+                    .iter()
+                    .map(|l| l.parse())
+                    .collect::<Result<_, _>>()?,
+                ..Faker.fake()
+            })
+        };
+        let base = request_with(&["en"])?;
+        let key = |locales: &[&str]| -> anyhow::Result<String> {
+            Ok(SuggestionRequest {
+                accepted_locales: request_with(locales)?.accepted_locales,
+                ..base.clone()
+            }
+            .cache_key(LocationGranularity::None, &supported))
+        };
+
+        assert_eq!(key(&["en"])?, key(&["en-us", "en-gb", "de"])?);
+        assert_ne!(key(&["en"])?, key(&["fr"])?);
+        assert_ne!(key(&["en", "fr"])?, key(&["fr", "en"])?);
+        Ok(())
+    }
+
+    #[test]
+    fn hash_fields_do_not_run_together() {
         let req1 = SuggestionRequest {
-            accepts_english: true,
+            query: "en".into(),
+            accepted_locales: vec![],
             ..Faker.fake()
         };
         let req2 = SuggestionRequest {
-            accepts_english: false,
+            query: "".into(),
+            accepted_locales: vec![LanguageIdentifier::Locale {
+                language: "en".into(),
+                region: None,
+            }],
+            ..req1.clone()
+        };
+
+        assert_ne!(
+            req1.cache_key(LocationGranularity::None, ANY_LOCALE),
+            req2.cache_key(LocationGranularity::None, ANY_LOCALE)
+        );
+    }
+
+    #[test]
+    fn hash_uses_location_up_to_granularity() {
+        let portland = SuggestionRequest {
+            country: Some("US".into()),
+            region: Some("OR".into()),
+            city: Some("Portland".into()),
+            dma: Some(820),
             ..Faker.fake()
         };
+        let salem = SuggestionRequest {
+            city: Some("Salem".into()),
+            ..portland.clone()
+        };
+        let seattle = SuggestionRequest {
+            region: Some("WA".into()),
+            city: Some("Seattle".into()),
+            dma: Some(819),
+            ..portland.clone()
+        };
+        let toronto = SuggestionRequest {
+            country: Some("CA".into()),
+            region: Some("ON".into()),
+            city: Some("Toronto".into()),
+            dma: None,
+            ..portland.clone()
+        };
+
+        let key = |req: &SuggestionRequest, granularity| req.cache_key(granularity, ANY_LOCALE);
 
-        assert_ne!(req1.cache_key(), req2.cache_key());
+        assert_eq!(
+            key(&portland, LocationGranularity::None),
+            key(&toronto, LocationGranularity::None)
+        );
+        assert_eq!(
+            key(&portland, LocationGranularity::Country),
+            key(&seattle, LocationGranularity::Country)
+        );
+        assert_ne!(
+            key(&portland, LocationGranularity::Country),
+            key(&toronto, LocationGranularity::Country)
+        );
+        assert_eq!(
+            key(&portland, LocationGranularity::Region),
+            key(&salem, LocationGranularity::Region)
+        );
+        assert_ne!(
+            key(&portland, LocationGranularity::Region),
+            key(&seattle, LocationGranularity::Region)
+        );
+        assert_ne!(
+            key(&portland, LocationGranularity::City),
+            key(&salem, LocationGranularity::City)
+        );
     }
 
     proptest! {
@@ -81,16 +261,17 @@ mod tests {
         // "\\PC*" is a regex for any number of Printable Characters.
         fn key_format(
             query in "\\PC*",
-            accepts_english in proptest::bool::ANY,
+            accepted_locales in proptest::collection::vec(locale_strategy(), 0..4),
             country in proptest::option::of("[A-Z]{2}"),
             region in proptest::option::of("[A-Z]{1,3}"),
             dma in proptest::option::of(100_u16..1000),
             city in proptest::option::of("[A-Z]{2}"),
-            device_info in device_info_strategy()
+            device_info in device_info_strategy(),
+            location_granularity in location_granularity_strategy()
         ) {
             let req = SuggestionRequest {
                 query,
-                accepts_english,
+                accepted_locales,
                 country,
                 region,
                 dma,
@@ -98,15 +279,32 @@ mod tests {
                 device_info,
             };
             const HEX_DIGITS: &str = "0123456789abcdef";
-            let parts: Vec<String> = req.cache_key().split(':').map(ToString::to_string).collect();
+            let parts: Vec<String> = req.cache_key(location_granularity, ANY_LOCALE).split(':').map(ToString::to_string).collect();
             prop_assert_eq!(parts.len(), 3);
             prop_assert_eq!(&parts[0], "req");
-            prop_assert_eq!(&parts[1], "v3");
+            prop_assert_eq!(&parts[1], "v6");
             prop_assert!(parts[2].chars().all(|c|HEX_DIGITS.contains(c)));
             prop_assert_eq!(parts[2].len(), 64);
         }
     }
 
+    fn locale_strategy() -> impl Strategy<Value = LanguageIdentifier> {
+        prop_oneof![
+            Just(LanguageIdentifier::Wildcard),
+            ("[a-z]{2}", proptest::option::of("[a-z]{2}"))
+                .prop_map(|(language, region)| LanguageIdentifier::Locale { language, region }),
+        ]
+    }
+
+    fn location_granularity_strategy() -> impl Strategy<Value = LocationGranularity> {
+        prop_oneof![
+            Just(LocationGranularity::None),
+            Just(LocationGranularity::Country),
+            Just(LocationGranularity::Region),
+            Just(LocationGranularity::City),
+        ]
+    }
+
     fn form_factor_strategy() -> impl Strategy<Value = FormFactor> {
         prop_oneof![
             Just(FormFactor::Desktop),
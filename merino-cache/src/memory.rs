@@ -6,19 +6,29 @@
 //! responses can be stored only once, even if they are used for many requests.
 
 use crate::{
-    deduped_map::{ControlFlow, DedupedMap},
+    deduped_map::{ApproximateSize, ControlFlow, DedupedMap},
     domain::CacheKey,
 };
+use anyhow::anyhow;
 use async_trait::async_trait;
-use lazy_static::lazy_static;
+use cadence::{Counted, Gauged, StatsdClient};
+use dashmap::DashMap;
+use futures::{
+    channel::oneshot,
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use http::Uri;
 use merino_settings::providers::MemoryCacheConfig;
 use merino_suggest::{
-    CacheStatus, Suggestion, SuggestionProvider, SuggestionRequest, SuggestionResponse,
+    CacheStatus, HealthCheck, LanguageIdentifier, LocationGranularity, SuggestError, Suggestion,
+    SuggestionProvider, SuggestionRequest, SuggestionResponse,
 };
 use std::{
     collections::HashMap,
     fmt::Debug,
     hash::Hash,
+    mem::size_of,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -26,29 +36,40 @@ use tracing::Instrument;
 
 use arc_swap::ArcSwap;
 
-lazy_static! {
-    static ref LOCK_TABLE: ArcSwap<HashMap<String, Instant>> =
-        ArcSwap::from_pointee(HashMap::new());
-}
+/// The locks held by requests that are fetching entries for a cache, by the
+/// key of the entry. Each cache has its own table, since caches don't share
+/// entries.
+#[derive(Default)]
+struct LockTable(ArcSwap<HashMap<String, Instant>>);
 
-impl LOCK_TABLE {
+impl LockTable {
     /// Check to see if there's any lock for a given key
+    #[cfg(test)]
     fn is_locked(&self, key: &str) -> bool {
-        if let Some(lock_val) = self.load().get(key) {
+        if let Some(lock_val) = self.0.load().get(key) {
             return *lock_val > Instant::now();
         }
         false
     }
 
-    /// Generate a lock for the given key and timeout
-    fn add_lock(&self, key: &str, lock_timeout: Duration) -> Instant {
-        let lock = Instant::now() + lock_timeout;
-        self.rcu(|table| {
+    /// Generate a lock for the given key and timeout, unless there is already
+    /// an unexpired lock for the key.
+    fn try_add_lock(&self, key: &str, lock_timeout: Duration) -> Option<Instant> {
+        let now = Instant::now();
+        let mut acquired = None;
+        self.0.rcu(|table| {
             let mut locked = HashMap::clone(table);
-            locked.insert(key.to_owned(), lock);
+            acquired = match locked.get(key) {
+                Some(lock_val) if *lock_val > now => None,
+                _ => {
+                    let lock = now + lock_timeout;
+                    locked.insert(key.to_owned(), lock);
+                    Some(lock)
+                }
+            };
             locked
         });
-        lock
+        acquired
     }
 
     /// run func and remove lock, only if the lock we have matches what
@@ -57,7 +78,7 @@ impl LOCK_TABLE {
     where
         F: FnMut(),
     {
-        self.rcu(|table| {
+        self.0.rcu(|table| {
             let mut locked = HashMap::clone(table);
             if let Some(ts) = locked.get(key) {
                 if *ts == lock {
@@ -72,7 +93,7 @@ impl LOCK_TABLE {
     /// remove any expired elements from the Pending table
     /// (There shouldn't be many.)
     fn prune(&self, start: &Instant) {
-        self.rcu(|table| {
+        self.0.rcu(|table| {
             let mut cleaned = HashMap::clone(table);
             cleaned.retain(|_k, v| *v > *start);
             cleaned
@@ -80,10 +101,15 @@ impl LOCK_TABLE {
     }
 }
 
+/// A fetch from the inner provider, which can be awaited by every request that
+/// is waiting for the same cache entry.
+type SharedFetch = Shared<BoxFuture<'static, Result<SuggestionResponse, Arc<SuggestError>>>>;
+
 /// A in-memory cache for suggestions.
 pub struct Suggester {
-    /// The suggester to query on cache-miss.
-    inner: Box<dyn SuggestionProvider>,
+    /// The suggester to query on cache-miss. It is shared with background
+    /// refresh tasks.
+    inner: Arc<dyn SuggestionProvider>,
 
     /// The cached items.
     items: Arc<DedupedMap<String, Instant, Vec<Suggestion>>>,
@@ -93,6 +119,22 @@ pub struct Suggester {
 
     /// TTL for locks on cache refresh updates
     default_lock_timeout: Duration,
+
+    /// How long after expiration an item may be served while it is refreshed.
+    stale_while_revalidate: Duration,
+
+    /// The client used to report evictions and the size of the cache.
+    metrics_client: StatsdClient,
+
+    /// Whether requests for an entry that is already being fetched should wait
+    /// for that fetch.
+    coalesce_requests: bool,
+
+    /// Fetches that are currently in progress, along with the lock they hold.
+    in_flight: Arc<DashMap<String, (Instant, SharedFetch)>>,
+
+    /// The locks held by requests that are fetching entries.
+    locks: Arc<LockTable>,
 }
 
 impl Suggester {
@@ -100,12 +142,24 @@ impl Suggester {
     pub fn new_boxed(
         config: &MemoryCacheConfig,
         provider: Box<dyn SuggestionProvider>,
+        metrics_client: StatsdClient,
     ) -> Box<Self> {
-        let items = Arc::new(DedupedMap::new());
+        let items = Arc::new(DedupedMap::with_limits(
+            config.max_entries,
+            config.max_size_bytes,
+        ));
+
+        let in_flight = Arc::new(DashMap::new());
+        let locks = Arc::new(LockTable::default());
 
         {
-            let task_items = items.clone();
+            let task_items = Arc::downgrade(&items);
+            let task_in_flight = Arc::downgrade(&in_flight);
+            let task_locks = Arc::downgrade(&locks);
             let task_interval = config.cleanup_interval;
+            let task_stale_while_revalidate = config.stale_while_revalidate;
+            let task_max_removals = config.max_removed_entries;
+            let task_metrics_client = metrics_client.clone();
             tokio::spawn(async move {
                 let mut timer = tokio::time::interval(task_interval);
                 // The timer fires immediately, but we don't want to run the
@@ -114,35 +168,70 @@ impl Suggester {
                 timer.tick().await;
                 loop {
                     timer.tick().await;
-                    Self::remove_expired_entries(&task_items);
+                    // Stop once the cache has been dropped, such as when the
+                    // provider tree is replaced.
+                    let items = match task_items.upgrade() {
+                        Some(items) => items,
+                        None => break,
+                    };
+                    Self::remove_expired_entries(
+                        &items,
+                        task_stale_while_revalidate,
+                        task_max_removals,
+                        &task_metrics_client,
+                    );
+                    if let Some(in_flight) = task_in_flight.upgrade() {
+                        Self::remove_stale_fetches(&in_flight, Instant::now());
+                    }
+                    if let Some(locks) = task_locks.upgrade() {
+                        locks.prune(&Instant::now());
+                    }
                 }
             });
         }
 
         Box::new(Self {
-            inner: provider,
+            inner: Arc::from(provider),
             items,
             default_ttl: config.default_ttl,
             default_lock_timeout: config.default_lock_timeout,
+            stale_while_revalidate: config.stale_while_revalidate,
+            metrics_client,
+            coalesce_requests: config.coalesce_requests,
+            in_flight,
+            locks,
         })
     }
 
-    /// Remove expired entries from `items`
+    /// Forget fetches whose lock expired before `now`. Those fetches are
+    /// taking longer than requests will wait for them, and new fetches for
+    /// their keys may already have been started.
     ///
     /// This is a selfless method so that it can be called from a spawned Tokio task.
-    #[tracing::instrument(level = "debug", skip(items))]
-    fn remove_expired_entries<K: Eq + Hash + Debug>(
+    fn remove_stale_fetches(in_flight: &DashMap<String, (Instant, SharedFetch)>, now: Instant) {
+        in_flight.retain(|_key, (lock, _fetch)| *lock > now);
+    }
+
+    /// Remove expired entries from `items`, except for those that are still
+    /// within the `stale_while_revalidate` window. At most `max_removals`
+    /// entries will be removed. The size of the cache is reported afterwards.
+    ///
+    /// This is a selfless method so that it can be called from a spawned Tokio task.
+    #[tracing::instrument(level = "debug", skip(items, metrics_client))]
+    fn remove_expired_entries<K: Eq + Hash + Debug + Clone>(
         items: &Arc<DedupedMap<K, Instant, Vec<Suggestion>>>,
+        stale_while_revalidate: Duration,
+        max_removals: usize,
+        metrics_client: &StatsdClient,
     ) {
         let start = Instant::now();
         let count_before_storage = items.len_storage();
         let count_before_pointers = items.len_pointers();
 
         // Retain all cache entries that have not yet expired.
-        let max_removals = 10_000;
         let mut num_removals = 0;
         items.retain(|_key, expiration, _suggestions| {
-            if num_removals > max_removals {
+            if num_removals >= max_removals {
                 tracing::warn!(
                     ?max_removals,
                     "memory-cache cleanup reached max number of removed entries"
@@ -150,25 +239,227 @@ impl Suggester {
                 return ControlFlow::Break;
             }
 
-            let should_remove = *expiration < start;
+            let should_remove = *expiration + stale_while_revalidate < start;
             if should_remove {
                 num_removals += 1;
             }
             ControlFlow::Continue(!should_remove)
         });
 
-        LOCK_TABLE.prune(&start);
-
         // Report finishing.
         let duration = Instant::now() - start;
-        let removed_storage = count_before_storage - items.len_storage();
-        let removed_pointers = count_before_pointers - items.len_pointers();
+        let removed_storage = count_before_storage.saturating_sub(items.len_storage());
+        let removed_pointers = count_before_pointers.saturating_sub(items.len_pointers());
         tracing::info!(
             ?duration,
             ?removed_pointers,
             ?removed_storage,
             "finished removing expired entries from cache"
         );
+
+        metrics_client
+            .gauge("cache.memory.pointers", items.len_pointers() as u64)
+            .ok();
+        metrics_client
+            .gauge("cache.memory.storage", items.len_storage() as u64)
+            .ok();
+        metrics_client
+            .gauge(
+                "cache.memory.storage-bytes",
+                items.size_storage_bytes() as u64,
+            )
+            .ok();
+    }
+}
+
+impl Suggester {
+    /// Start a background task to refresh the cache entry for `key`, unless
+    /// one is already in progress.
+    fn refresh_in_background(&self, key: String, query: SuggestionRequest) {
+        let lock = match self.locks.try_add_lock(&key, self.default_lock_timeout) {
+            Some(lock) => lock,
+            None => {
+                tracing::debug!("cache refresh already in progress");
+                return;
+            }
+        };
+
+        let inner = self.inner.clone();
+        let items = self.items.clone();
+        let locks = self.locks.clone();
+        let default_ttl = self.default_ttl;
+        let metrics_client = self.metrics_client.clone();
+        tokio::spawn(
+            async move {
+                match inner.suggest(query).await {
+                    Ok(response) => {
+                        Self::store(
+                            &items,
+                            &locks,
+                            key,
+                            lock,
+                            response,
+                            default_ttl,
+                            &metrics_client,
+                        );
+                    }
+                    Err(error) => {
+                        tracing::warn!(
+                            r#type = "cache.memory.refresh-error",
+                            %error,
+                            "Error refreshing stale cache entry"
+                        );
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Start fetching the entry for `key` from the inner provider, and register
+    /// the fetch so that other requests for the same key can wait for it.
+    ///
+    /// The fetch runs in its own task, so that it completes and is
+    /// unregistered even if every request waiting for it is dropped.
+    fn start_shared_fetch(
+        &self,
+        key: String,
+        lock: Instant,
+        query: SuggestionRequest,
+    ) -> SharedFetch {
+        let inner = self.inner.clone();
+        let items = self.items.clone();
+        let in_flight = self.in_flight.clone();
+        let locks = self.locks.clone();
+        let default_ttl = self.default_ttl;
+        let metrics_client = self.metrics_client.clone();
+        let task_key = key.clone();
+        // The fetch may finish before it is registered, so it waits for that
+        // before unregistering itself.
+        let (registered_tx, registered_rx) = oneshot::channel::<()>();
+
+        let task = tokio::spawn(
+            async move {
+                let result = match inner.suggest(query).await {
+                    Ok(response) => Ok(Self::store(
+                        &items,
+                        &locks,
+                        task_key.clone(),
+                        lock,
+                        response.with_cache_status(CacheStatus::Miss),
+                        default_ttl,
+                        &metrics_client,
+                    )),
+                    Err(error) => Err(Arc::new(error)),
+                };
+                registered_rx.await.ok();
+                in_flight.remove_if(&task_key, |_, (fetch_lock, _)| *fetch_lock == lock);
+                result
+            }
+            .in_current_span(),
+        );
+        let fetch = async move {
+            task.await.unwrap_or_else(|error| {
+                Err(Arc::new(SuggestError::Internal(
+                    anyhow!(error).context("Cache fetch task failed"),
+                )))
+            })
+        }
+        .boxed()
+        .shared();
+
+        self.in_flight.insert(key, (lock, fetch.clone()));
+        registered_tx.send(()).ok();
+        fetch
+    }
+
+    /// Wait for an in-progress fetch of `key` to complete, for at most the lock
+    /// timeout. Returns `None` if there is no such fetch, or if it took too long.
+    async fn wait_for_fetch(&self, key: &str) -> Option<Result<SuggestionResponse, SuggestError>> {
+        let fetch = self.in_flight.get(key).map(|entry| entry.1.clone())?;
+        match tokio::time::timeout(self.default_lock_timeout, fetch).await {
+            Ok(result) => Some(result.map_err(unshare_error)),
+            Err(_) => {
+                tracing::debug!("timed out waiting for in-flight fetch");
+                None
+            }
+        }
+    }
+
+    /// Store `response` in `items` at `key`, if `lock` is still the current
+    /// lock in `locks` for that key. The response's TTL will be set to the default if the
+    /// inner provider did not provide one. Any entries evicted to make room are
+    /// reported to `metrics_client`.
+    ///
+    /// This is a selfless method so that it can be called from a spawned Tokio task.
+    fn store(
+        items: &DedupedMap<String, Instant, Vec<Suggestion>>,
+        locks: &LockTable,
+        key: String,
+        lock: Instant,
+        mut response: SuggestionResponse,
+        default_ttl: Duration,
+        metrics_client: &StatsdClient,
+    ) -> SuggestionResponse {
+        locks.update(&key, lock, || {
+            // Update the cache data.
+            let now = Instant::now();
+            let cache_ttl = response.cache_ttl.get_or_insert(default_ttl);
+            let expiration = now + *cache_ttl;
+            tracing::debug!(?now, ?expiration, "inserting into cache");
+            let evicted = items.insert(key.clone(), expiration, response.suggestions.clone());
+            if evicted > 0 {
+                tracing::debug!(?evicted, "evicted entries from cache");
+                metrics_client
+                    .count("cache.memory.evicted", evicted as i64)
+                    .ok();
+            }
+        });
+        response
+    }
+}
+
+/// Turn an error from a fetch shared between requests into an error for one of
+/// those requests, keeping the kind of error.
+fn unshare_error(error: Arc<SuggestError>) -> SuggestError {
+    match Arc::try_unwrap(error) {
+        Ok(error) => error,
+        Err(error) => {
+            let kind = match *error {
+                SuggestError::Network(_) => SuggestError::Network,
+                SuggestError::Timeout(_) => SuggestError::Timeout,
+                SuggestError::Internal(_) | SuggestError::Serialization(_) => {
+                    SuggestError::Internal
+                }
+            };
+            kind(anyhow::Error::new(error))
+        }
+    }
+}
+
+impl ApproximateSize for Vec<Suggestion> {
+    fn approximate_size(&self) -> usize {
+        /// The length of the text of a URI.
+        fn uri_len(uri: &Uri) -> usize {
+            uri.scheme_str().map_or(0, str::len)
+                + uri.authority().map_or(0, |a| a.as_str().len())
+                + uri.path_and_query().map_or(0, |p| p.as_str().len())
+        }
+
+        size_of::<Self>()
+            + self
+                .iter()
+                .map(|suggestion| {
+                    size_of::<Suggestion>()
+                        + suggestion.full_keyword.len()
+                        + suggestion.title.len()
+                        + suggestion.provider.len()
+                        + uri_len(&suggestion.url)
+                        + uri_len(&suggestion.impression_url)
+                        + uri_len(&suggestion.click_url)
+                        + uri_len(&suggestion.icon)
+                })
+                .sum::<usize>()
     }
 }
 
@@ -178,22 +469,33 @@ impl SuggestionProvider for Suggester {
         format!("MemoryCache({})", self.inner.name())
     }
 
+    fn supported_locales(&self) -> Vec<LanguageIdentifier> {
+        self.inner.supported_locales()
+    }
+
+    fn location_granularity(&self) -> LocationGranularity {
+        self.inner.location_granularity()
+    }
+
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        self.inner.health_checks().await
+    }
+
     async fn suggest(
         &self,
         query: SuggestionRequest,
     ) -> Result<SuggestionResponse, merino_suggest::SuggestError> {
         let now = Instant::now();
-        let key = query.cache_key().to_string();
+        let key = query.cache_key(
+            self.inner.location_granularity(),
+            &self.inner.supported_locales(),
+        );
         let span = tracing::debug_span!("memory-suggest", ?key);
         async move {
             tracing::debug!("suggesting with memory cache");
 
             match self.items.get(&key) {
-                Some((expiration, _)) if expiration <= now => {
-                    tracing::debug!("cache expired");
-                    self.items.remove(key.clone());
-                }
-                Some((expiration, suggestions)) => {
+                Some((expiration, suggestions)) if expiration > now => {
                     tracing::debug!("cache hit");
                     return Ok(SuggestionResponse {
                         cache_status: CacheStatus::Hit,
@@ -201,38 +503,69 @@ impl SuggestionProvider for Suggester {
                         suggestions,
                     });
                 }
+                Some((expiration, suggestions))
+                    if expiration + self.stale_while_revalidate > now =>
+                {
+                    tracing::debug!("cache stale");
+                    self.refresh_in_background(key, query);
+                    return Ok(SuggestionResponse {
+                        cache_status: CacheStatus::Stale,
+                        cache_ttl: None,
+                        suggestions,
+                    });
+                }
+                Some(_) => {
+                    tracing::debug!("cache expired");
+                    self.items.remove(key.clone());
+                }
                 None => {
                     tracing::debug!("cache miss");
                 }
             }
 
-            if LOCK_TABLE.is_locked(&key) {
-                // there's a fetch already in progress. Return empty for now.
-                return Ok(SuggestionResponse {
-                    cache_status: CacheStatus::Hit,
-                    cache_ttl: None,
-                    suggestions: Vec::new(),
-                });
+            let lock = match self.locks.try_add_lock(&key, self.default_lock_timeout) {
+                Some(lock) => lock,
+                None => {
+                    // there's a fetch already in progress. Wait for it if
+                    // possible, otherwise return empty for now.
+                    if self.coalesce_requests {
+                        if let Some(result) = self.wait_for_fetch(&key).await {
+                            tracing::debug!("shared in-flight fetch");
+                            return result;
+                        }
+                    }
+                    return Ok(SuggestionResponse {
+                        cache_status: CacheStatus::Miss,
+                        cache_ttl: None,
+                        suggestions: Vec::new(),
+                    });
+                }
+            };
+
+            // handle cache miss or expired cache
+            if self.coalesce_requests {
+                return self
+                    .start_shared_fetch(key, lock, query)
+                    .await
+                    .map_err(unshare_error);
             }
 
-            // handle cache miss or stale cache
-            let lock = LOCK_TABLE.add_lock(&key, self.default_lock_timeout);
-            let mut response = self
+            let response = self
                 .inner
                 .suggest(query)
                 .await?
                 // Todo, cache status should be a vec.
                 .with_cache_status(CacheStatus::Miss);
 
-            LOCK_TABLE.update(&key, lock, || {
-                // Update the cache data.
-                let cache_ttl = response.cache_ttl.get_or_insert(self.default_ttl);
-                let expiration = now + *cache_ttl;
-                tracing::debug!(?now, ?expiration, "inserting into cache");
-                self.items
-                    .insert(key.clone(), expiration, response.suggestions.clone());
-            });
-            Ok(response)
+            Ok(Self::store(
+                &self.items,
+                &self.locks,
+                key,
+                lock,
+                response,
+                self.default_ttl,
+                &self.metrics_client,
+            ))
         }
         .instrument(span)
         .await
@@ -241,15 +574,51 @@ impl SuggestionProvider for Suggester {
 
 #[cfg(test)]
 mod tests {
-    use super::{Suggester, LOCK_TABLE};
-    use crate::deduped_map::DedupedMap;
+    use super::{unshare_error, LockTable, Suggester};
+    use crate::{deduped_map::DedupedMap, domain::CacheKey};
+    use async_trait::async_trait;
+    use cadence::{NopMetricSink, SpyMetricSink, StatsdClient};
+    use dashmap::DashMap;
     use fake::{Fake, Faker};
-    use merino_suggest::Suggestion;
+    use futures::FutureExt;
+    use merino_settings::providers::MemoryCacheConfig;
+    use merino_suggest::{
+        CacheStatus, LanguageIdentifier, LocationGranularity, SuggestError, Suggestion,
+        SuggestionProvider, SuggestionRequest, SuggestionResponse,
+    };
     use std::{
-        sync::Arc,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
         time::{Duration, Instant},
     };
 
+    /// A provider that counts how many times it has been called.
+    #[derive(Default)]
+    struct CountingProvider {
+        /// The number of calls made so far.
+        calls: Arc<AtomicUsize>,
+        /// How long each call takes.
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl SuggestionProvider for CountingProvider {
+        fn name(&self) -> String {
+            "CountingProvider".into()
+        }
+
+        async fn suggest(
+            &self,
+            _request: SuggestionRequest,
+        ) -> Result<SuggestionResponse, SuggestError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            Ok(SuggestionResponse::new(vec![Faker.fake()]))
+        }
+    }
+
     #[test]
     fn cache_maintainer_removes_expired_entries() {
         let cache: Arc<DedupedMap<String, Instant, Vec<Suggestion>>> = Arc::new(DedupedMap::new());
@@ -270,7 +639,12 @@ mod tests {
         assert!(cache.contains_key(&"current".to_owned()));
         assert!(cache.contains_key(&"expired".to_owned()));
 
-        Suggester::remove_expired_entries(&cache);
+        Suggester::remove_expired_entries(
+            &cache,
+            Duration::from_secs(0),
+            usize::MAX,
+            &StatsdClient::from_sink("merino", NopMetricSink),
+        );
 
         assert_eq!(cache.len_storage(), 1);
         assert_eq!(cache.len_pointers(), 1);
@@ -278,28 +652,362 @@ mod tests {
         assert!(!cache.contains_key(&"expired".to_owned()));
     }
 
+    #[test]
+    fn cache_maintainer_keeps_stale_entries_in_window() {
+        let cache: Arc<DedupedMap<String, Instant, Vec<Suggestion>>> = Arc::new(DedupedMap::new());
+
+        cache.insert(
+            "stale".to_string(),
+            Instant::now() - Duration::from_secs(30),
+            vec![Faker.fake()],
+        );
+        cache.insert(
+            "expired".to_string(),
+            Instant::now() - Duration::from_secs(300),
+            vec![Faker.fake()],
+        );
+
+        Suggester::remove_expired_entries(
+            &cache,
+            Duration::from_secs(60),
+            usize::MAX,
+            &StatsdClient::from_sink("merino", NopMetricSink),
+        );
+
+        assert!(cache.contains_key(&"stale".to_owned()));
+        assert!(!cache.contains_key(&"expired".to_owned()));
+    }
+
+    #[test]
+    fn cache_maintainer_respects_max_removals() {
+        let cache: Arc<DedupedMap<String, Instant, Vec<Suggestion>>> = Arc::new(DedupedMap::new());
+
+        for i in 0..5 {
+            cache.insert(
+                format!("expired-{}", i),
+                Instant::now() - Duration::from_secs(300),
+                vec![Faker.fake()],
+            );
+        }
+
+        Suggester::remove_expired_entries(
+            &cache,
+            Duration::from_secs(0),
+            2,
+            &StatsdClient::from_sink("merino", NopMetricSink),
+        );
+
+        assert_eq!(cache.len_pointers(), 3);
+    }
+
+    #[test]
+    fn configs_without_max_entries_are_bounded() -> anyhow::Result<()> {
+        let config: MemoryCacheConfig = serde_json::from_value(serde_json::json!({
+            "default_ttl_sec": 900,
+            "cleanup_interval_sec": 300,
+            "max_removed_entries": 100_000,
+            "default_lock_timeout_sec": 10,
+            "inner": {"type": "null"},
+        }))?;
+        assert_eq!(config.max_entries, MemoryCacheConfig::default().max_entries);
+
+        let config: MemoryCacheConfig = serde_json::from_value(serde_json::json!({
+            "default_ttl_sec": 900,
+            "cleanup_interval_sec": 300,
+            "max_removed_entries": 100_000,
+            "max_entries": null,
+            "default_lock_timeout_sec": 10,
+            "inner": {"type": "null"},
+        }))?;
+        assert_eq!(config.max_entries, None);
+        Ok(())
+    }
+
+    #[test]
+    fn evictions_are_reported() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics_client = StatsdClient::from_sink("test", sink);
+        let items = DedupedMap::with_limits(Some(2), None);
+        let locks = LockTable::default();
+
+        for i in 0..3 {
+            let key = format!("evictions-are-reported-{}", i);
+            let lock = locks
+                .try_add_lock(&key, Duration::from_secs(3))
+                .expect("key should not be locked");
+            let response = SuggestionResponse::new(vec![Faker.fake()]);
+            Suggester::store(
+                &items,
+                &locks,
+                key,
+                lock,
+                response,
+                Duration::from_secs(300),
+                &metrics_client,
+            );
+        }
+
+        assert!(items.len_pointers() <= 2);
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|bytes| String::from_utf8(bytes).unwrap())
+            .collect();
+        assert!(
+            metrics
+                .iter()
+                .any(|m| m.starts_with("test.cache.memory.evicted:")),
+            "{:?}",
+            metrics
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_entries_are_served_while_refreshing() -> anyhow::Result<()> {
+        let provider = CountingProvider::default();
+        let calls = provider.calls.clone();
+        let config = MemoryCacheConfig {
+            stale_while_revalidate: Duration::from_secs(60),
+            ..MemoryCacheConfig::default()
+        };
+        let suggester = Suggester::new_boxed(
+            &config,
+            Box::new(provider),
+            StatsdClient::from_sink("merino", NopMetricSink),
+        );
+
+        let request: SuggestionRequest = Faker.fake();
+        let stale_suggestions: Vec<Suggestion> = vec![Faker.fake()];
+        suggester.items.insert(
+            request.cache_key(LocationGranularity::None, &[LanguageIdentifier::Wildcard]),
+            Instant::now() - Duration::from_secs(30),
+            stale_suggestions.clone(),
+        );
+
+        // Both requests get the stale data, but only one refresh is started.
+        for _ in 0..2 {
+            let response = suggester.suggest(request.clone()).await?;
+            assert_eq!(response.cache_status, CacheStatus::Stale);
+            assert_eq!(response.suggestions, stale_suggestions);
+        }
+
+        // Wait for the background refresh to replace the stale entry.
+        let mut response = suggester.suggest(request.clone()).await?;
+        for _ in 0..100 {
+            if response.cache_status == CacheStatus::Hit {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            response = suggester.suggest(request.clone()).await?;
+        }
+        assert_eq!(response.cache_status, CacheStatus::Hit);
+        assert_ne!(response.suggestions, stale_suggestions);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn entries_past_the_stale_window_are_refreshed_immediately() -> anyhow::Result<()> {
+        let provider = CountingProvider::default();
+        let calls = provider.calls.clone();
+        let config = MemoryCacheConfig {
+            stale_while_revalidate: Duration::from_secs(60),
+            ..MemoryCacheConfig::default()
+        };
+        let suggester = Suggester::new_boxed(
+            &config,
+            Box::new(provider),
+            StatsdClient::from_sink("merino", NopMetricSink),
+        );
+
+        let request: SuggestionRequest = Faker.fake();
+        let expired_suggestions: Vec<Suggestion> = vec![Faker.fake()];
+        suggester.items.insert(
+            request.cache_key(LocationGranularity::None, &[LanguageIdentifier::Wildcard]),
+            Instant::now() - Duration::from_secs(300),
+            expired_suggestions.clone(),
+        );
+
+        let response = suggester.suggest(request).await?;
+        assert_eq!(response.cache_status, CacheStatus::Miss);
+        assert_ne!(response.suggestions, expired_suggestions);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_share_one_fetch() -> anyhow::Result<()> {
+        let provider = CountingProvider {
+            delay: Duration::from_millis(100),
+            ..CountingProvider::default()
+        };
+        let calls = provider.calls.clone();
+        let config = MemoryCacheConfig {
+            coalesce_requests: true,
+            ..MemoryCacheConfig::default()
+        };
+        let suggester = Suggester::new_boxed(
+            &config,
+            Box::new(provider),
+            StatsdClient::from_sink("merino", NopMetricSink),
+        );
+
+        let request: SuggestionRequest = Faker.fake();
+        let (first, second) = futures::join!(
+            suggester.suggest(request.clone()),
+            suggester.suggest(request)
+        );
+        let (first, second) = (first?, second?);
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(first.suggestions.len(), 1);
+        assert_eq!(first.suggestions, second.suggestions);
+        assert_eq!(second.cache_status, CacheStatus::Miss);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn caches_do_not_share_locks() -> anyhow::Result<()> {
+        let config = MemoryCacheConfig {
+            coalesce_requests: true,
+            ..MemoryCacheConfig::default()
+        };
+        let make_suggester = || {
+            Suggester::new_boxed(
+                &config,
+                Box::new(CountingProvider {
+                    delay: Duration::from_millis(100),
+                    ..CountingProvider::default()
+                }),
+                StatsdClient::from_sink("merino", NopMetricSink),
+            )
+        };
+        let (first_suggester, second_suggester) = (make_suggester(), make_suggester());
+
+        let request: SuggestionRequest = Faker.fake();
+        let (first, second) = futures::join!(
+            first_suggester.suggest(request.clone()),
+            second_suggester.suggest(request)
+        );
+
+        assert_eq!(first?.suggestions.len(), 1);
+        assert_eq!(second?.suggestions.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn shared_errors_keep_their_kind() {
+        let error = Arc::new(SuggestError::Timeout(anyhow::anyhow!("too slow")));
+        let _other_waiter = error.clone();
+        assert!(matches!(unshare_error(error), SuggestError::Timeout(_)));
+
+        let error = Arc::new(SuggestError::Network(anyhow::anyhow!("unreachable")));
+        assert!(matches!(unshare_error(error), SuggestError::Network(_)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_are_empty_without_coalescing() -> anyhow::Result<()> {
+        let provider = CountingProvider {
+            delay: Duration::from_millis(100),
+            ..CountingProvider::default()
+        };
+        let suggester = Suggester::new_boxed(
+            &MemoryCacheConfig::default(),
+            Box::new(provider),
+            StatsdClient::from_sink("merino", NopMetricSink),
+        );
+
+        let request: SuggestionRequest = Faker.fake();
+        let (first, second) = futures::join!(
+            suggester.suggest(request.clone()),
+            suggester.suggest(request)
+        );
+
+        assert_eq!(first?.suggestions.len(), 1);
+        assert!(second?.suggestions.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetches_finish_when_the_first_request_is_dropped() -> anyhow::Result<()> {
+        let provider = CountingProvider {
+            delay: Duration::from_millis(50),
+            ..CountingProvider::default()
+        };
+        let calls = provider.calls.clone();
+        let config = MemoryCacheConfig {
+            coalesce_requests: true,
+            ..MemoryCacheConfig::default()
+        };
+        let suggester = Suggester::new_boxed(
+            &config,
+            Box::new(provider),
+            StatsdClient::from_sink("merino", NopMetricSink),
+        );
+
+        // Start a fetch, and give up on it before it finishes.
+        let request: SuggestionRequest = Faker.fake();
+        let first = tokio::time::timeout(
+            Duration::from_millis(10),
+            suggester.suggest(request.clone()),
+        )
+        .await;
+        assert!(first.is_err());
+
+        // Nothing is waiting for the fetch, but it still completes.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(suggester.in_flight.is_empty());
+        let response = suggester.suggest(request).await?;
+        assert_eq!(response.cache_status, CacheStatus::Hit);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetches_with_expired_locks_are_forgotten() {
+        let in_flight = DashMap::new();
+        let now = Instant::now();
+        for (key, lock) in [
+            ("current", now + Duration::from_secs(10)),
+            ("stale", now - Duration::from_secs(10)),
+        ] {
+            let fetch = futures::future::pending().boxed().shared();
+            in_flight.insert(key.to_string(), (lock, fetch));
+        }
+
+        Suggester::remove_stale_fetches(&in_flight, now);
+
+        assert!(in_flight.contains_key("current"));
+        assert!(!in_flight.contains_key("stale"));
+    }
+
     #[test]
     fn cache_lock_test() {
         let lock_name = "testLock";
         let other_lock_name = "otherLock";
         let timeout = Duration::from_secs(3);
-        let lock = LOCK_TABLE.add_lock(lock_name, timeout);
+        let locks = LockTable::default();
+        let lock = locks.try_add_lock(lock_name, timeout).unwrap();
         let mut lock_check = false;
-        LOCK_TABLE.add_lock(other_lock_name, timeout);
-        assert!(LOCK_TABLE.is_locked(lock_name));
-        assert!(!LOCK_TABLE.is_locked("unlocked"));
+        locks.try_add_lock(other_lock_name, timeout).unwrap();
+        assert!(locks.is_locked(lock_name));
+        assert!(!locks.is_locked("unlocked"));
+
+        // Should fail, already locked
+        assert!(locks.try_add_lock(lock_name, timeout).is_none());
 
-        LOCK_TABLE.update(lock_name, lock, || lock_check = true);
+        locks.update(lock_name, lock, || lock_check = true);
 
         assert!(lock_check);
-        assert!(!LOCK_TABLE.is_locked(lock_name));
+        assert!(!locks.is_locked(lock_name));
 
         // Should fail, lock dismissed
-        LOCK_TABLE.update(lock_name, lock, || lock_check = false);
+        locks.update(lock_name, lock, || lock_check = false);
         assert!(lock_check);
 
         // Should fail, wrong lock value
-        LOCK_TABLE.update(other_lock_name, lock, || lock_check = false);
+        locks.update(other_lock_name, lock, || lock_check = false);
         assert!(lock_check);
     }
 }
@@ -5,13 +5,14 @@ mod domain;
 use std::{convert::TryInto, time::Duration};
 
 use crate::{domain::CacheKey, redis::domain::RedisSuggestions};
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
+use cadence::{CountedExt, StatsdClient};
 use fix_hidden_lifetime_bug::fix_hidden_lifetime_bug;
 use merino_settings::{providers::RedisCacheConfig, Settings};
 use merino_suggest::{
-    CacheStatus, SetupError, SuggestError, Suggestion, SuggestionProvider, SuggestionRequest,
-    SuggestionResponse,
+    CacheStatus, HealthCheck, LanguageIdentifier, LocationGranularity, SetupError, SuggestError,
+    Suggestion, SuggestionProvider, SuggestionRequest, SuggestionResponse,
 };
 use redis::RedisError;
 use tracing_futures::{Instrument, WithSubscriber};
@@ -19,6 +20,9 @@ use uuid::Uuid;
 
 use self::domain::RedisTtl;
 
+/// How long to wait for Redis to respond to a health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// A suggester that uses Redis to cache previous results.
 pub struct Suggester {
     /// The suggester to query on cache-miss.
@@ -31,8 +35,36 @@ pub struct Suggester {
     /// `inner`.
     default_ttl: Duration,
 
+    /// The bounds that TTLs of cache entries are clamped to.
+    ttl_bounds: TtlBounds,
+
     /// Default lock timeout
     default_lock_timeout: Duration,
+
+    /// Whether requests for a locked entry should wait for it to be stored.
+    coalesce_requests: bool,
+
+    /// How often to check on a locked entry while waiting for it.
+    lock_poll_interval: Duration,
+
+    /// The client used to report metrics about the cache.
+    metrics_client: StatsdClient,
+}
+
+/// The shortest and longest time that an entry may be stored in the cache.
+#[derive(Debug, Clone, Copy)]
+struct TtlBounds {
+    /// The shortest allowed TTL.
+    min: Duration,
+    /// The longest allowed TTL.
+    max: Duration,
+}
+
+impl TtlBounds {
+    /// Clamp `ttl` to be within these bounds.
+    fn clamp(&self, ttl: Duration) -> Duration {
+        ttl.max(self.min).min(self.max)
+    }
 }
 
 #[derive(Debug)]
@@ -174,7 +206,9 @@ impl Suggester {
     /// Opens a connection to Redis.
     ///
     /// # Errors
-    /// Fails if it cannot connect to Redis.
+    /// Fails if the configured TTL bounds, default TTL, or lock poll interval
+    /// are invalid, or if it cannot connect to Redis. Redis stores TTLs in
+    /// whole seconds, so `min_ttl` must be at least one second.
 
     #[allow(clippy::manual_async_fn)]
     #[fix_hidden_lifetime_bug]
@@ -182,7 +216,39 @@ impl Suggester {
         settings: &Settings,
         config: &RedisCacheConfig,
         provider: Box<dyn SuggestionProvider + 'static>,
+        metrics_client: StatsdClient,
     ) -> Result<Box<Self>, SetupError> {
+        if config.min_ttl < Duration::from_secs(1) {
+            return Err(SetupError::InvalidConfiguration(anyhow!(
+                "Redis cache min_ttl ({:?}) must be at least one second",
+                config.min_ttl
+            )));
+        }
+        if config.min_ttl > config.max_ttl {
+            return Err(SetupError::InvalidConfiguration(anyhow!(
+                "Redis cache min_ttl ({:?}) must not be greater than max_ttl ({:?})",
+                config.min_ttl,
+                config.max_ttl
+            )));
+        }
+        if config.default_ttl < config.min_ttl || config.default_ttl > config.max_ttl {
+            return Err(SetupError::InvalidConfiguration(anyhow!(
+                "Redis cache default_ttl ({:?}) must be between min_ttl ({:?}) and max_ttl ({:?})",
+                config.default_ttl,
+                config.min_ttl,
+                config.max_ttl
+            )));
+        }
+        if config.lock_poll_interval.is_zero() {
+            return Err(SetupError::InvalidConfiguration(anyhow!(
+                "Redis cache lock_poll_interval must be greater than zero"
+            )));
+        }
+        let ttl_bounds = TtlBounds {
+            min: config.min_ttl,
+            max: config.max_ttl,
+        };
+
         tracing::debug!(?settings.redis.url, "Setting up redis connection");
         let client = redis::Client::open(settings.redis.url.clone())
             .context("Setting up Redis client")
@@ -197,7 +263,11 @@ impl Suggester {
             inner: provider,
             redis_connection,
             default_ttl: config.default_ttl,
+            ttl_bounds,
             default_lock_timeout: config.default_lock_timeout,
+            coalesce_requests: config.coalesce_requests,
+            lock_poll_interval: config.lock_poll_interval,
+            metrics_client,
         }))
     }
 
@@ -255,6 +325,38 @@ impl Suggester {
         }
     }
 
+    /// Wait for another request to store the entry for `key`, which it holds
+    /// the lock for, checking every `lock_poll_interval` for at most the lock
+    /// timeout.
+    ///
+    /// Returns `None` if the lock is released without an entry being stored,
+    /// or if waiting takes too long.
+    async fn wait_for_locked_key(
+        &self,
+        key: &str,
+    ) -> Result<Option<SuggestionResponse>, SuggestError> {
+        let mut rlock = SimpleRedisLock::from(&self.redis_connection);
+        let deadline = tokio::time::Instant::now() + self.default_lock_timeout;
+
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(self.lock_poll_interval).await;
+            // The entry is stored and the lock released atomically, so the
+            // lock must be checked first to not miss an entry stored between
+            // the two checks.
+            let still_locked = rlock.is_locked(key).await?;
+            if let CacheCheckResult::Hit(response) = self.get_key(key).await? {
+                return Ok(Some(response));
+            }
+            if !still_locked {
+                tracing::debug!(%key, "lock released without storing an entry");
+                return Ok(None);
+            }
+        }
+
+        tracing::debug!(%key, "timed out waiting for locked cache entry");
+        Ok(None)
+    }
+
     /// Queue a command to store an entry in the cache.
     ///
     /// This runs as a separate task, and this function returns before the
@@ -268,20 +370,25 @@ impl Suggester {
         key: &str,
         suggestions: Vec<Suggestion>,
         lock: String,
+        ttl: Duration,
     ) -> Result<(), SuggestError> {
         let connection = self.redis_connection.clone();
+        let metrics_client = self.metrics_client.clone();
         let key = key.to_string();
         let span = tracing::info_span!("storing-cache-entry", %key);
-        let ttl = self.default_ttl;
 
         tokio::task::spawn(
             async move {
                 let mut rlock = SimpleRedisLock::from(&connection);
                 let to_store = RedisSuggestions(suggestions);
-                rlock
+                // The error itself is logged by `write_if_locked`.
+                if rlock
                     .write_if_locked(&key, &lock, to_store, ttl)
                     .await
-                    .expect("Could not write data");
+                    .is_err()
+                {
+                    metrics_client.incr("cache.redis.save-error").ok();
+                }
             }
             .with_current_subscriber()
             .instrument(span),
@@ -352,11 +459,39 @@ impl SuggestionProvider for Suggester {
         format!("RedisCache({})", self.inner.name())
     }
 
+    fn supported_locales(&self) -> Vec<LanguageIdentifier> {
+        self.inner.supported_locales()
+    }
+
+    fn location_granularity(&self) -> LocationGranularity {
+        self.inner.location_granularity()
+    }
+
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        let mut connection = self.redis_connection.clone();
+        let ping_command = redis::cmd("PING");
+        let ping = ping_command.query_async::<_, String>(&mut connection);
+        let check = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, ping).await {
+            Ok(Ok(_)) => HealthCheck::ok(self.name()),
+            Ok(Err(error)) => {
+                HealthCheck::error(self.name(), format!("Could not ping Redis: {}", error))
+            }
+            Err(_) => HealthCheck::error(self.name(), "Redis did not respond to ping in time"),
+        };
+
+        let mut checks = self.inner.health_checks().await;
+        checks.push(check);
+        checks
+    }
+
     async fn suggest(
         &self,
         request: SuggestionRequest,
     ) -> Result<SuggestionResponse, SuggestError> {
-        let key = request.cache_key();
+        let key = request.cache_key(
+            self.inner.location_granularity(),
+            &self.inner.supported_locales(),
+        );
         let mut rlock = SimpleRedisLock::from(&self.redis_connection);
 
         let cache_result = self.get_key(&key).await?;
@@ -367,19 +502,25 @@ impl SuggestionProvider for Suggester {
         } else {
             if rlock.is_locked(&key).await? {
                 tracing::debug!(%key, "cache updating...");
+                if self.coalesce_requests {
+                    if let Some(response) = self.wait_for_locked_key(&key).await? {
+                        tracing::debug!(%key, "cache filled while waiting");
+                        return Ok(response);
+                    }
+                }
                 // A "pending" review may not yet have content (e.g. it's the initial lookup), otherwise it's a "Hit"
                 let response =
                     SuggestionResponse::new(Vec::new()).with_cache_status(CacheStatus::Miss);
                 return Ok(response);
             }
             let response = if let Some(lock) = rlock.lock(&key, self.default_lock_timeout).await? {
-                let response = self
-                    .inner
-                    .suggest(request)
-                    .await?
-                    .with_cache_ttl(self.default_ttl);
+                let response = self.inner.suggest(request).await?;
+                let ttl = response
+                    .cache_ttl
+                    .map_or(self.default_ttl, |ttl| self.ttl_bounds.clamp(ttl));
+                let response = response.with_cache_ttl(ttl);
 
-                self.queue_store_key(&key, response.suggestions.clone(), lock)?;
+                self.queue_store_key(&key, response.suggestions.clone(), lock, ttl)?;
 
                 if let CacheCheckResult::Miss = cache_result {
                     tracing::debug!(%key, "cache miss");
@@ -401,13 +542,14 @@ impl SuggestionProvider for Suggester {
 mod test {
     use std::time::Duration;
 
-    use crate::redis::{domain::RedisSuggestions, SimpleRedisLock};
+    use crate::redis::{domain::RedisSuggestions, SimpleRedisLock, Suggester, TtlBounds};
 
     use super::SetupError;
     use anyhow::Context;
+    use cadence::{NopMetricSink, StatsdClient};
     use http::Uri;
-    use merino_settings::Settings;
-    use merino_suggest::{Proportion, Suggestion};
+    use merino_settings::{providers::RedisCacheConfig, Settings};
+    use merino_suggest::{NullProvider, Proportion, Suggestion};
 
     #[tokio::test]
     async fn check_cache() -> Result<(), SetupError> {
@@ -499,4 +641,77 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn ttls_are_clamped_to_bounds() {
+        let bounds = TtlBounds {
+            min: Duration::from_secs(60),
+            max: Duration::from_secs(3600),
+        };
+        assert_eq!(
+            bounds.clamp(Duration::from_secs(0)),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            bounds.clamp(Duration::from_secs(900)),
+            Duration::from_secs(900)
+        );
+        assert_eq!(
+            bounds.clamp(Duration::from_secs(86400)),
+            Duration::from_secs(3600)
+        );
+    }
+
+    #[tokio::test]
+    async fn zero_lock_poll_interval_is_rejected() {
+        let config = RedisCacheConfig {
+            lock_poll_interval: Duration::ZERO,
+            ..RedisCacheConfig::default()
+        };
+        let result = Suggester::new_boxed(
+            &Settings::load_for_tests(),
+            &config,
+            Box::new(NullProvider),
+            StatsdClient::from_sink("merino", NopMetricSink),
+        )
+        .await;
+        assert!(matches!(result, Err(SetupError::InvalidConfiguration(_))));
+    }
+
+    #[tokio::test]
+    async fn default_ttl_outside_of_bounds_is_rejected() {
+        for default_ttl in &[
+            Duration::from_secs(30),
+            Duration::from_secs(2 * 24 * 60 * 60),
+        ] {
+            let config = RedisCacheConfig {
+                default_ttl: *default_ttl,
+                ..RedisCacheConfig::default()
+            };
+            let result = Suggester::new_boxed(
+                &Settings::load_for_tests(),
+                &config,
+                Box::new(NullProvider),
+                StatsdClient::from_sink("merino", NopMetricSink),
+            )
+            .await;
+            assert!(matches!(result, Err(SetupError::InvalidConfiguration(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn zero_min_ttl_is_rejected() {
+        let config = RedisCacheConfig {
+            min_ttl: Duration::ZERO,
+            ..RedisCacheConfig::default()
+        };
+        let result = Suggester::new_boxed(
+            &Settings::load_for_tests(),
+            &config,
+            Box::new(NullProvider),
+            StatsdClient::from_sink("merino", NopMetricSink),
+        )
+        .await;
+        assert!(matches!(result, Err(SetupError::InvalidConfiguration(_))));
+    }
 }
@@ -178,3 +178,62 @@ async fn missing_ttls_are_re_set(
             )
     });
 }
+
+#[merino_test_macro(|settings| {
+    settings.debug = true;
+    settings.suggestion_providers.insert(
+        "wiki_fruit_redis".to_string(),
+        SuggestionProviderConfig::RedisCache(RedisCacheConfig {
+            default_ttl: Duration::from_secs(900),
+            max_ttl: Duration::from_secs(120),
+            ..RedisCacheConfig::with_inner(SuggestionProviderConfig::WikiFruit)
+        }),
+    );
+})]
+async fn ttls_are_clamped_to_the_configured_maximum(
+    TestingTools {
+        test_client,
+        mut redis_client,
+        ..
+    }: TestingTools,
+) {
+    let response = test_client
+        .get("/api/v1/suggest?q=apple")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+
+    let keys: Vec<String> = redis_client.keys("*").expect("Could not get keys");
+    assert_eq!(keys.len(), 1, "an item should be in the cache");
+    let ttl: i64 = redis_client.ttl(&keys[0]).expect("Could not get TTL");
+    assert!(ttl > 0 && ttl <= 120, "TTL {} should be clamped", ttl);
+}
+
+#[merino_test_macro(|settings| {
+    settings.debug = true;
+    settings.suggestion_providers.insert(
+        "wiki_fruit_redis".to_string(),
+        SuggestionProviderConfig::RedisCache(RedisCacheConfig::with_inner(SuggestionProviderConfig::WikiFruit)),
+    );
+})]
+async fn heartbeat_checks_redis_connection(TestingTools { test_client, .. }: TestingTools) {
+    // Make a request so that the providers are built.
+    let response = test_client
+        .get("/api/v1/suggest?q=apple")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test_client
+        .get("/__heartbeat__")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.json().await.expect("body was not json");
+    assert_eq!(body["checks"]["provider.RedisCache(WikiFruit)"], "ok");
+}
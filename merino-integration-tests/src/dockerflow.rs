@@ -118,3 +118,89 @@ mod error {
         Ok(())
     }
 }
+
+mod readiness {
+    use crate::{merino_test_macro, TestingTools};
+    use anyhow::Result;
+    use merino_settings::providers::{
+        DeviceTargetingConfig, DeviceTargetingRule, SuggestionProviderConfig, SuggestionSelector,
+        TargetingAction,
+    };
+    use reqwest::StatusCode;
+    use serde_json::Value;
+    use std::time::Duration;
+
+    #[merino_test_macro(|settings| {
+        settings.provider_startup.eager = true;
+        settings.suggestion_providers.insert(
+            "broken".to_string(),
+            SuggestionProviderConfig::DeviceTargeting(DeviceTargetingConfig {
+                rules: vec![DeviceTargetingRule {
+                    action: TargetingAction::Drop,
+                    applies_to: SuggestionSelector::All,
+                    min_firefox_version: None,
+                    form_factors: Some(vec!["smartwatch".to_string()]),
+                    os_families: None,
+                }],
+                inner: Box::new(SuggestionProviderConfig::Null),
+            }),
+        );
+    })]
+    async fn lbheartbeat_is_not_ready_until_providers_are_built(
+        TestingTools { test_client, .. }: TestingTools,
+    ) -> Result<()> {
+        let response = test_client
+            .get("/__lbheartbeat__")
+            .send()
+            .await
+            .expect("failed to execute request");
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = test_client
+            .get("/__heartbeat__")
+            .send()
+            .await
+            .expect("failed to execute request");
+        let body: Value = response.json().await?;
+        assert_eq!(body["checks"]["provider.broken.ready"], "warn");
+        assert_eq!(body["status"], "warn");
+        Ok(())
+    }
+
+    #[merino_test_macro(|settings| {
+        settings.provider_startup.eager = true;
+        settings
+            .suggestion_providers
+            .insert("null".to_string(), SuggestionProviderConfig::Null);
+    })]
+    async fn lbheartbeat_is_ready_once_providers_are_built(
+        TestingTools { test_client, .. }: TestingTools,
+    ) -> Result<()> {
+        let mut status = None;
+        for _ in 0..20 {
+            let response = test_client
+                .get("/__lbheartbeat__")
+                .send()
+                .await
+                .expect("failed to execute request");
+            status = Some(response.status());
+            if response.status() == StatusCode::OK {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(status, Some(StatusCode::OK));
+
+        let response = test_client
+            .get("/__heartbeat__")
+            .send()
+            .await
+            .expect("failed to execute request");
+        let body: Value = response.json().await?;
+        assert_eq!(body["checks"]["provider.null.ready"], "ok");
+        // Location lookup isn't configured, so it isn't checked.
+        assert_eq!(body["checks"]["maxmind"], Value::Null);
+        assert_eq!(body["status"], "ok");
+        Ok(())
+    }
+}
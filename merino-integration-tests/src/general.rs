@@ -34,3 +34,28 @@ async fn root_of_services_has_a_fallback_message(
 
     Ok(())
 }
+
+#[merino_test_macro(|settings| settings.metrics.mode = merino_settings::MetricsMode::Prometheus)]
+async fn prometheus_metrics_are_served(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client.get("/__metrics__").send().await?;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("text/plain")));
+
+    Ok(())
+}
+
+#[merino_test_macro(|settings| settings.metrics.mode = merino_settings::MetricsMode::Statsd)]
+async fn prometheus_metrics_are_not_served_with_statsd(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client.get("/__metrics__").send().await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    Ok(())
+}
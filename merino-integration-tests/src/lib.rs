@@ -40,12 +40,15 @@ mod debug;
 mod dockerflow;
 mod general;
 mod logging;
+mod rate_limit;
 mod suggest;
+mod telemetry;
 mod utils;
 
 pub use crate::utils::{
     logging::{LogWatcher, TracingJsonEvent},
     metrics::MetricsWatcher,
+    span_collector::{ExportedSpan, SpanCollector},
     test_tools::{merino_test, TestingTools},
 };
 
@@ -0,0 +1,57 @@
+//! Tests that clients making too many suggestion requests are limited.
+#![cfg(test)]
+
+use crate::{merino_test_macro, TestingTools};
+use anyhow::Result;
+use merino_settings::{providers::SuggestionProviderConfig, RateLimitStorage};
+use reqwest::StatusCode;
+use serde_json::Value;
+
+#[merino_test_macro(|settings| {
+    settings.debug = true;
+    settings.suggestion_providers.insert("wiki_fruit".to_string(), SuggestionProviderConfig::WikiFruit);
+    settings.rate_limit.enabled = true;
+    settings.rate_limit.burst = 2;
+    settings.rate_limit.requests_per_second = 0.1;
+    settings.rate_limit.storage = RateLimitStorage::Memory;
+})]
+async fn requests_over_the_limit_are_rejected(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    for _ in 0..2 {
+        let response = test_client.get("/api/v1/suggest?q=apple").send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let response = test_client.get("/api/v1/suggest?q=apple").send().await?;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key("retry-after"));
+    let body: Value = response.json().await?;
+    assert_eq!(body["code"], "rate-limited");
+
+    // Health checks are not limited.
+    let response = test_client.get("/__lbheartbeat__").send().await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[merino_test_macro(|settings| {
+    settings.debug = true;
+    settings.suggestion_providers.insert("wiki_fruit".to_string(), SuggestionProviderConfig::WikiFruit);
+    settings.rate_limit.enabled = true;
+    settings.rate_limit.burst = 1;
+    settings.rate_limit.requests_per_second = 0.1;
+    settings.rate_limit.storage = RateLimitStorage::Redis;
+})]
+async fn limits_can_be_shared_in_redis(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client.get("/api/v1/suggest?q=apple").send().await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test_client.get("/api/v1/suggest?q=apple").send().await?;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    Ok(())
+}
@@ -4,10 +4,13 @@
 use crate::{merino_test_macro, TestingTools};
 use anyhow::Result;
 use httpmock::{Method::GET, MockServer};
-use merino_settings::providers::{RemoteSettingsConfig, SuggestionProviderConfig};
+use merino_settings::{
+    providers::{RemoteSettingsConfig, SuggestionProviderConfig},
+    ExperimentSettings, ExperimentVariantSettings,
+};
 use reqwest::StatusCode;
 use serde_json::json;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 #[merino_test_macro(|settings| {
     // Wiki fruit is only enabled when debug is true.
@@ -27,6 +30,27 @@ async fn suggest_wikifruit_works(TestingTools { test_client, .. }: TestingTools)
     Ok(())
 }
 
+#[merino_test_macro(|settings| {
+    // Wiki fruit is only enabled when debug is true.
+    settings.debug = true;
+    settings.suggestion_providers.insert("wiki_fruit".to_string(), SuggestionProviderConfig::WikiFruit);
+})]
+async fn suggest_normalizes_queries(TestingTools { test_client, .. }: TestingTools) -> Result<()> {
+    let response = test_client
+        .get("/api/v1/suggest?q=%20%20APPLE%20")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await?;
+    assert_eq!(
+        body["suggestions"][0]["url"],
+        json!("https://en.wikipedia.org/wiki/Apple")
+    );
+
+    Ok(())
+}
+
 #[merino_test_macro(|settings| {
     // Wiki fruit is only enabled when debug is true.
     settings.debug = true;
@@ -105,6 +129,38 @@ async fn test_expected_variant_fields(
     Ok(())
 }
 
+#[merino_test_macro(|settings| {
+    settings.experiments.insert(
+        "test_experiment".to_string(),
+        ExperimentSettings {
+            variants: vec![ExperimentVariantSettings {
+                name: "treatment".to_string(),
+                traffic_percentage: 100.0,
+                suggestion_providers: HashMap::new(),
+            }],
+        },
+    );
+})]
+async fn test_returns_server_variants(
+    TestingTools {
+        test_client,
+        mut metrics_watcher,
+        ..
+    }: TestingTools,
+) -> Result<()> {
+    let response = test_client
+        .get("/api/v1/suggest?q=apple&bucket_id=test")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await?;
+    assert_eq!(body["server_variants"], json!(["treatment"]));
+    assert!(metrics_watcher.has_incr_with_tags("server_variants", &[("variant", "treatment")]));
+
+    Ok(())
+}
+
 #[merino_test_macro(|settings| {
     settings.suggestion_providers.insert(
         "adm".to_string(),
@@ -179,6 +235,35 @@ async fn suggest_records_suggestion_metrics(
     Ok(())
 }
 
+#[merino_test_macro(|settings| {
+    settings.debug = true;
+    settings.suggestion_providers.insert("wiki_fruit".to_string(), SuggestionProviderConfig::WikiFruit);
+})]
+async fn suggest_records_provider_metrics(
+    TestingTools {
+        test_client,
+        mut metrics_watcher,
+        ..
+    }: TestingTools,
+) -> Result<()> {
+    test_client.get("/api/v1/suggest?q=apple").send().await?;
+
+    assert!(metrics_watcher.has(|msg| {
+        msg.name == "provider.duration"
+            && msg
+                .tags
+                .as_ref()
+                .and_then(|tags| tags.get("provider"))
+                .map(String::as_str)
+                == Some("WikiFruit")
+    }));
+    assert!(metrics_watcher.has(|msg| {
+        msg.name == "provider.suggestions"
+            && matches!(&msg.metric, statsd_parser::Metric::Histogram(h) if (h.value - 1.0).abs() < 0.0001)
+    }));
+    Ok(())
+}
+
 #[merino_test_macro]
 async fn suggest_records_client_variants_metrics(
     TestingTools {
@@ -192,7 +277,173 @@ async fn suggest_records_client_variants_metrics(
         .send()
         .await?;
 
-    assert!(metrics_watcher.has_incr("client_variants.one"));
-    assert!(metrics_watcher.has_incr("client_variants.two"));
+    assert!(metrics_watcher.has_incr_with_tags("client_variants", &[("variant", "one")]));
+    assert!(metrics_watcher.has_incr_with_tags("client_variants", &[("variant", "two")]));
+    Ok(())
+}
+
+#[merino_test_macro]
+async fn suggest_without_query_is_a_bad_request(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client
+        .get("/api/v1/suggest")
+        .header("X-Request-Id", "missing-query-test")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.headers()["x-request-id"], "missing-query-test");
+    let body: serde_json::Value = response.json().await?;
+    assert_eq!(
+        body,
+        json!({
+            "code": "missing-query",
+            "message": "Missing query parameter: q",
+            "request_id": "missing-query-test",
+        })
+    );
+    Ok(())
+}
+
+#[merino_test_macro(|settings| {
+    settings.suggest_api.max_query_length = 5;
+})]
+async fn suggest_with_overlong_query_is_a_bad_request(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client.get("/api/v1/suggest?q=apple").send().await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test_client.get("/api/v1/suggest?q=apples").send().await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let request_id = response.headers()["x-request-id"].to_str()?.to_string();
+    let body: serde_json::Value = response.json().await?;
+    assert_eq!(body["code"], "query-too-long");
+    assert_eq!(body["request_id"], request_id);
+    Ok(())
+}
+
+#[merino_test_macro]
+async fn suggest_with_malformed_header_is_a_bad_request(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client
+        .get("/api/v1/suggest?q=apple")
+        .header("Accept-Language", "en-US;3")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = response.json().await?;
+    assert_eq!(body["code"], "malformed-header");
+    assert_eq!(body["message"], "Malformed header: Accept-Language");
+    Ok(())
+}
+
+#[merino_test_macro(|settings| {
+    settings.debug = true;
+    settings.suggestion_providers.insert("wiki_fruit".to_string(), SuggestionProviderConfig::WikiFruit);
+})]
+async fn suggest_batch_returns_results_in_order(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client
+        .post("/api/v1/suggest/batch")
+        .json(&json!({
+            "requests": [
+                {"q": "banana"},
+                {"q": "apple", "locale": "en-US", "location": {"country": "US"}},
+                {"q": "cherry"},
+            ]
+        }))
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await?;
+    let titles: Vec<_> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["suggestions"][0]["title"].clone())
+        .collect();
+    assert_eq!(
+        titles,
+        vec![
+            json!("Wikipedia - Banana"),
+            json!("Wikipedia - Apple"),
+            json!("Wikipedia - Cherry")
+        ]
+    );
+    Ok(())
+}
+
+#[merino_test_macro(|settings| {
+    settings.suggest_api.max_batch_size = 2;
+})]
+async fn suggest_batch_rejects_oversized_batches(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client
+        .post("/api/v1/suggest/batch")
+        .json(&json!({"requests": [{"q": "a"}, {"q": "b"}, {"q": "c"}]}))
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = response.json().await?;
+    assert_eq!(body["code"], "batch-too-large");
+    Ok(())
+}
+
+#[merino_test_macro(|settings| {
+    settings.debug = true;
+    settings.suggestion_providers.insert("wiki_fruit".to_string(), SuggestionProviderConfig::WikiFruit);
+    settings.suggest_api.selectable_providers = vec!["wiki_fruit".to_string()];
+})]
+async fn suggest_providers_can_be_selected(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client
+        .get("/api/v1/suggest?q=apple&providers=wiki_fruit")
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await?;
+    assert_eq!(body["suggestions"][0]["title"], json!("Wikipedia - Apple"));
+
+    let response = test_client
+        .get("/api/v1/suggest?q=apple&providers=not_a_provider")
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = response.json().await?;
+    assert_eq!(body["code"], "unknown-provider");
+
+    Ok(())
+}
+
+#[merino_test_macro(|settings| {
+    settings.debug = true;
+    settings.suggestion_providers.insert("wiki_fruit".to_string(), SuggestionProviderConfig::WikiFruit);
+})]
+async fn suggest_supports_conditional_requests(
+    TestingTools { test_client, .. }: TestingTools,
+) -> Result<()> {
+    let response = test_client.get("/api/v1/suggest?q=apple").send().await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["vary"], "Accept-Language, User-Agent");
+    assert!(response.headers().contains_key("cache-control"));
+    let etag = response.headers()["etag"].clone();
+
+    let response = test_client
+        .get("/api/v1/suggest?q=apple")
+        .header("If-None-Match", etag.clone())
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["etag"], etag);
+
     Ok(())
 }
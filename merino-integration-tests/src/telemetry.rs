@@ -0,0 +1,67 @@
+//! Tests that Merino exports spans to an OpenTelemetry collector.
+#![cfg(test)]
+
+use crate::{merino_test_macro, TestingTools};
+use anyhow::Result;
+use std::time::Duration;
+
+#[merino_test_macro]
+async fn request_spans_are_exported(
+    TestingTools {
+        test_client,
+        span_collector,
+        ..
+    }: TestingTools,
+) -> Result<()> {
+    test_client.get("/__heartbeat__").send().await?;
+
+    let span = span_collector
+        .wait_for_span("request")
+        .await
+        .expect("request span was not exported");
+    assert_eq!(span.service_name.as_deref(), Some("merino"));
+    assert_eq!(span.parent_span_id, "");
+    Ok(())
+}
+
+#[merino_test_macro]
+async fn traceparent_header_continues_the_callers_trace(
+    TestingTools {
+        test_client,
+        span_collector,
+        ..
+    }: TestingTools,
+) -> Result<()> {
+    test_client
+        .get("/__heartbeat__")
+        .header(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        )
+        .send()
+        .await?;
+
+    let span = span_collector
+        .wait_for_span("request")
+        .await
+        .expect("request span was not exported");
+    assert_eq!(span.trace_id, "0af7651916cd43dd8448eb211c80319c");
+    assert_eq!(span.parent_span_id, "b7ad6b7169203331");
+    Ok(())
+}
+
+#[merino_test_macro(|settings| settings.tracing.otlp_endpoint = None)]
+async fn spans_are_not_exported_without_a_collector(
+    TestingTools {
+        test_client,
+        span_collector,
+        ..
+    }: TestingTools,
+) -> Result<()> {
+    test_client.get("/__heartbeat__").send().await?;
+
+    // Give spans several export intervals to arrive, if they were exported.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(span_collector.spans().is_empty());
+    Ok(())
+}
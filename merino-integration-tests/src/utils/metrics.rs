@@ -91,4 +91,23 @@ impl MetricsWatcher {
                 }
         })
     }
+
+    /// Test if any metric this watcher received increased in value for a given
+    /// name, and had all of the given tags.
+    pub fn has_incr_with_tags(&mut self, name: &str, tags: &[(&str, &str)]) -> bool {
+        self.has(|msg| {
+            msg.name == name
+                && match &msg.metric {
+                    statsd_parser::Metric::Counter(counter) => (counter.value - 1.0).abs() < 0.0001,
+                    _ => false,
+                }
+                && tags.iter().all(|(key, value)| {
+                    msg.tags
+                        .as_ref()
+                        .and_then(|msg_tags| msg_tags.get(*key))
+                        .map(String::as_str)
+                        == Some(*value)
+                })
+        })
+    }
 }
@@ -3,4 +3,5 @@
 pub(crate) mod logging;
 pub(crate) mod metrics;
 pub(crate) mod redis;
+pub(crate) mod span_collector;
 pub(crate) mod test_tools;
@@ -0,0 +1,161 @@
+//! A stand-in for an OpenTelemetry collector, to make assertions about the
+//! spans Merino exports.
+
+use opentelemetry_otlp::proto::{
+    collector::trace::v1::{
+        trace_service_server::{TraceService, TraceServiceServer},
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    },
+    common::v1::any_value::Value,
+};
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+/// How long to wait for a span to be exported before giving up.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Receives spans over OTLP/gRPC, and keeps them for tests to inspect.
+///
+/// The collector runs on its own thread, so that it keeps receiving spans even
+/// while the test's runtime is blocked, such as while the span exporter is
+/// flushing its remaining spans on drop. It stops once every clone is dropped.
+#[derive(Clone)]
+pub struct SpanCollector {
+    /// The address the collector listens on.
+    address: SocketAddr,
+
+    /// The spans received so far.
+    spans: Arc<Mutex<Vec<ExportedSpan>>>,
+
+    /// Stops the collector when dropped.
+    _shutdown: Arc<oneshot::Sender<()>>,
+}
+
+/// A span that was exported to a [`SpanCollector`].
+#[derive(Clone, Debug)]
+pub struct ExportedSpan {
+    /// The name of the span.
+    pub name: String,
+
+    /// The ID of the span's trace, in hex.
+    pub trace_id: String,
+
+    /// The ID of the span's parent, in hex, or an empty string for root spans.
+    pub parent_span_id: String,
+
+    /// The `service.name` attribute of the exporting service, if any.
+    pub service_name: Option<String>,
+}
+
+impl SpanCollector {
+    /// Start a collector on a port assigned arbitrarily by the OS.
+    pub fn start() -> Self {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").expect("Failed to bind a port for span collector");
+        listener
+            .set_nonblocking(true)
+            .expect("Failed to configure span collector socket");
+        let address = listener.local_addr().unwrap();
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+
+        let service = TraceServiceServer::new(CollectorService {
+            spans: spans.clone(),
+        });
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to start span collector runtime");
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener)
+                    .expect("Failed to listen for spans");
+                Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                        shutdown_receiver.await.ok();
+                    })
+                    .await
+                    .expect("Span collector failed");
+            });
+        });
+
+        Self {
+            address,
+            spans,
+            _shutdown: Arc::new(shutdown_sender),
+        }
+    }
+
+    /// The endpoint to configure the span exporter with.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// All spans received so far.
+    pub fn spans(&self) -> Vec<ExportedSpan> {
+        self.spans.lock().expect("mutex was poisoned").clone()
+    }
+
+    /// Wait for a span named `name` to be exported.
+    ///
+    /// Returns `None` if no such span is received within a few seconds.
+    pub async fn wait_for_span(&self, name: &str) -> Option<ExportedSpan> {
+        let deadline = tokio::time::Instant::now() + EXPORT_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            if let Some(span) = self.spans().into_iter().find(|span| span.name == name) {
+                return Some(span);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        None
+    }
+}
+
+/// The gRPC service that receives exported spans.
+struct CollectorService {
+    /// Where to keep received spans.
+    spans: Arc<Mutex<Vec<ExportedSpan>>>,
+}
+
+#[tonic::async_trait]
+impl TraceService for CollectorService {
+    async fn export(
+        &self,
+        request: Request<ExportTraceServiceRequest>,
+    ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+        let mut spans = self.spans.lock().expect("mutex was poisoned");
+        for resource_spans in request.into_inner().resource_spans {
+            let service_name = resource_spans
+                .resource
+                .iter()
+                .flat_map(|resource| &resource.attributes)
+                .find(|attribute| attribute.key == "service.name")
+                .and_then(|attribute| match &attribute.value.as_ref()?.value {
+                    Some(Value::StringValue(name)) => Some(name.clone()),
+                    _ => None,
+                });
+
+            for library_spans in resource_spans.instrumentation_library_spans {
+                spans.extend(library_spans.spans.into_iter().map(|span| ExportedSpan {
+                    name: span.name,
+                    trace_id: hex(&span.trace_id),
+                    parent_span_id: hex(&span.parent_span_id),
+                    service_name: service_name.clone(),
+                }));
+            }
+        }
+        Ok(Response::new(ExportTraceServiceResponse {}))
+    }
+}
+
+/// Format `bytes` as lower case hex.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -1,13 +1,17 @@
 //! Tools for running tests
 
-use crate::utils::{logging::LogWatcher, metrics::MetricsWatcher, redis::get_temp_db};
+use crate::utils::{
+    logging::LogWatcher, metrics::MetricsWatcher, redis::get_temp_db, span_collector::SpanCollector,
+};
 use httpmock::MockServer;
 use merino_settings::Settings;
+use merino_web::SpanExporter;
 use reqwest::{redirect, Client, ClientBuilder, RequestBuilder};
 use std::{future::Future, net::TcpListener, sync::Once};
+use tracing::Subscriber;
 use tracing_futures::{Instrument, WithSubscriber};
 
-use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt};
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan};
 
 /// A marker to track that the viaduct backend has been initialized.
 static VIADUCT_INIT: Once = Once::new();
@@ -49,17 +53,7 @@ where
 
     // Set up logging
     let log_watcher = LogWatcher::default();
-    let log_watcher_writer = log_watcher.make_writer();
-
-    let tracing_subscriber = tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .json()
-                .with_writer(move || log_watcher_writer.clone()),
-        )
-        .with(tracing_subscriber::fmt::layer().pretty().with_test_writer());
-
-    let _tracing_subscriber_guard = tracing::subscriber::set_default(tracing_subscriber);
+    let _tracing_subscriber_guard = tracing::subscriber::set_default(make_subscriber(&log_watcher));
 
     // Load settings
     let mut settings = Settings::load_for_tests();
@@ -68,6 +62,10 @@ where
     let remote_settings_mock = MockServer::start();
     settings.remote_settings.server = Some(remote_settings_mock.base_url());
 
+    // Set up a stand-in collector for spans to be exported to
+    let span_collector = SpanCollector::start();
+    settings.tracing.otlp_endpoint = Some(span_collector.endpoint());
+
     // Set up Redis
     let _redis_connection_guard = match get_temp_db(&settings.redis.url).await {
         Ok((connection_info, connection_guard)) => {
@@ -83,6 +81,14 @@ where
 
     settings_changer(&mut settings);
 
+    // Export spans, if the test didn't disable it, by adding a layer to the
+    // logging subscriber.
+    let span_exporter =
+        SpanExporter::from_settings(&settings.tracing).expect("Failed to set up span exporter");
+    let _span_exporter_subscriber_guard = span_exporter.as_ref().map(|exporter| {
+        tracing::subscriber::set_default(make_subscriber(&log_watcher).with(exporter.layer()))
+    });
+
     // `remote_settings_client` uses viaduct. Tell viaduct to use reqwest.
     VIADUCT_INIT.call_once(|| {
         viaduct::set_backend(&viaduct_reqwest::ReqwestBackend)
@@ -114,6 +120,7 @@ where
         log_watcher,
         redis_client,
         metrics_watcher,
+        span_collector: span_collector.clone(),
     };
     // Run the test
     let rv = test(tools).instrument(test_span).await;
@@ -149,6 +156,24 @@ pub struct TestingTools {
 
     /// To make assertions about metrics.
     pub metrics_watcher: MetricsWatcher,
+
+    /// To make assertions about exported spans.
+    pub span_collector: SpanCollector,
+}
+
+/// Make a subscriber that records logs for `log_watcher`, and prints them for
+/// tests that fail.
+fn make_subscriber(
+    log_watcher: &LogWatcher,
+) -> impl Subscriber + for<'span> LookupSpan<'span> + Send + Sync {
+    let log_watcher_writer = log_watcher.make_writer();
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(move || log_watcher_writer.clone()),
+        )
+        .with(tracing_subscriber::fmt::layer().pretty().with_test_writer())
 }
 
 /// A wrapper around a `[reqwest::client]` that automatically sends requests to
@@ -184,4 +209,13 @@ impl TestReqwestClient {
         let url = format!("http://{}{}", &self.address, path);
         self.client.get(url)
     }
+
+    /// Start building a POST request to the test server with the path specified.
+    ///
+    /// The path should start with `/`, such as `/api/v1/suggest/batch`.
+    pub fn post(&self, path: &str) -> RequestBuilder {
+        assert!(path.starts_with('/'));
+        let url = format!("http://{}{}", &self.address, path);
+        self.client.post(url)
+    }
 }
@@ -34,15 +34,16 @@ mod redis;
 
 pub use logging::{LogFormat, LoggingSettings};
 
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use config::{Config, Environment, File};
 use http::Uri;
+use ipnet::IpNet;
 use sentry::internals::Dsn;
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DisplayFromStr};
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, str::FromStr};
+use serde_with::{serde_as, DisplayFromStr, DurationMilliSeconds, DurationSeconds};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
 
-use crate::providers::SuggestionProviderConfig;
+use crate::providers::{MultiplexerConfig, SuggestionProviderConfig};
 
 /// Top level settings object for Merino.
 #[serde_as]
@@ -62,12 +63,24 @@ pub struct Settings {
     /// Providers to use to generate suggestions
     pub suggestion_providers: HashMap<String, SuggestionProviderConfig>,
 
+    /// How to combine the suggestions of the providers in
+    /// `suggestion_providers`. Its `providers` must be empty, since the top
+    /// level providers are the ones in `suggestion_providers`.
+    pub multiplexer: MultiplexerConfig,
+
+    /// Server side experiments, by name. Every request is assigned to at most
+    /// one variant of each experiment.
+    pub experiments: HashMap<String, ExperimentSettings>,
+
     /// Logging settings.
     pub logging: LoggingSettings,
 
     /// Metrics settings.
     pub metrics: MetricsSettings,
 
+    /// Settings for exporting traces to an OpenTelemetry collector.
+    pub tracing: TracingSettings,
+
     /// Settings for error reporting via Sentry.
     pub sentry: SentrySettings,
 
@@ -84,6 +97,22 @@ pub struct Settings {
 
     /// Settings to use when determining the location associated with requests.
     pub location: LocationSettings,
+
+    /// Settings for normalizing the text of queries before they are passed to
+    /// suggestion providers.
+    pub query_normalization: QueryNormalizationSettings,
+
+    /// Limits on requests to the suggest API.
+    pub suggest_api: SuggestApiSettings,
+
+    /// Settings for limiting how many suggestion requests each client makes.
+    pub rate_limit: RateLimitSettings,
+
+    /// Settings for building the suggestion providers when Merino starts.
+    pub provider_startup: ProviderStartupSettings,
+
+    /// Settings for rebuilding the suggestion providers while running.
+    pub provider_reload: ProviderReloadSettings,
 }
 
 /// Settings for the HTTP server.
@@ -159,8 +188,146 @@ pub struct LocationSettings {
     pub maxmind_database: Option<PathBuf>,
 }
 
+/// Settings for normalizing queries. Each step is applied in the order the
+/// fields are listed here, and can be individually enabled or disabled.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueryNormalizationSettings {
+    /// Apply Unicode compatibility normalization (NFKC), so that equivalent
+    /// characters such as full-width letters and ligatures are unified.
+    pub unicode_nfkc: bool,
+
+    /// Convert the query to lower case.
+    pub lowercase: bool,
+
+    /// Remove diacritical marks, so that, for example, "café" becomes "cafe".
+    pub fold_diacritics: bool,
+
+    /// Replace runs of whitespace with a single space.
+    pub collapse_whitespace: bool,
+
+    /// Remove leading and trailing whitespace.
+    pub trim: bool,
+}
+
+/// Limits on requests to the suggest API.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SuggestApiSettings {
+    /// The longest query, in characters, to provide suggestions for. Longer
+    /// queries are rejected with a `400 Bad Request` response.
+    pub max_query_length: usize,
+
+    /// The most queries that a single batch request may contain.
+    pub max_batch_size: usize,
+
+    /// The names of the top level providers in `suggestion_providers` that
+    /// clients may explicitly select with the `providers` query parameter.
+    pub selectable_providers: Vec<String>,
+}
+
+/// Settings for limiting how many suggestion requests each client can make.
+///
+/// Each client has a bucket of `burst` tokens, that refills at
+/// `requests_per_second`. Every request takes a token, and requests made while
+/// the bucket is empty are rejected with a `429 Too Many Requests` response.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimitSettings {
+    /// Whether suggestion requests are rate limited.
+    pub enabled: bool,
+
+    /// The most requests a client can make at once, after being idle.
+    pub burst: u32,
+
+    /// The number of requests per second that a client can sustain.
+    pub requests_per_second: f64,
+
+    /// Where the buckets of clients are kept.
+    pub storage: RateLimitStorage,
+
+    /// Networks of proxies, such as load balancers, that are trusted to report
+    /// the address of the client in the `X-Forwarded-For` header. Example:
+    /// `["10.0.0.0/8"]`.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// Where the buckets of rate limited clients are kept.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitStorage {
+    /// In the memory of each Merino instance. Clients are limited separately
+    /// by each instance.
+    Memory,
+    /// In the Redis server at `redis.url`, shared by all Merino instances.
+    Redis,
+}
+
+/// A server side experiment.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExperimentSettings {
+    /// The variants of the experiment. Requests that are not assigned to any
+    /// variant get the default behavior.
+    pub variants: Vec<ExperimentVariantSettings>,
+}
+
+/// A variant of a server side experiment.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExperimentVariantSettings {
+    /// The name of the variant. This is included in the `server_variants` of
+    /// responses, so it should be unique across experiments.
+    pub name: String,
+
+    /// The percentage of requests, from 0 to 100, to assign to this variant.
+    /// The percentages of an experiment's variants must add up to 100 or less,
+    /// or the settings will fail to load.
+    pub traffic_percentage: f64,
+
+    /// Top level providers to use instead of the ones of the same name in
+    /// `suggestion_providers`, for requests assigned to this variant. Providers
+    /// with new names are added for those requests.
+    #[serde(default)]
+    pub suggestion_providers: HashMap<String, SuggestionProviderConfig>,
+}
+
+/// Settings for building the suggestion providers when Merino starts.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProviderStartupSettings {
+    /// Build the providers as soon as the server starts, instead of when the
+    /// first suggestion request is made. Until they are built, the
+    /// `__lbheartbeat__` endpoint reports that Merino is not ready.
+    pub eager: bool,
+
+    /// How long to wait before trying again if building the providers at
+    /// startup fails.
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "retry_interval_sec")]
+    pub retry_interval: Duration,
+}
+
+/// Settings for rebuilding the suggestion providers without restarting.
+///
+/// The providers are always rebuilt when Merino receives `SIGHUP`. Only
+/// `suggestion_providers`, and the settings the providers themselves use, take
+/// effect on a rebuild. Other settings still require a restart.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProviderReloadSettings {
+    /// Also rebuild the providers when a file in the `config` directory changes.
+    pub watch_config_files: bool,
+
+    /// How often to check the `config` directory for changes. Must be greater
+    /// than zero.
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "poll_interval_sec")]
+    pub poll_interval: Duration,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MetricsSettings {
+    /// Where metrics are reported.
+    pub mode: MetricsMode,
+
     /// The host and port to send metrics to, such as "127.0.0.1:8125" or "metrics.local:9999".
     pub sink_address: SocketAddr,
 
@@ -169,6 +336,38 @@ pub struct MetricsSettings {
     pub max_queue_size_kb: usize,
 }
 
+/// Where metrics are reported.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsMode {
+    /// Send metrics to the statsd server at `sink_address`.
+    Statsd,
+    /// Keep metrics in process, and serve them in the Prometheus text format
+    /// at `/__metrics__`. `sink_address` and `max_queue_size_kb` are ignored.
+    Prometheus,
+}
+
+/// Settings for exporting traces to an OpenTelemetry collector.
+///
+/// The spans Merino records with [`tracing`] are exported over OTLP/gRPC. A
+/// W3C `traceparent` header on incoming requests makes them part of the
+/// caller's trace.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TracingSettings {
+    /// The collector to export spans to, such as "http://localhost:4317". If
+    /// no value is provided, spans are not exported.
+    pub otlp_endpoint: Option<String>,
+
+    /// The service name to report with exported spans.
+    pub service_name: String,
+
+    /// How often to export batches of finished spans.
+    #[serde_as(as = "DurationMilliSeconds")]
+    #[serde(rename = "export_interval_ms")]
+    pub export_interval: Duration,
+}
+
 /// Settings for the error and event reporting system Sentry.
 ///
 /// Uses an enum to maintain invariants. In yaml or environment variable configs, set using one of these patterns:
@@ -245,7 +444,41 @@ impl Settings {
         s.merge(Environment::default().prefix("MERINO").separator("__"))
             .context("merging config")?;
 
-        serde_path_to_error::deserialize(s).context("Deserializing settings")
+        let settings: Self =
+            serde_path_to_error::deserialize(s).context("Deserializing settings")?;
+        settings.validate().context("Validating settings")?;
+        Ok(settings)
+    }
+
+    /// Check the settings for invalid combinations of values that can't be
+    /// caught while deserializing them.
+    ///
+    /// # Errors
+    /// If an experiment variant has a traffic percentage that is negative or
+    /// not finite, or if the percentages of an experiment's variants add up to
+    /// more than 100.
+    fn validate(&self) -> Result<()> {
+        for (name, experiment) in &self.experiments {
+            let mut total = 0.0;
+            for variant in &experiment.variants {
+                ensure!(
+                    variant.traffic_percentage.is_finite() && variant.traffic_percentage >= 0.0,
+                    "experiment {} variant {} has an invalid traffic_percentage of {}",
+                    name,
+                    variant.name,
+                    variant.traffic_percentage
+                );
+                total += variant.traffic_percentage;
+            }
+            // Allow for rounding errors in percentages like 33.3 + 33.3 + 33.4.
+            ensure!(
+                total <= 100.0 + 1e-9,
+                "the traffic percentages of experiment {} add up to {}, which is more than 100",
+                name,
+                total
+            );
+        }
+        Ok(())
     }
 
     /// Load settings from configuration files for tests.
@@ -265,6 +498,8 @@ impl Settings {
         s.merge(File::with_name("../config/local_test").required(false))
             .expect("Could not load local settings for tests");
 
-        s.try_into().expect("Could not convert settings")
+        let settings: Self = s.try_into().expect("Could not convert settings");
+        settings.validate().expect("Invalid settings for tests");
+        settings
     }
 }
@@ -1,5 +1,6 @@
+use http::Uri;
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DurationSeconds};
+use serde_with::{serde_as, DisplayFromStr, DurationMilliSeconds, DurationSeconds};
 use std::time::Duration;
 
 #[serde_as]
@@ -7,9 +8,11 @@ use std::time::Duration;
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum SuggestionProviderConfig {
     RemoteSettings(RemoteSettingsConfig),
+    AdmServerSide(AdmServerSideConfig),
     MemoryCache(MemoryCacheConfig),
     RedisCache(RedisCacheConfig),
     Multiplexer(MultiplexerConfig),
+    DeviceTargeting(DeviceTargetingConfig),
     Debug,
     WikiFruit,
     Null,
@@ -21,6 +24,137 @@ pub enum SuggestionProviderConfig {
 pub struct MultiplexerConfig {
     /// The multiplexed providers.
     pub providers: Vec<SuggestionProviderConfig>,
+
+    /// The maximum time to wait for each multiplexed provider. Providers that
+    /// do not respond in time are treated as failed. If no value is provided,
+    /// providers are waited on indefinitely.
+    #[serde_as(as = "Option<DurationMilliSeconds>")]
+    #[serde(rename = "provider_timeout_ms")]
+    pub provider_timeout: Option<Duration>,
+
+    /// What to do when a multiplexed provider fails or times out.
+    pub on_provider_failure: ProviderFailurePolicy,
+
+    /// Sort the combined suggestions by score, highest first. Suggestions with
+    /// equal scores keep their relative order.
+    pub sort_by_score: bool,
+
+    /// How to detect duplicate suggestions. Only the first of each set of
+    /// duplicates is kept.
+    pub dedupe_by: DedupeStrategy,
+
+    /// The maximum number of suggestions to return from each provider, as
+    /// identified by the suggestion's `provider` field.
+    pub max_suggestions_per_provider: Option<usize>,
+
+    /// Only keep the first sponsored and the first non-sponsored suggestion.
+    /// When combined with `sort_by_score`, these are the highest scored of each.
+    pub only_top_sponsored_and_organic: bool,
+
+    /// The maximum number of suggestions to return in total.
+    pub max_suggestions: Option<usize>,
+}
+
+/// How a multiplexer identifies duplicate suggestions.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupeStrategy {
+    /// Do not remove any suggestions.
+    None,
+    /// Suggestions with the same provider and ID are duplicates.
+    ProviderAndId,
+    /// Suggestions with the same URL are duplicates.
+    Url,
+}
+
+impl Default for DedupeStrategy {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// How a multiplexer handles one of its providers failing.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderFailurePolicy {
+    /// Fail the entire request.
+    Fail,
+    /// Skip the failed provider and return suggestions from the others.
+    Skip,
+}
+
+impl Default for ProviderFailurePolicy {
+    fn default() -> Self {
+        Self::Skip
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceTargetingConfig {
+    /// The rules to check suggestions against, in order. The first rule that
+    /// matches both the requesting device and the suggestion decides whether
+    /// the suggestion is kept. Suggestions no rule matches are kept.
+    #[serde(default)]
+    pub rules: Vec<DeviceTargetingRule>,
+
+    /// The provider whose suggestions are filtered.
+    pub inner: Box<SuggestionProviderConfig>,
+}
+
+/// A rule that keeps or drops suggestions for requests from some devices.
+///
+/// Every condition that is given must hold for the rule to match a request.
+/// A rule with no conditions matches every request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceTargetingRule {
+    /// What to do with the suggestions this rule matches.
+    pub action: TargetingAction,
+
+    /// Which suggestions this rule matches.
+    #[serde(default)]
+    pub applies_to: SuggestionSelector,
+
+    /// Only match requests from Firefox at this major version or newer.
+    /// Requests from other browsers never match.
+    #[serde(default)]
+    pub min_firefox_version: Option<u32>,
+
+    /// Only match requests from these form factors, such as `desktop` or `phone`.
+    #[serde(default)]
+    pub form_factors: Option<Vec<String>>,
+
+    /// Only match requests from these operating system families, such as
+    /// `windows`, `android`, or `chrome os`.
+    #[serde(default)]
+    pub os_families: Option<Vec<String>>,
+}
+
+/// What a device targeting rule does with the suggestions it matches.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetingAction {
+    /// Keep the suggestions.
+    Allow,
+    /// Remove the suggestions from the response.
+    Drop,
+}
+
+/// Which suggestions a device targeting rule matches.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SuggestionSelector {
+    /// Every suggestion.
+    All,
+    /// Only sponsored suggestions.
+    Sponsored,
+    /// Only suggestions that are not sponsored.
+    NonSponsored,
+}
+
+impl Default for SuggestionSelector {
+    fn default() -> Self {
+        Self::All
+    }
 }
 
 #[serde_as]
@@ -31,7 +165,7 @@ pub struct RedisCacheConfig {
     // #[serde_as(as = "crate::redis::AsConnectionInfo")]
     // pub url: redis::ConnectionInfo,
     /// The default time a cache entry will be valid for, if not specified by
-    /// the inner provider.
+    /// the inner provider. Must be between `min_ttl` and `max_ttl`.
     #[serde_as(as = "DurationSeconds")]
     #[serde(rename = "default_ttl_sec")]
     pub default_ttl: Duration,
@@ -42,6 +176,32 @@ pub struct RedisCacheConfig {
     #[serde(rename = "default_lock_timeout_sec")]
     pub default_lock_timeout: Duration,
 
+    /// The shortest time a cache entry will be valid for. TTLs provided by the
+    /// inner provider are raised to at least this. Must be at least one second.
+    /// Defaults to one minute.
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "min_ttl_sec")]
+    pub min_ttl: Duration,
+
+    /// The longest time a cache entry will be valid for. TTLs provided by the
+    /// inner provider are lowered to at most this. Defaults to one day.
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "max_ttl_sec")]
+    pub max_ttl: Duration,
+
+    /// If true, requests that miss the cache while another request holds the
+    /// refresh lock wait for the entry to be stored, instead of immediately
+    /// returning no suggestions. Waiting is bounded by `default_lock_timeout`.
+    /// Defaults to false.
+    #[serde(default)]
+    pub coalesce_requests: bool,
+
+    /// How often to check whether a locked cache entry has been stored, while
+    /// waiting for it. Must be greater than zero.
+    #[serde_as(as = "DurationMilliSeconds")]
+    #[serde(rename = "lock_poll_interval_ms")]
+    pub lock_poll_interval: Duration,
+
     /// The cached provider.
     pub inner: Box<SuggestionProviderConfig>,
 }
@@ -60,6 +220,10 @@ impl Default for RedisCacheConfig {
         Self {
             default_ttl: Duration::from_secs(900), // 15 minutes
             default_lock_timeout: Duration::from_secs(3),
+            min_ttl: Duration::from_secs(60),           // 1 minute
+            max_ttl: Duration::from_secs(24 * 60 * 60), // 1 day
+            coalesce_requests: false,
+            lock_poll_interval: Duration::from_millis(50),
             inner: Box::new(SuggestionProviderConfig::Null),
         }
     }
@@ -84,12 +248,38 @@ pub struct MemoryCacheConfig {
     /// amount of time the cleanup task takes.
     pub max_removed_entries: usize,
 
+    /// The maximum number of requests to cache responses for. When this is
+    /// exceeded, the least recently used entries are evicted. Defaults to
+    /// 100,000. If it is set to null, the number of entries is not limited.
+    #[serde(default = "default_max_entries")]
+    pub max_entries: Option<usize>,
+
+    /// The maximum approximate size of cached responses, in bytes. When this is
+    /// exceeded, the least recently used entries are evicted. If no value is
+    /// provided, the size of the cache is not limited.
+    pub max_size_bytes: Option<usize>,
+
     /// The default TTL for in-memory locks to prevent multiple update requests from
     /// being fired at providers at the same time.
     #[serde_as(as = "DurationSeconds")]
     #[serde(rename = "default_lock_timeout_sec")]
     pub default_lock_timeout: Duration,
 
+    /// How long after expiring a cache entry may still be served. Such entries
+    /// are served immediately, while a single background request to the cached
+    /// provider refreshes them. Defaults to 0, which never serves expired
+    /// entries.
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "stale_while_revalidate_sec", default)]
+    pub stale_while_revalidate: Duration,
+
+    /// If true, requests that miss the cache while another request is already
+    /// fetching the same entry wait for that fetch and share its result,
+    /// instead of immediately returning no suggestions. Waiting is bounded by
+    /// `default_lock_timeout`. Defaults to false.
+    #[serde(default)]
+    pub coalesce_requests: bool,
+
     /// The cached provider.
     pub inner: Box<SuggestionProviderConfig>,
 }
@@ -103,13 +293,23 @@ impl MemoryCacheConfig {
     }
 }
 
+/// The default for [`MemoryCacheConfig::max_entries`], used both by `Default`
+/// and for configs that don't set it.
+fn default_max_entries() -> Option<usize> {
+    Some(100_000)
+}
+
 impl Default for MemoryCacheConfig {
     fn default() -> Self {
         Self {
             default_ttl: Duration::from_secs(900),
             cleanup_interval: Duration::from_secs(300),
             max_removed_entries: 100_000,
+            max_entries: default_max_entries(),
+            max_size_bytes: None,
             default_lock_timeout: Duration::from_secs(10),
+            stale_while_revalidate: Duration::ZERO,
+            coalesce_requests: false,
             inner: Box::new(SuggestionProviderConfig::Null),
         }
     }
@@ -121,12 +321,83 @@ impl Default for MemoryCacheConfig {
 pub struct RemoteSettingsConfig {
     /// The collection to sync form.
     pub collection: String,
+
+    /// The time between re-syncs of Remote Settings data. If a re-sync fails,
+    /// the previously synced suggestions will continue to be served.
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "resync_interval_sec")]
+    pub resync_interval: Duration,
+
+    /// The minimum number of characters a query must have before it will be
+    /// matched against the beginning of keywords, instead of only matching
+    /// whole keywords.
+    pub min_prefix_length: usize,
+
+    /// The locales, such as `en` or `en-US`, that suggestions are provided
+    /// for if their Remote Settings record does not list any locales.
+    pub default_locales: Vec<String>,
 }
 
 impl Default for RemoteSettingsConfig {
     fn default() -> Self {
         Self {
             collection: "quicksuggest".to_string(),
+            resync_interval: Duration::from_secs(60 * 60 * 3), // 3 hours
+            min_prefix_length: 3,
+            default_locales: vec!["en".to_string()],
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmServerSideConfig {
+    /// The base URL of the adM suggestion API. If no value is provided, the
+    /// partner-specific production endpoint is used.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub endpoint_url: Option<Uri>,
+
+    /// The partner code assigned to us by adM.
+    pub partner: String,
+
+    /// The version of the adM API to request.
+    pub api_version: String,
+
+    /// The identifier for the area of inventory these suggestions are for.
+    pub sub1: String,
+
+    /// Further subdivision of the traffic identified by `sub1`.
+    pub sub2: Option<String>,
+
+    /// The maximum number of paid suggestions to request.
+    pub max_paid_results: Option<u32>,
+
+    /// The maximum number of organic suggestions to request.
+    pub max_organic_results: Option<u32>,
+
+    /// The time to wait for a response from adM before giving up.
+    #[serde_as(as = "DurationMilliSeconds")]
+    #[serde(rename = "timeout_ms")]
+    pub timeout: Duration,
+
+    /// The score, from 0 to 1, to give suggestions from adM, since the API
+    /// does not provide one.
+    pub score: f64,
+}
+
+impl Default for AdmServerSideConfig {
+    fn default() -> Self {
+        Self {
+            endpoint_url: None,
+            partner: String::new(),
+            api_version: "1.0".to_string(),
+            sub1: String::new(),
+            sub2: None,
+            max_paid_results: Some(1),
+            max_organic_results: Some(0),
+            timeout: Duration::from_millis(200),
+            score: 0.2,
         }
     }
 }
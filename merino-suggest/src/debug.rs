@@ -10,8 +10,8 @@ use fake::{Fake, Faker};
 use merino_settings::Settings;
 
 use crate::{
-    Proportion, SetupError, SuggestError, Suggestion, SuggestionProvider, SuggestionRequest,
-    SuggestionResponse,
+    LocationGranularity, Proportion, SetupError, SuggestError, Suggestion, SuggestionProvider,
+    SuggestionRequest, SuggestionResponse,
 };
 
 /// A toy suggester to test the system.
@@ -54,4 +54,9 @@ impl SuggestionProvider for DebugProvider {
             ..Faker.fake()
         }]))
     }
+
+    fn location_granularity(&self) -> LocationGranularity {
+        // The whole request is included in the response.
+        LocationGranularity::City
+    }
 }
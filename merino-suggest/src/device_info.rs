@@ -3,6 +3,9 @@
 
 use std::fmt::{self, Debug};
 use std::hash::Hash;
+use std::str::FromStr;
+
+use anyhow::anyhow;
 
 use fake::{Fake, Faker};
 use serde::Serialize;
@@ -33,6 +36,21 @@ impl fmt::Display for FormFactor {
     }
 }
 
+impl FromStr for FormFactor {
+    type Err = anyhow::Error;
+
+    /// Parse a form factor from the same names it is displayed with.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "desktop" => Ok(Self::Desktop),
+            "phone" => Ok(Self::Phone),
+            "tablet" => Ok(Self::Tablet),
+            "other" => Ok(Self::Other),
+            _ => Err(anyhow!("unknown form factor {:?}", s)),
+        }
+    }
+}
+
 impl<'a, F> fake::Dummy<F> for FormFactor {
     fn dummy_with_rng<R: rand::Rng + ?Sized>(_config: &F, rng: &mut R) -> Self {
         match rng.gen_range(0..4) {
@@ -81,6 +99,25 @@ impl fmt::Display for OsFamily {
     }
 }
 
+impl FromStr for OsFamily {
+    type Err = anyhow::Error;
+
+    /// Parse an operating system family from the same names it is displayed with.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "windows" => Ok(Self::Windows),
+            "macos" => Ok(Self::MacOs),
+            "linux" => Ok(Self::Linux),
+            "ios" => Ok(Self::IOs),
+            "android" => Ok(Self::Android),
+            "chrome os" => Ok(Self::ChromeOs),
+            "blackberry" => Ok(Self::BlackBerry),
+            "other" => Ok(Self::Other),
+            _ => Err(anyhow!("unknown operating system family {:?}", s)),
+        }
+    }
+}
+
 impl<'a, F> fake::Dummy<F> for OsFamily {
     fn dummy_with_rng<R: rand::Rng + ?Sized>(_config: &F, rng: &mut R) -> Self {
         match rng.gen_range(0..8) {
@@ -0,0 +1,343 @@
+//! Provides a provider-combinator that filters another provider's suggestions
+//! based on the device that made the request.
+
+use crate::{
+    device_info::{Browser, DeviceInfo, FormFactor, OsFamily},
+    HealthCheck, LanguageIdentifier, LocationGranularity, SetupError, SuggestError, Suggestion,
+    SuggestionProvider, SuggestionRequest, SuggestionResponse,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use merino_settings::providers::{
+    DeviceTargetingConfig, DeviceTargetingRule, SuggestionSelector, TargetingAction,
+};
+
+/// A provider that drops some of its inner provider's suggestions, depending
+/// on the browser, form factor, and operating system of the requesting device.
+pub struct DeviceTargeting {
+    /// The rules to check suggestions against, in order.
+    rules: Vec<Rule>,
+
+    /// The provider whose suggestions are filtered.
+    inner: Box<dyn SuggestionProvider>,
+}
+
+/// A device targeting rule, with its conditions parsed.
+#[derive(Debug)]
+struct Rule {
+    /// What to do with matched suggestions.
+    action: TargetingAction,
+
+    /// Which suggestions the rule matches.
+    applies_to: SuggestionSelector,
+
+    /// The oldest major version of Firefox the rule matches, if limited.
+    min_firefox_version: Option<u32>,
+
+    /// The form factors the rule matches, if limited.
+    form_factors: Option<Vec<FormFactor>>,
+
+    /// The operating system families the rule matches, if limited.
+    os_families: Option<Vec<OsFamily>>,
+}
+
+impl Rule {
+    /// Parse the form factors and operating system families of `config`.
+    fn parse(config: &DeviceTargetingRule) -> anyhow::Result<Self> {
+        let form_factors = config
+            .form_factors
+            .as_ref()
+            .map(|names| names.iter().map(|name| name.parse()).collect())
+            .transpose()?;
+        let os_families = config
+            .os_families
+            .as_ref()
+            .map(|names| names.iter().map(|name| name.parse()).collect())
+            .transpose()?;
+
+        Ok(Self {
+            action: config.action,
+            applies_to: config.applies_to,
+            min_firefox_version: config.min_firefox_version,
+            form_factors,
+            os_families,
+        })
+    }
+
+    /// Check if every condition of this rule holds for `device`.
+    fn matches_device(&self, device: &DeviceInfo) -> bool {
+        if let Some(min_version) = self.min_firefox_version {
+            match device.browser {
+                Browser::Firefox(version) if version >= min_version => (),
+                _ => return false,
+            }
+        }
+
+        if let Some(form_factors) = &self.form_factors {
+            if !form_factors.contains(&device.form_factor) {
+                return false;
+            }
+        }
+
+        if let Some(os_families) = &self.os_families {
+            if !os_families.contains(&device.os_family) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Check if `suggestion` is one of the suggestions this rule applies to.
+    fn matches_suggestion(&self, suggestion: &Suggestion) -> bool {
+        match self.applies_to {
+            SuggestionSelector::All => true,
+            SuggestionSelector::Sponsored => suggestion.is_sponsored,
+            SuggestionSelector::NonSponsored => !suggestion.is_sponsored,
+        }
+    }
+}
+
+impl DeviceTargeting {
+    /// Create a `DeviceTargeting` that filters the suggestions of `inner`.
+    ///
+    /// # Errors
+    /// If any rule names an unknown form factor or operating system family.
+    pub fn new(
+        config: &DeviceTargetingConfig,
+        inner: Box<dyn SuggestionProvider>,
+    ) -> Result<Self, SetupError> {
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                Rule::parse(rule)
+                    .with_context(|| format!("Invalid device targeting rule {}", index))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map_err(SetupError::InvalidConfiguration)?;
+
+        Ok(Self { rules, inner })
+    }
+
+    /// Create a boxed `DeviceTargeting`.
+    ///
+    /// # Errors
+    /// If any rule names an unknown form factor or operating system family.
+    pub fn new_boxed(
+        config: &DeviceTargetingConfig,
+        inner: Box<dyn SuggestionProvider>,
+    ) -> Result<Box<Self>, SetupError> {
+        Self::new(config, inner).map(Box::new)
+    }
+}
+
+#[async_trait]
+impl SuggestionProvider for DeviceTargeting {
+    fn name(&self) -> String {
+        format!("DeviceTargeting({})", self.inner.name())
+    }
+
+    async fn suggest(
+        &self,
+        request: SuggestionRequest,
+    ) -> Result<SuggestionResponse, SuggestError> {
+        let device_rules: Vec<&Rule> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches_device(&request.device_info))
+            .collect();
+
+        let mut response = self.inner.suggest(request).await?;
+        response.suggestions.retain(|suggestion| {
+            device_rules
+                .iter()
+                .find(|rule| rule.matches_suggestion(suggestion))
+                .map(|rule| rule.action == TargetingAction::Allow)
+                .unwrap_or(true)
+        });
+        Ok(response)
+    }
+
+    fn supported_locales(&self) -> Vec<LanguageIdentifier> {
+        self.inner.supported_locales()
+    }
+
+    fn location_granularity(&self) -> LocationGranularity {
+        self.inner.location_granularity()
+    }
+
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        self.inner.health_checks().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceTargeting;
+    use crate::{
+        device_info::{Browser, FormFactor, OsFamily},
+        SetupError, SuggestError, Suggestion, SuggestionProvider, SuggestionRequest,
+        SuggestionResponse,
+    };
+    use async_trait::async_trait;
+    use fake::{Fake, Faker};
+    use merino_settings::providers::{
+        DeviceTargetingConfig, DeviceTargetingRule, SuggestionProviderConfig, SuggestionSelector,
+        TargetingAction,
+    };
+
+    /// A provider that returns one sponsored and one non-sponsored suggestion.
+    struct MixedProvider;
+
+    #[async_trait]
+    impl SuggestionProvider for MixedProvider {
+        fn name(&self) -> String {
+            "MixedProvider".into()
+        }
+
+        async fn suggest(
+            &self,
+            _request: SuggestionRequest,
+        ) -> Result<SuggestionResponse, SuggestError> {
+            let sponsored = Suggestion {
+                is_sponsored: true,
+                ..Faker.fake()
+            };
+            let organic = Suggestion {
+                is_sponsored: false,
+                ..Faker.fake()
+            };
+            Ok(SuggestionResponse::new(vec![sponsored, organic]))
+        }
+    }
+
+    /// A rule with the given action and no conditions.
+    fn rule(action: TargetingAction) -> DeviceTargetingRule {
+        DeviceTargetingRule {
+            action,
+            applies_to: SuggestionSelector::All,
+            min_firefox_version: None,
+            form_factors: None,
+            os_families: None,
+        }
+    }
+
+    /// Wrap a `MixedProvider` with the given rules.
+    fn targeting(rules: Vec<DeviceTargetingRule>) -> Result<DeviceTargeting, SetupError> {
+        let config = DeviceTargetingConfig {
+            rules,
+            inner: Box::new(SuggestionProviderConfig::Null),
+        };
+        DeviceTargeting::new(&config, Box::new(MixedProvider))
+    }
+
+    /// Get the sponsored flags of the suggestions `provider` returns for a
+    /// request from the given device.
+    async fn sponsored_flags(
+        provider: &DeviceTargeting,
+        form_factor: FormFactor,
+        os_family: OsFamily,
+        browser: Browser,
+    ) -> Vec<bool> {
+        let mut request: SuggestionRequest = Faker.fake();
+        request.device_info.form_factor = form_factor;
+        request.device_info.os_family = os_family;
+        request.device_info.browser = browser;
+        provider
+            .suggest(request)
+            .await
+            .expect("suggest failed")
+            .suggestions
+            .iter()
+            .map(|s| s.is_sponsored)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sponsored_suggestions_are_hidden_on_phones() -> anyhow::Result<()> {
+        let provider = targeting(vec![DeviceTargetingRule {
+            applies_to: SuggestionSelector::Sponsored,
+            form_factors: Some(vec!["phone".to_string()]),
+            ..rule(TargetingAction::Drop)
+        }])?;
+
+        let phone = sponsored_flags(
+            &provider,
+            FormFactor::Phone,
+            OsFamily::Android,
+            Browser::Firefox(92),
+        )
+        .await;
+        assert_eq!(phone, vec![false]);
+
+        let desktop = sponsored_flags(
+            &provider,
+            FormFactor::Desktop,
+            OsFamily::Android,
+            Browser::Firefox(92),
+        )
+        .await;
+        assert_eq!(desktop, vec![true, false]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn first_matching_rule_decides() -> anyhow::Result<()> {
+        // Only Firefox 92 and newer, other than on Chrome OS, gets suggestions.
+        let provider = targeting(vec![
+            DeviceTargetingRule {
+                os_families: Some(vec!["chrome os".to_string()]),
+                ..rule(TargetingAction::Drop)
+            },
+            DeviceTargetingRule {
+                min_firefox_version: Some(92),
+                ..rule(TargetingAction::Allow)
+            },
+            rule(TargetingAction::Drop),
+        ])?;
+
+        let cases = vec![
+            (OsFamily::Windows, Browser::Firefox(92), 2),
+            (OsFamily::Windows, Browser::Firefox(91), 0),
+            (OsFamily::Windows, Browser::Other, 0),
+            (OsFamily::ChromeOs, Browser::Firefox(93), 0),
+        ];
+        for (os_family, browser, expected) in cases {
+            let flags = sponsored_flags(
+                &provider,
+                FormFactor::Desktop,
+                os_family.clone(),
+                browser.clone(),
+            )
+            .await;
+            assert_eq!(flags.len(), expected, "{} {}", os_family, browser);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn suggestions_are_kept_without_rules() -> anyhow::Result<()> {
+        let provider = targeting(vec![])?;
+        let flags =
+            sponsored_flags(&provider, FormFactor::Phone, OsFamily::IOs, Browser::Other).await;
+        assert_eq!(flags, vec![true, false]);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_device_names_are_rejected() {
+        let result = targeting(vec![DeviceTargetingRule {
+            form_factors: Some(vec!["smartwatch".to_string()]),
+            ..rule(TargetingAction::Drop)
+        }]);
+        assert!(matches!(result, Err(SetupError::InvalidConfiguration(_))));
+
+        let result = targeting(vec![DeviceTargetingRule {
+            os_families: Some(vec!["ChromeOS".to_string()]),
+            ..rule(TargetingAction::Drop)
+        }]);
+        assert!(matches!(result, Err(SetupError::InvalidConfiguration(_))));
+    }
+}
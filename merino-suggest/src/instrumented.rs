@@ -0,0 +1,222 @@
+//! Provides a provider-combinator that reports metrics about another provider.
+
+use std::time::Instant;
+
+use crate::{
+    CacheStatus, HealthCheck, LanguageIdentifier, LocationGranularity, SuggestError,
+    SuggestionProvider, SuggestionRequest, SuggestionResponse,
+};
+use async_trait::async_trait;
+use cadence::{CountedExt, Histogrammed, StatsdClient, Timed};
+
+/// A provider that records the latency, suggestion count, errors, and cache
+/// status of its inner provider, tagged with the inner provider's name.
+///
+/// It is transparent: its name, supported locales, location granularity, and
+/// health checks are those of the inner provider.
+pub struct Instrumented {
+    /// The provider to report metrics about.
+    inner: Box<dyn SuggestionProvider>,
+
+    /// The name of the inner provider, as used in metric tags.
+    tag: String,
+
+    /// The client used to report metrics.
+    metrics_client: StatsdClient,
+}
+
+impl Instrumented {
+    /// Wrap `inner` so that every request to it is reported to `metrics_client`.
+    pub fn new(inner: Box<dyn SuggestionProvider>, metrics_client: StatsdClient) -> Self {
+        // Commas separate tags in statsd lines, and names of combinators such
+        // as `Multi(A, B)` contain them.
+        let tag = inner.name().replace(',', ";");
+        Self {
+            inner,
+            tag,
+            metrics_client,
+        }
+    }
+
+    /// Create a boxed `Instrumented`.
+    pub fn new_boxed(
+        inner: Box<dyn SuggestionProvider>,
+        metrics_client: StatsdClient,
+    ) -> Box<Self> {
+        Box::new(Self::new(inner, metrics_client))
+    }
+}
+
+#[async_trait]
+impl SuggestionProvider for Instrumented {
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    async fn suggest(
+        &self,
+        request: SuggestionRequest,
+    ) -> Result<SuggestionResponse, SuggestError> {
+        let start = Instant::now();
+        let result = self.inner.suggest(request).await;
+        self.metrics_client
+            .time_with_tags("provider.duration", start.elapsed())
+            .with_tag("provider", &self.tag)
+            .send();
+
+        match &result {
+            Ok(response) => {
+                self.metrics_client
+                    .histogram_with_tags("provider.suggestions", response.suggestions.len() as u64)
+                    .with_tag("provider", &self.tag)
+                    .send();
+                if response.cache_status != CacheStatus::NoCache {
+                    self.metrics_client
+                        .incr_with_tags("provider.cache")
+                        .with_tag("provider", &self.tag)
+                        .with_tag("status", &response.cache_status.to_string())
+                        .send();
+                }
+            }
+            Err(error) => {
+                let kind = match error {
+                    SuggestError::Network(_) => "network",
+                    SuggestError::Serialization(_) => "serialization",
+                    SuggestError::Internal(_) => "internal",
+                    SuggestError::Timeout(_) => "timeout",
+                };
+                self.metrics_client
+                    .incr_with_tags("provider.errors")
+                    .with_tag("provider", &self.tag)
+                    .with_tag("error", kind)
+                    .send();
+            }
+        }
+
+        result
+    }
+
+    fn supported_locales(&self) -> Vec<LanguageIdentifier> {
+        self.inner.supported_locales()
+    }
+
+    fn location_granularity(&self) -> LocationGranularity {
+        self.inner.location_granularity()
+    }
+
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        self.inner.health_checks().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instrumented;
+    use crate::{
+        CacheStatus, SuggestError, SuggestionProvider, SuggestionRequest, SuggestionResponse,
+    };
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use cadence::{SpyMetricSink, StatsdClient};
+    use fake::{Fake, Faker};
+
+    /// A provider that always returns the same response, and is named like a
+    /// combinator.
+    struct FixedProvider(SuggestionResponse);
+
+    #[async_trait]
+    impl SuggestionProvider for FixedProvider {
+        fn name(&self) -> String {
+            "Fixed(A, B)".into()
+        }
+
+        async fn suggest(
+            &self,
+            _request: SuggestionRequest,
+        ) -> Result<SuggestionResponse, SuggestError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// A provider that always fails.
+    struct FailingProvider;
+
+    #[async_trait]
+    impl SuggestionProvider for FailingProvider {
+        fn name(&self) -> String {
+            "FailingProvider".into()
+        }
+
+        async fn suggest(
+            &self,
+            _request: SuggestionRequest,
+        ) -> Result<SuggestionResponse, SuggestError> {
+            Err(SuggestError::Network(anyhow!("unreachable")))
+        }
+    }
+
+    /// Convert sent metrics to strings, without timer values, which vary from
+    /// run to run.
+    fn sent_metrics(metrics: impl Iterator<Item = Vec<u8>>) -> Vec<String> {
+        metrics
+            .map(|m| {
+                let line = String::from_utf8(m).unwrap();
+                match line.split_once("|ms") {
+                    Some((name_value, tags)) => {
+                        format!("{}:_|ms{}", name_value.rsplit_once(':').unwrap().0, tags)
+                    }
+                    None => line,
+                }
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn successful_requests_are_reported() -> anyhow::Result<()> {
+        let (metrics, sink) = SpyMetricSink::new();
+        let mut response: SuggestionResponse = Faker.fake();
+        response.suggestions.truncate(2);
+        let suggestions = response.suggestions.len();
+        let response = response.with_cache_status(CacheStatus::Hit);
+        let provider = Instrumented::new(
+            Box::new(FixedProvider(response)),
+            StatsdClient::from_sink("test", sink),
+        );
+
+        provider.suggest(Faker.fake()).await?;
+
+        assert_eq!(provider.name(), "Fixed(A, B)");
+        assert_eq!(
+            sent_metrics(metrics.try_iter()),
+            vec![
+                "test.provider.duration:_|ms|#provider:Fixed(A; B)".to_string(),
+                format!(
+                    "test.provider.suggestions:{}|h|#provider:Fixed(A; B)",
+                    suggestions
+                ),
+                "test.provider.cache:1|c|#provider:Fixed(A; B),status:hit".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_are_reported_by_kind() {
+        let (metrics, sink) = SpyMetricSink::new();
+        let provider = Instrumented::new(
+            Box::new(FailingProvider),
+            StatsdClient::from_sink("test", sink),
+        );
+
+        let result = provider.suggest(Faker.fake()).await;
+
+        assert!(matches!(result, Err(SuggestError::Network(_))));
+        assert_eq!(
+            sent_metrics(metrics.try_iter()),
+            vec![
+                "test.provider.duration:_|ms|#provider:FailingProvider",
+                "test.provider.errors:1|c|#provider:FailingProvider,error:network",
+            ]
+        );
+    }
+}
@@ -4,16 +4,21 @@
 
 mod debug;
 pub mod device_info;
+mod device_targeting;
 mod domain;
+mod instrumented;
 mod multi;
 mod wikifruit;
 
-use std::fmt::Debug;
+use std::cmp::Ordering;
+use std::fmt::{self, Debug, Display};
 use std::hash::Hash;
 use std::ops::Range;
+use std::str::FromStr;
 use std::time::Duration;
 
 use crate::device_info::DeviceInfo;
+use anyhow::anyhow;
 use async_trait::async_trait;
 use fake::{
     faker::{
@@ -28,7 +33,9 @@ use serde_with::{serde_as, DisplayFromStr};
 use thiserror::Error;
 
 pub use crate::debug::DebugProvider;
+pub use crate::device_targeting::DeviceTargeting;
 pub use crate::domain::Proportion;
+pub use crate::instrumented::Instrumented;
 pub use crate::multi::Multi;
 pub use crate::wikifruit::WikiFruit;
 
@@ -41,8 +48,9 @@ pub struct SuggestionRequest {
     /// The text typed by the user.
     pub query: String,
 
-    /// Whether or not the request indicated support for English.
-    pub accepts_english: bool,
+    /// The locales the request indicated support for, ranked from most to
+    /// least preferred.
+    pub accepted_locales: Vec<LanguageIdentifier>,
 
     /// Country in ISO 3166-1 alpha-2 format, such as "MX" for Mexico or "IT" for Italy.
     pub country: Option<String>,
@@ -68,7 +76,14 @@ impl<'a, F> fake::Dummy<F> for SuggestionRequest {
     fn dummy_with_rng<R: rand::Rng + ?Sized>(_config: &F, rng: &mut R) -> Self {
         Self {
             query: Words(1..10).fake_with_rng::<Vec<String>, R>(rng).join(" "),
-            accepts_english: Faker.fake(),
+            accepted_locales: if rng.gen_bool(0.5) {
+                vec![LanguageIdentifier::Wildcard]
+            } else {
+                vec![LanguageIdentifier::Locale {
+                    language: "en".to_string(),
+                    region: Some("us".to_string()),
+                }]
+            },
             country: Some(CountryCode().fake::<String>()),
             region: Some(StateAbbr().fake::<String>()),
             dma: Some(rng.gen_range(100_u16..1000)),
@@ -78,6 +93,102 @@ impl<'a, F> fake::Dummy<F> for SuggestionRequest {
     }
 }
 
+/// How specific the location information used by a provider is.
+///
+/// Each level includes the levels before it. For example, a provider that
+/// uses regions must also use countries, since region codes are only unique
+/// within a country.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LocationGranularity {
+    /// No location information is used.
+    None,
+    /// Only the country is used.
+    Country,
+    /// The country and region are used.
+    Region,
+    /// All location information is used, including the DMA and city.
+    City,
+}
+
+impl Default for LocationGranularity {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// How healthy a provider is, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HealthStatus {
+    /// The provider is working normally.
+    Ok,
+    /// Something is wrong, but the provider can still provide suggestions.
+    Warn,
+    /// Something is wrong, and the provider cannot provide suggestions.
+    Error,
+}
+
+/// The result of checking the health of a single provider.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    /// The name of the checked provider.
+    pub provider: String,
+
+    /// How healthy the provider is.
+    pub status: HealthStatus,
+
+    /// A description of what is wrong, if anything.
+    pub message: Option<String>,
+}
+
+impl HealthCheck {
+    /// A check reporting that `provider` is working normally.
+    pub fn ok<S: Into<String>>(provider: S) -> Self {
+        Self {
+            provider: provider.into(),
+            status: HealthStatus::Ok,
+            message: None,
+        }
+    }
+
+    /// A check reporting that something is wrong with `provider`, but it can
+    /// still provide suggestions.
+    pub fn warn<S: Into<String>, M: Into<String>>(provider: S, message: M) -> Self {
+        Self {
+            provider: provider.into(),
+            status: HealthStatus::Warn,
+            message: Some(message.into()),
+        }
+    }
+
+    /// A check reporting that `provider` cannot provide suggestions.
+    pub fn error<S: Into<String>, M: Into<String>>(provider: S, message: M) -> Self {
+        Self {
+            provider: provider.into(),
+            status: HealthStatus::Error,
+            message: Some(message.into()),
+        }
+    }
+}
+
+impl SuggestionRequest {
+    /// Pick the locale out of `supported` that best fits this request.
+    ///
+    /// The accepted locales are tried in order of preference. For each one, a
+    /// supported locale with the same language and region is preferred over
+    /// one that only has a compatible language. Returns `None` if none of the
+    /// accepted locales are supported.
+    pub fn preferred_locale<'a, I>(&self, supported: I) -> Option<&'a LanguageIdentifier>
+    where
+        I: IntoIterator<Item = &'a LanguageIdentifier>,
+        I::IntoIter: Clone,
+    {
+        let supported = supported.into_iter();
+        self.accepted_locales
+            .iter()
+            .find_map(|accepted| accepted.best_match(supported.clone()))
+    }
+}
+
 /// A response of suggestions, along with related metadata.
 #[derive(Clone, Debug)]
 pub struct SuggestionResponse {
@@ -137,6 +248,9 @@ pub enum CacheStatus {
     Hit,
     /// The object was not available from the cache, and was regenerated.
     Miss,
+    /// The object was pulled from the cache after it expired, and is being
+    /// regenerated in the background.
+    Stale,
     /// No cache was consulted for this response.
     NoCache,
     /// The response is made of suggestions from multiple sources that have varying cache status.
@@ -150,6 +264,7 @@ impl ToString for CacheStatus {
         match self {
             CacheStatus::Hit => "hit",
             CacheStatus::Miss => "miss",
+            CacheStatus::Stale => "stale",
             CacheStatus::NoCache => "no-cache",
             CacheStatus::Mixed => "mixed",
             CacheStatus::Error => "error",
@@ -242,6 +357,31 @@ pub trait SuggestionProvider: Send + Sync {
 
     /// Provide suggested results for `query`.
     async fn suggest(&self, query: SuggestionRequest) -> Result<SuggestionResponse, SuggestError>;
+
+    /// The locales this provider can provide suggestions for. Requests that
+    /// don't accept any of these locales may not be sent to the provider.
+    ///
+    /// By default, providers support every locale.
+    fn supported_locales(&self) -> Vec<LanguageIdentifier> {
+        vec![LanguageIdentifier::Wildcard]
+    }
+
+    /// The most specific location information that can affect this
+    /// provider's suggestions. Caches only distinguish requests by this much
+    /// of their location.
+    ///
+    /// By default, providers don't use location information.
+    fn location_granularity(&self) -> LocationGranularity {
+        LocationGranularity::None
+    }
+
+    /// Check the health of this provider, and of any providers it wraps.
+    ///
+    /// By default, providers report no checks. Providers that wrap other
+    /// providers should include the checks of the wrapped providers.
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        Vec::new()
+    }
 }
 
 /// A provider that never provides any suggestions
@@ -287,6 +427,9 @@ pub enum SuggestError {
 
     #[error("There was an internal error in the suggestion provider")]
     Internal(#[source] anyhow::Error),
+
+    #[error("A suggestion provider did not respond in time")]
+    Timeout(#[source] anyhow::Error),
 }
 
 /// Languages supported by the client.
@@ -326,6 +469,29 @@ impl SupportedLanguages {
                 LanguageIdentifier::Wildcard => true,
             })
     }
+
+    /// List the languages in order of preference, as indicated by their
+    /// quality values. Languages without a quality value are most preferred,
+    /// and languages with a quality value of zero are not acceptable, so they
+    /// are left out.
+    pub fn ranked(&self) -> Vec<LanguageIdentifier> {
+        let mut languages: Vec<&Language> = self
+            .0
+            .iter()
+            .filter(|language| language.quality_value.unwrap_or(1.0) > 0.0)
+            .collect();
+        // This is a stable sort, so equally preferred languages keep the order
+        // they were given in.
+        languages.sort_by(|a, b| {
+            let a = a.quality_value.unwrap_or(1.0);
+            let b = b.quality_value.unwrap_or(1.0);
+            b.partial_cmp(&a).unwrap_or(Ordering::Equal)
+        });
+        languages
+            .into_iter()
+            .map(|language| language.language_identifier.clone())
+            .collect()
+    }
 }
 
 /// A representation of a language, as given in the Accept-Language HTTP header.
@@ -356,7 +522,9 @@ impl Language {
 }
 
 /// An enum used to signify whether a `Language` refers to a specific language or a wildcard.
-#[derive(Debug, PartialEq)]
+///
+/// Language and region codes are stored in lowercase.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub enum LanguageIdentifier {
     /// A specific locale, consisting of a language code and optional country code.
     Locale {
@@ -370,6 +538,96 @@ pub enum LanguageIdentifier {
     Wildcard,
 }
 
+impl LanguageIdentifier {
+    /// Check if `self` and `other` are compatible. Wildcards match any
+    /// language. Otherwise the languages must be the same, and the regions
+    /// must be the same if both are specified.
+    pub fn matches(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Wildcard, _) | (_, Self::Wildcard) => true,
+            (
+                Self::Locale {
+                    language: language_a,
+                    region: region_a,
+                },
+                Self::Locale {
+                    language: language_b,
+                    region: region_b,
+                },
+            ) => {
+                language_a == language_b
+                    && match (region_a, region_b) {
+                        (Some(a), Some(b)) => a == b,
+                        _ => true,
+                    }
+            }
+        }
+    }
+
+    /// Pick the locale out of `supported` that best fits `self`. A locale with
+    /// the same language and region is preferred, then one with the same
+    /// language and no region, then one with the same language and another
+    /// region, and finally a wildcard. Ties are broken by the locales' tags, so
+    /// the result doesn't depend on the order of `supported`.
+    pub fn best_match<'a, I>(&self, supported: I) -> Option<&'a LanguageIdentifier>
+    where
+        I: IntoIterator<Item = &'a LanguageIdentifier>,
+    {
+        supported
+            .into_iter()
+            .filter(|candidate| candidate.matches(self))
+            .min_by_key(|candidate| {
+                let rank = match candidate {
+                    _ if *candidate == self => 0,
+                    Self::Locale { region: None, .. } => 1,
+                    Self::Locale { .. } => 2,
+                    Self::Wildcard => 3,
+                };
+                (rank, candidate.to_string())
+            })
+    }
+}
+
+impl FromStr for LanguageIdentifier {
+    type Err = anyhow::Error;
+
+    /// Parse a language tag such as `en`, `en-US`, or `*`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(Self::Wildcard);
+        }
+
+        let (language, region) = match s.split_once('-') {
+            Some((language, region)) => (language, Some(region)),
+            None => (s, None),
+        };
+        if language.is_empty() || region == Some("") {
+            return Err(anyhow!("Invalid language tag {:?}", s));
+        }
+
+        Ok(Self::Locale {
+            language: language.to_lowercase(),
+            region: region.map(str::to_lowercase),
+        })
+    }
+}
+
+impl Display for LanguageIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Locale {
+                language,
+                region: Some(region),
+            } => write!(f, "{}-{}", language, region),
+            Self::Locale {
+                language,
+                region: None,
+            } => write!(f, "{}", language),
+            Self::Wildcard => write!(f, "*"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -429,4 +687,100 @@ mod tests {
         // Includes fr-CH
         assert!(supported_languages.includes("fr", Some("ch")));
     }
+
+    #[test]
+    fn supported_languages_are_ranked_by_quality() {
+        let supported_languages = SupportedLanguages(vec![
+            Language::locale("de", None::<String>, Some(0.5)),
+            Language::locale("fr", Some("ch"), None),
+            Language::locale("en", None::<String>, Some(0.8)),
+            Language::locale("es", None::<String>, Some(0.0)),
+            Language::locale("fr", None::<String>, None),
+        ]);
+
+        let ranked: Vec<String> = supported_languages
+            .ranked()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(ranked, vec!["fr-ch", "fr", "en", "de"]);
+    }
+
+    #[test]
+    fn language_identifiers_round_trip() -> anyhow::Result<()> {
+        for tag in &["en", "en-us", "*"] {
+            assert_eq!(tag.parse::<LanguageIdentifier>()?.to_string(), *tag);
+        }
+        assert_eq!(
+            "en-US".parse::<LanguageIdentifier>()?,
+            LanguageIdentifier::Locale {
+                language: "en".to_string(),
+                region: Some("us".to_string())
+            }
+        );
+        assert!("".parse::<LanguageIdentifier>().is_err());
+        assert!("en-".parse::<LanguageIdentifier>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn best_match_does_not_depend_on_candidate_order() -> anyhow::Result<()> {
+        let en_us: LanguageIdentifier = "en-us".parse()?;
+        let en_ca: LanguageIdentifier = "en-ca".parse()?;
+        let en: LanguageIdentifier = "en".parse()?;
+        let accepted: LanguageIdentifier = "en".parse()?;
+
+        for supported in &[
+            vec![en_us.clone(), en_ca.clone()],
+            vec![en_ca.clone(), en_us.clone()],
+        ] {
+            assert_eq!(accepted.best_match(supported), Some(&en_ca));
+            assert_eq!(
+                LanguageIdentifier::Wildcard.best_match(supported),
+                Some(&en_ca)
+            );
+        }
+
+        let supported = vec![LanguageIdentifier::Wildcard, en_us.clone(), en.clone()];
+        assert_eq!(accepted.best_match(&supported), Some(&en));
+        assert_eq!(
+            "en-gb"
+                .parse::<LanguageIdentifier>()?
+                .best_match(&supported),
+            Some(&en)
+        );
+        assert_eq!(en_us.best_match(&supported), Some(&en_us));
+        assert_eq!(
+            "fr".parse::<LanguageIdentifier>()?.best_match(&supported),
+            Some(&LanguageIdentifier::Wildcard)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn preferred_locale_follows_request_ranking() -> anyhow::Result<()> {
+        let supported: Vec<LanguageIdentifier> = vec!["en".parse()?, "fr-fr".parse()?];
+        let request_with = |locales: &[&str]| -> anyhow::Result<SuggestionRequest> {
+            Ok(SuggestionRequest {
+                accepted_locales: locales
+                    .iter()
+                    .map(|l| l.parse())
+                    .collect::<Result<_, _>>()?,
+                ..Faker.fake()
+            })
+        };
+
+        let request = request_with(&["fr", "en"])?;
+        assert_eq!(request.preferred_locale(&supported), Some(&supported[1]));
+
+        let request = request_with(&["fr-ca", "en-us"])?;
+        assert_eq!(request.preferred_locale(&supported), Some(&supported[0]));
+
+        let request = request_with(&["de"])?;
+        assert_eq!(request.preferred_locale(&supported), None);
+
+        let request = request_with(&["de", "*"])?;
+        assert_eq!(request.preferred_locale(&supported), Some(&supported[0]));
+        Ok(())
+    }
 }
@@ -1,26 +1,260 @@
 //! Provides a provider-combinator that provides suggestions from multiple sub-providers.
 
-use crate::{CacheStatus, SuggestError, SuggestionProvider, SuggestionRequest, SuggestionResponse};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use crate::{
+    CacheStatus, HealthCheck, LanguageIdentifier, LocationGranularity, SuggestError, Suggestion,
+    SuggestionProvider, SuggestionRequest, SuggestionResponse,
+};
+use anyhow::anyhow;
 use async_trait::async_trait;
+use cadence::{CountedExt, StatsdClient};
 use futures::future::join_all;
-
-/// Type alias for the contained suggestion type to save some typing.
+use merino_settings::providers::{DedupeStrategy, MultiplexerConfig, ProviderFailurePolicy};
 
 /// A provider that aggregates suggestions from multiple suggesters.
 pub struct Multi {
     /// The providers to aggregate from.
     providers: Vec<Box<dyn SuggestionProvider>>,
+
+    /// The maximum time to wait for each provider, if any.
+    provider_timeout: Option<Duration>,
+
+    /// What to do when a provider fails or times out.
+    on_provider_failure: ProviderFailurePolicy,
+
+    /// The client used to report skipped providers.
+    metrics_client: StatsdClient,
+
+    /// How to combine the suggestions from each provider.
+    merge_options: MergeOptions,
+}
+
+/// Options for combining the suggestions from multiple providers.
+#[derive(Debug)]
+struct MergeOptions {
+    /// Sort suggestions by score, highest first.
+    sort_by_score: bool,
+
+    /// How to detect duplicate suggestions.
+    dedupe_by: DedupeStrategy,
+
+    /// The maximum number of suggestions from each provider.
+    max_suggestions_per_provider: Option<usize>,
+
+    /// Only keep the first sponsored and first non-sponsored suggestion.
+    only_top_sponsored_and_organic: bool,
+
+    /// The maximum number of suggestions in total.
+    max_suggestions: Option<usize>,
+}
+
+impl From<&MultiplexerConfig> for MergeOptions {
+    fn from(config: &MultiplexerConfig) -> Self {
+        Self {
+            sort_by_score: config.sort_by_score,
+            dedupe_by: config.dedupe_by,
+            max_suggestions_per_provider: config.max_suggestions_per_provider,
+            only_top_sponsored_and_organic: config.only_top_sponsored_and_organic,
+            max_suggestions: config.max_suggestions,
+        }
+    }
+}
+
+impl MergeOptions {
+    /// Apply the sorting, deduplication, and limits to `suggestions`.
+    fn apply(&self, mut suggestions: Vec<Suggestion>) -> Vec<Suggestion> {
+        if self.sort_by_score {
+            // The sort is stable, so equally scored suggestions keep their order.
+            suggestions.sort_by_key(|s| std::cmp::Reverse(s.score));
+        }
+
+        match self.dedupe_by {
+            DedupeStrategy::None => (),
+            DedupeStrategy::ProviderAndId => {
+                let mut seen = HashSet::new();
+                suggestions.retain(|s| seen.insert((s.provider.clone(), s.id)));
+            }
+            DedupeStrategy::Url => {
+                let mut seen = HashSet::new();
+                suggestions.retain(|s| seen.insert(s.url.clone()));
+            }
+        }
+
+        if let Some(max_per_provider) = self.max_suggestions_per_provider {
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            suggestions.retain(|s| {
+                let count = counts.entry(s.provider.clone()).or_default();
+                *count += 1;
+                *count <= max_per_provider
+            });
+        }
+
+        if self.only_top_sponsored_and_organic {
+            let mut seen_sponsored = false;
+            let mut seen_organic = false;
+            suggestions.retain(|s| {
+                let seen = if s.is_sponsored {
+                    &mut seen_sponsored
+                } else {
+                    &mut seen_organic
+                };
+                !std::mem::replace(seen, true)
+            });
+        }
+
+        if let Some(max_suggestions) = self.max_suggestions {
+            suggestions.truncate(max_suggestions);
+        }
+
+        suggestions
+    }
 }
 
 impl Multi {
     /// Create a `Multi` that draws suggestions from `providers`.
-    pub fn new(providers: Vec<Box<dyn SuggestionProvider>>) -> Self {
-        Self { providers }
+    pub fn new(
+        config: &MultiplexerConfig,
+        providers: Vec<Box<dyn SuggestionProvider>>,
+        metrics_client: StatsdClient,
+    ) -> Self {
+        Self {
+            providers,
+            provider_timeout: config.provider_timeout,
+            on_provider_failure: config.on_provider_failure,
+            metrics_client,
+            merge_options: config.into(),
+        }
     }
 
     /// Create a boxed multi
-    pub fn new_boxed(providers: Vec<Box<dyn SuggestionProvider>>) -> Box<Self> {
-        Box::new(Self::new(providers))
+    pub fn new_boxed(
+        config: &MultiplexerConfig,
+        providers: Vec<Box<dyn SuggestionProvider>>,
+        metrics_client: StatsdClient,
+    ) -> Box<Self> {
+        Box::new(Self::new(config, providers, metrics_client))
+    }
+
+    /// Get suggestions from only some of the providers, selected by their
+    /// position in the list this `Multi` was created with.
+    pub async fn suggest_from_some(
+        &self,
+        request: SuggestionRequest,
+        include: impl Fn(usize) -> bool,
+    ) -> Result<SuggestionResponse, SuggestError> {
+        // Only ask providers that support one of the requested locales.
+        let providers: Vec<&dyn SuggestionProvider> = self
+            .providers
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| include(*index))
+            .map(|(_, p)| p.as_ref())
+            .filter(|p| request.preferred_locale(&p.supported_locales()).is_some())
+            .collect();
+
+        let results = join_all(
+            providers
+                .iter()
+                .map(|p| self.suggest_from(*p, request.clone())),
+        )
+        .await;
+
+        let mut responses = Vec::with_capacity(results.len());
+        for (provider, result) in providers.iter().zip(results) {
+            match (result, self.on_provider_failure) {
+                (Ok(response), _) => responses.push(response),
+                (Err(failure), ProviderFailurePolicy::Fail) => {
+                    return Err(failure.into_suggest_error(&provider.name()));
+                }
+                (Err(failure), ProviderFailurePolicy::Skip) => {
+                    let provider_name = provider.name();
+                    tracing::warn!(
+                        r#type = "suggest.multi.provider-skipped",
+                        provider = %provider_name,
+                        reason = failure.reason(),
+                        ?failure,
+                        "Skipping suggestions from provider"
+                    );
+                    self.metrics_client
+                        .incr_with_tags("multi.provider.skipped")
+                        .with_tag("provider", &provider_name)
+                        .with_tag("reason", failure.reason())
+                        .send();
+                }
+            }
+        }
+
+        // now flatten it
+        let mut rv = responses
+            .pop()
+            .unwrap_or_else(|| SuggestionResponse::new(vec![]));
+
+        for response in responses {
+            rv.suggestions.extend_from_slice(&response.suggestions);
+            rv.cache_status = match (rv.cache_status, response.cache_status) {
+                (a, b) if a == b => a,
+                (a, CacheStatus::NoCache) => a,
+                _ => CacheStatus::Mixed,
+            };
+            // The merged response is only valid as long as all of its parts are.
+            rv.cache_ttl = match (rv.cache_ttl, response.cache_ttl) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        rv.suggestions = self.merge_options.apply(rv.suggestions);
+
+        Ok(rv)
+    }
+
+    /// Get suggestions from a single provider, applying the provider timeout.
+    async fn suggest_from(
+        &self,
+        provider: &dyn SuggestionProvider,
+        request: SuggestionRequest,
+    ) -> Result<SuggestionResponse, ProviderFailure> {
+        let suggest = provider.suggest(request);
+        let result = match self.provider_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, suggest)
+                .await
+                .map_err(|_| ProviderFailure::TimedOut)?,
+            None => suggest.await,
+        };
+        result.map_err(ProviderFailure::Errored)
+    }
+}
+
+/// The reason a single provider did not contribute to a response.
+#[derive(Debug)]
+enum ProviderFailure {
+    /// The provider did not respond within the provider timeout.
+    TimedOut,
+    /// The provider returned an error.
+    Errored(SuggestError),
+}
+
+impl ProviderFailure {
+    /// A short description of the failure, suitable for metric tags.
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::TimedOut => "timeout",
+            Self::Errored(_) => "error",
+        }
+    }
+
+    /// Convert this failure into an error for the entire request.
+    fn into_suggest_error(self, provider_name: &str) -> SuggestError {
+        match self {
+            Self::TimedOut => SuggestError::Timeout(anyhow!(
+                "provider {} did not respond in time",
+                provider_name
+            )),
+            Self::Errored(error) => error,
+        }
     }
 }
 
@@ -40,28 +274,408 @@ impl SuggestionProvider for Multi {
         &self,
         request: SuggestionRequest,
     ) -> Result<SuggestionResponse, SuggestError> {
-        // collect a Vec<Result<Vec<T>, E>>, and then transpose it into a Result<Vec<Vec<T>>, E>.
-        let v: Result<Vec<SuggestionResponse>, _> =
-            join_all(self.providers.iter().map(|p| p.suggest(request.clone())))
-                .await
-                .into_iter()
-                .collect();
-        // now flatten it
-        v.map(|mut responses| {
-            let mut rv = responses
-                .pop()
-                .unwrap_or_else(|| SuggestionResponse::new(vec![]));
-
-            for response in responses {
-                rv.suggestions.extend_from_slice(&response.suggestions);
-                rv.cache_status = match (rv.cache_status, response.cache_status) {
-                    (a, b) if a == b => a,
-                    (a, CacheStatus::NoCache) => a,
-                    _ => CacheStatus::Mixed,
-                }
+        self.suggest_from_some(request, |_| true).await
+    }
+
+    fn supported_locales(&self) -> Vec<LanguageIdentifier> {
+        let mut locales = Vec::new();
+        for locale in self.providers.iter().flat_map(|p| p.supported_locales()) {
+            if locale == LanguageIdentifier::Wildcard {
+                return vec![locale];
+            }
+            if !locales.contains(&locale) {
+                locales.push(locale);
             }
+        }
+        locales
+    }
+
+    fn location_granularity(&self) -> LocationGranularity {
+        self.providers
+            .iter()
+            .map(|p| p.location_granularity())
+            .max()
+            .unwrap_or(LocationGranularity::None)
+    }
+
+    async fn health_checks(&self) -> Vec<HealthCheck> {
+        join_all(self.providers.iter().map(|p| p.health_checks()))
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MergeOptions, Multi};
+    use crate::{
+        HealthCheck, LanguageIdentifier, NullProvider, Proportion, SuggestError, Suggestion,
+        SuggestionProvider, SuggestionRequest, SuggestionResponse,
+    };
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use cadence::{NopMetricSink, SpyMetricSink, StatsdClient};
+    use fake::{Fake, Faker};
+    use http::Uri;
+    use merino_settings::providers::{DedupeStrategy, MultiplexerConfig, ProviderFailurePolicy};
+    use std::time::Duration;
+
+    /// A provider that always fails.
+    struct FailingProvider;
+
+    #[async_trait]
+    impl SuggestionProvider for FailingProvider {
+        fn name(&self) -> String {
+            "FailingProvider".into()
+        }
+
+        async fn suggest(
+            &self,
+            _request: SuggestionRequest,
+        ) -> Result<SuggestionResponse, SuggestError> {
+            Err(SuggestError::Internal(anyhow!("always fails")))
+        }
+
+        async fn health_checks(&self) -> Vec<HealthCheck> {
+            vec![HealthCheck::error(self.name(), "always fails")]
+        }
+    }
+
+    /// A provider that takes longer than the test timeouts to respond.
+    struct SlowProvider;
+
+    #[async_trait]
+    impl SuggestionProvider for SlowProvider {
+        fn name(&self) -> String {
+            "SlowProvider".into()
+        }
+
+        async fn suggest(
+            &self,
+            _request: SuggestionRequest,
+        ) -> Result<SuggestionResponse, SuggestError> {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(SuggestionResponse::new(vec![Faker.fake()]))
+        }
+    }
+
+    /// A provider that always returns the same fixed response.
+    struct FixedProvider(SuggestionResponse);
+
+    #[async_trait]
+    impl SuggestionProvider for FixedProvider {
+        fn name(&self) -> String {
+            "FixedProvider".into()
+        }
+
+        async fn suggest(
+            &self,
+            _request: SuggestionRequest,
+        ) -> Result<SuggestionResponse, SuggestError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// A provider that only supports French, and fails if it is asked anyway.
+    struct FrenchProvider;
+
+    #[async_trait]
+    impl SuggestionProvider for FrenchProvider {
+        fn name(&self) -> String {
+            "FrenchProvider".into()
+        }
+
+        async fn suggest(
+            &self,
+            _request: SuggestionRequest,
+        ) -> Result<SuggestionResponse, SuggestError> {
+            Err(SuggestError::Internal(anyhow!("asked for suggestions")))
+        }
+
+        fn supported_locales(&self) -> Vec<LanguageIdentifier> {
+            vec!["fr".parse().unwrap()]
+        }
+    }
+
+    /// Make a multi with a short provider timeout and the given failure policy.
+    fn multi_with_policy(
+        on_provider_failure: ProviderFailurePolicy,
+        providers: Vec<Box<dyn SuggestionProvider>>,
+        metrics_client: StatsdClient,
+    ) -> Multi {
+        let config = MultiplexerConfig {
+            provider_timeout: Some(Duration::from_millis(50)),
+            on_provider_failure,
+            ..MultiplexerConfig::default()
+        };
+        Multi::new(&config, providers, metrics_client)
+    }
+
+    #[tokio::test]
+    async fn failed_and_late_providers_are_skipped() -> anyhow::Result<()> {
+        let expected: SuggestionResponse = Faker.fake();
+        let (metrics, sink) = SpyMetricSink::new();
+        let multi = multi_with_policy(
+            ProviderFailurePolicy::Skip,
+            vec![
+                Box::new(FailingProvider),
+                Box::new(FixedProvider(expected.clone())),
+                Box::new(SlowProvider),
+            ],
+            StatsdClient::from_sink("test", sink),
+        );
+
+        let response = multi.suggest(Faker.fake()).await?;
+        assert_eq!(response.suggestions, expected.suggestions);
+
+        let metrics: Vec<String> = metrics
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert_eq!(
+            metrics,
+            vec![
+                "test.multi.provider.skipped:1|c|#provider:FailingProvider,reason:error",
+                "test.multi.provider.skipped:1|c|#provider:SlowProvider,reason:timeout",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_providers_fail_the_request() {
+        let multi = multi_with_policy(
+            ProviderFailurePolicy::Fail,
+            vec![Box::new(NullProvider), Box::new(FailingProvider)],
+            StatsdClient::from_sink("test", NopMetricSink),
+        );
+        assert!(multi.suggest(Faker.fake()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn late_providers_fail_the_request() {
+        let multi = multi_with_policy(
+            ProviderFailurePolicy::Fail,
+            vec![Box::new(NullProvider), Box::new(SlowProvider)],
+            StatsdClient::from_sink("test", NopMetricSink),
+        );
+        assert!(matches!(
+            multi.suggest(Faker.fake()).await,
+            Err(SuggestError::Timeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_selected_providers_are_asked() -> anyhow::Result<()> {
+        let expected: SuggestionResponse = Faker.fake();
+        let multi = multi_with_policy(
+            ProviderFailurePolicy::Fail,
+            vec![
+                Box::new(FailingProvider),
+                Box::new(FixedProvider(expected.clone())),
+            ],
+            StatsdClient::from_sink("test", NopMetricSink),
+        );
+
+        assert!(multi.suggest(Faker.fake()).await.is_err());
+        let response = multi
+            .suggest_from_some(Faker.fake(), |index| index == 1)
+            .await?;
+        assert_eq!(response.suggestions, expected.suggestions);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn the_shortest_cache_ttl_is_kept() -> anyhow::Result<()> {
+        let responses = vec![
+            SuggestionResponse::new(vec![]).with_cache_ttl(Duration::from_secs(300)),
+            SuggestionResponse::new(vec![]),
+            SuggestionResponse::new(vec![]).with_cache_ttl(Duration::from_secs(60)),
+        ];
+        let multi = multi_with_policy(
+            ProviderFailurePolicy::Fail,
+            responses
+                .into_iter()
+                .map(|response| Box::new(FixedProvider(response)) as Box<dyn SuggestionProvider>)
+                .collect(),
+            StatsdClient::from_sink("test", NopMetricSink),
+        );
+
+        let response = multi.suggest(Faker.fake()).await?;
+        assert_eq!(response.cache_ttl, Some(Duration::from_secs(60)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health_checks_of_every_provider_are_reported() {
+        let multi = multi_with_policy(
+            ProviderFailurePolicy::Skip,
+            vec![
+                Box::new(FailingProvider),
+                Box::new(NullProvider),
+                Box::new(FailingProvider),
+            ],
+            StatsdClient::from_sink("test", NopMetricSink),
+        );
+        let expected = HealthCheck::error("FailingProvider", "always fails");
+        assert_eq!(
+            multi.health_checks().await,
+            vec![expected.clone(), expected]
+        );
+    }
+
+    #[tokio::test]
+    async fn providers_are_only_asked_for_supported_locales() -> anyhow::Result<()> {
+        let multi = multi_with_policy(
+            ProviderFailurePolicy::Fail,
+            vec![Box::new(NullProvider), Box::new(FrenchProvider)],
+            StatsdClient::from_sink("test", NopMetricSink),
+        );
+
+        let english_request = SuggestionRequest {
+            accepted_locales: vec!["en-US".parse()?],
+            ..Faker.fake()
+        };
+        assert!(multi.suggest(english_request).await.is_ok());
+
+        let french_request = SuggestionRequest {
+            accepted_locales: vec!["fr-CA".parse()?, "en".parse()?],
+            ..Faker.fake()
+        };
+        assert!(multi.suggest(french_request).await.is_err());
+
+        assert_eq!(
+            multi.supported_locales(),
+            vec![LanguageIdentifier::Wildcard]
+        );
+        Ok(())
+    }
+
+    /// Make a suggestion with the fields that merging looks at.
+    fn suggestion(provider: &str, id: u32, url: &'static str, score: f32) -> Suggestion {
+        Suggestion {
+            provider: provider.into(),
+            id,
+            url: Uri::from_static(url),
+            score: Proportion::from(score),
+            is_sponsored: false,
+            ..Faker.fake()
+        }
+    }
+
+    /// Summarize suggestions as (provider, id) pairs for easy comparison.
+    fn ids(suggestions: &[Suggestion]) -> Vec<(&str, u32)> {
+        suggestions
+            .iter()
+            .map(|s| (s.provider.as_str(), s.id))
+            .collect()
+    }
+
+    /// Merge options that don't change anything.
+    fn no_merging() -> MergeOptions {
+        (&MultiplexerConfig::default()).into()
+    }
+
+    #[test]
+    fn default_merge_options_keep_everything() {
+        let suggestions = vec![
+            suggestion("a", 1, "https://example.com/1", 0.1),
+            suggestion("a", 1, "https://example.com/1", 0.9),
+        ];
+        assert_eq!(no_merging().apply(suggestions.clone()), suggestions);
+    }
+
+    #[test]
+    fn sort_by_score_is_stable() {
+        let options = MergeOptions {
+            sort_by_score: true,
+            ..no_merging()
+        };
+        let suggestions = vec![
+            suggestion("a", 1, "https://example.com/1", 0.1),
+            suggestion("a", 2, "https://example.com/2", 0.5),
+            suggestion("b", 3, "https://example.com/3", 0.9),
+            suggestion("b", 4, "https://example.com/4", 0.5),
+        ];
+        assert_eq!(
+            ids(&options.apply(suggestions)),
+            vec![("b", 3), ("a", 2), ("b", 4), ("a", 1)]
+        );
+    }
+
+    #[test]
+    fn dedupe_by_provider_and_id_keeps_highest_score_when_sorted() {
+        let options = MergeOptions {
+            sort_by_score: true,
+            dedupe_by: DedupeStrategy::ProviderAndId,
+            ..no_merging()
+        };
+        let suggestions = vec![
+            suggestion("a", 1, "https://example.com/1", 0.1),
+            suggestion("a", 1, "https://example.com/other", 0.9),
+            suggestion("b", 1, "https://example.com/1", 0.5),
+        ];
+        let merged = options.apply(suggestions);
+        assert_eq!(ids(&merged), vec![("a", 1), ("b", 1)]);
+        assert_eq!(merged[0].url, "https://example.com/other");
+    }
+
+    #[test]
+    fn dedupe_by_url() {
+        let options = MergeOptions {
+            dedupe_by: DedupeStrategy::Url,
+            ..no_merging()
+        };
+        let suggestions = vec![
+            suggestion("a", 1, "https://example.com/1", 0.1),
+            suggestion("b", 2, "https://example.com/1", 0.9),
+            suggestion("b", 3, "https://example.com/3", 0.5),
+        ];
+        assert_eq!(ids(&options.apply(suggestions)), vec![("a", 1), ("b", 3)]);
+    }
+
+    #[test]
+    fn limits_are_applied() {
+        let options = MergeOptions {
+            max_suggestions_per_provider: Some(2),
+            max_suggestions: Some(3),
+            ..no_merging()
+        };
+        let suggestions = vec![
+            suggestion("a", 1, "https://example.com/1", 0.1),
+            suggestion("a", 2, "https://example.com/2", 0.1),
+            suggestion("a", 3, "https://example.com/3", 0.1),
+            suggestion("b", 4, "https://example.com/4", 0.1),
+            suggestion("b", 5, "https://example.com/5", 0.1),
+        ];
+        assert_eq!(
+            ids(&options.apply(suggestions)),
+            vec![("a", 1), ("a", 2), ("b", 4)]
+        );
+    }
 
-            rv
-        })
+    #[test]
+    fn only_top_sponsored_and_organic() {
+        let options = MergeOptions {
+            sort_by_score: true,
+            only_top_sponsored_and_organic: true,
+            ..no_merging()
+        };
+        let suggestions = vec![
+            Suggestion {
+                is_sponsored: true,
+                ..suggestion("a", 1, "https://example.com/1", 0.3)
+            },
+            suggestion("b", 2, "https://example.com/2", 0.2),
+            Suggestion {
+                is_sponsored: true,
+                ..suggestion("a", 3, "https://example.com/3", 0.5)
+            },
+            suggestion("b", 4, "https://example.com/4", 0.4),
+        ];
+        assert_eq!(ids(&options.apply(suggestions)), vec![("a", 3), ("b", 4)]);
     }
 }
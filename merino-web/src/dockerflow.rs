@@ -8,10 +8,11 @@ use actix_web::{
     HttpRequest, HttpResponse,
 };
 use merino_settings::Settings;
+use merino_suggest::{HealthStatus, SuggestionProvider};
 use serde::{Deserialize, Serialize};
 use tracing::Level;
 
-use crate::errors::HandlerError;
+use crate::{errors::HandlerError, suggest::SuggestionProviderRef};
 
 /// Handles required Dockerflow Endpoints.
 pub fn configure(config: &mut web::ServiceConfig) {
@@ -23,10 +24,15 @@ pub fn configure(config: &mut web::ServiceConfig) {
 }
 
 /// Used by the load balancer to indicate that the server can respond to
-/// requests. Should just return OK.
+/// requests. Returns OK, unless the suggestion providers are built at startup
+/// and that has not finished yet.
 #[get("__lbheartbeat__")]
-fn lbheartbeat(_: HttpRequest) -> HttpResponse {
-    HttpResponse::Ok().body("")
+fn lbheartbeat(settings: Data<Settings>, providers: Data<SuggestionProviderRef>) -> HttpResponse {
+    if settings.provider_startup.eager && !providers.is_ready() {
+        HttpResponse::ServiceUnavailable().body("")
+    } else {
+        HttpResponse::Ok().body("")
+    }
 }
 
 /// Return the contents of the `version.json` file created by CircleCI and stored
@@ -47,10 +53,8 @@ enum CheckStatus {
     /// The check could not determine the status.
     Unknown,
     /// Something is wrong, but it is not interrupting the system.
-    #[allow(dead_code)]
     Warn,
     /// Something is wrong, and it is interrupting the system.
-    #[allow(dead_code)]
     Error,
 }
 
@@ -84,6 +88,23 @@ impl HeartbeatResponse {
     fn add_check<S: Into<String>>(&mut self, name: S, check: CheckStatus) {
         self.checks.insert(name.into(), check);
     }
+
+    /// Add the results of a check, keeping the worse result if a check with
+    /// the same name was already added.
+    fn add_worst_check<S: Into<String>>(&mut self, name: S, check: CheckStatus) {
+        let entry = self.checks.entry(name.into()).or_insert(check);
+        *entry = (*entry).max(check);
+    }
+}
+
+impl From<HealthStatus> for CheckStatus {
+    fn from(status: HealthStatus) -> Self {
+        match status {
+            HealthStatus::Ok => Self::Ok,
+            HealthStatus::Warn => Self::Warn,
+            HealthStatus::Error => Self::Error,
+        }
+    }
 }
 
 // Serde doesn't have a concept of "derived" fields for serialization. So
@@ -111,11 +132,53 @@ impl Serialize for HeartbeatResponse {
 }
 
 /// Returns a status message indicating the current state of the server.
+///
+/// Includes a check for each top level suggestion provider, which warns until
+/// that provider has been built, and the health checks of every provider that
+/// has been built. Responds with 503 if any check reports an error.
 #[get("__heartbeat__")]
-fn heartbeat(_: HttpRequest) -> HttpResponse {
+async fn heartbeat(
+    settings: Data<Settings>,
+    providers: Data<SuggestionProviderRef>,
+) -> HttpResponse {
     let mut checklist = HeartbeatResponse::default();
     checklist.add_check("heartbeat", CheckStatus::Ok);
-    HttpResponse::Ok().json(checklist)
+
+    // The server does not start if a configured database cannot be loaded, so
+    // it is always available when it is configured.
+    if settings.location.maxmind_database.is_some() {
+        checklist.add_check("maxmind", CheckStatus::Ok);
+    }
+
+    for (name, ready) in providers.provider_readiness() {
+        let status = if ready {
+            CheckStatus::Ok
+        } else {
+            CheckStatus::Warn
+        };
+        checklist.add_check(format!("provider.{}.ready", name), status);
+    }
+
+    if let Some(provider) = providers.current() {
+        for check in provider.health_checks().await {
+            if let Some(message) = &check.message {
+                tracing::warn!(
+                    r#type = "dockerflow.heartbeat.provider-unhealthy",
+                    provider = %check.provider,
+                    status = ?check.status,
+                    %message,
+                    "Provider health check did not pass"
+                );
+            }
+            checklist.add_worst_check(format!("provider.{}", check.provider), check.status.into());
+        }
+    }
+
+    if checklist.status() == CheckStatus::Error {
+        HttpResponse::ServiceUnavailable().json(checklist)
+    } else {
+        HttpResponse::Ok().json(checklist)
+    }
 }
 
 /// Arguments to the __error__ handler.
@@ -1,9 +1,8 @@
 //! Any errors that merino-web might generate, and supporting implementations.
 
-use std::collections::HashMap;
-
+use crate::middleware::current_request_id;
 use actix_web::{http::StatusCode, HttpResponse, ResponseError};
-use serde_json::Value;
+use serde::Serialize;
 use thiserror::Error;
 
 /// An error that happened in a web handler.
@@ -16,19 +15,183 @@ pub enum HandlerError {
     /// An error that indicates that one of the request headers is malformed.
     #[error("Malformed header: {0}")]
     MalformedHeader(&'static str),
+
+    /// The request did not include the query to provide suggestions for.
+    #[error("Missing query parameter: q")]
+    MissingQuery,
+
+    /// The query is longer than the maximum number of characters, which is
+    /// included.
+    #[error("The query is longer than the maximum of {0} characters")]
+    QueryTooLong(usize),
+
+    /// The suggestion providers did not respond in time.
+    #[error("Suggestion providers did not respond in time")]
+    UpstreamTimeout,
+
+    /// The request body could not be parsed.
+    #[error("Malformed request body")]
+    MalformedBody,
+
+    /// A field of the request body has an invalid value.
+    #[error("Invalid value for field: {0}")]
+    InvalidField(&'static str),
+
+    /// A batch request has more items than the maximum, which is included.
+    #[error("The batch has more than the maximum of {0} requests")]
+    BatchTooLarge(usize),
+
+    /// The request selected a provider that is not configured.
+    #[error("Unknown provider: {0}")]
+    UnknownProvider(String),
+
+    /// The request selected a provider that clients may not select.
+    #[error("Provider may not be selected: {0}")]
+    ProviderNotAllowed(String),
+
+    /// The client has made too many requests, and should wait before trying
+    /// again.
+    #[error("Too many requests")]
+    RateLimited,
+}
+
+impl HandlerError {
+    /// A stable identifier for the kind of error, that clients can match on
+    /// instead of the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Internal => "internal-error",
+            Self::MalformedHeader(_) => "malformed-header",
+            Self::MissingQuery => "missing-query",
+            Self::QueryTooLong(_) => "query-too-long",
+            Self::UpstreamTimeout => "upstream-timeout",
+            Self::MalformedBody => "malformed-body",
+            Self::InvalidField(_) => "invalid-field",
+            Self::BatchTooLarge(_) => "batch-too-large",
+            Self::UnknownProvider(_) => "unknown-provider",
+            Self::ProviderNotAllowed(_) => "provider-not-allowed",
+            Self::RateLimited => "rate-limited",
+        }
+    }
+}
+
+/// The body of error responses.
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    /// See [`HandlerError::code`].
+    code: &'static str,
+
+    /// A human readable description of the error.
+    message: String,
+
+    /// The ID of the request, to find it in logs.
+    request_id: Option<String>,
 }
 
 impl ResponseError for HandlerError {
     fn status_code(&self) -> StatusCode {
         match self {
             Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
-            Self::MalformedHeader(_) => StatusCode::BAD_REQUEST,
+            Self::MalformedHeader(_)
+            | Self::MissingQuery
+            | Self::QueryTooLong(_)
+            | Self::MalformedBody
+            | Self::InvalidField(_)
+            | Self::BatchTooLarge(_)
+            | Self::UnknownProvider(_) => StatusCode::BAD_REQUEST,
+            Self::ProviderNotAllowed(_) => StatusCode::FORBIDDEN,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        let mut response = HashMap::new();
-        response.insert("error".to_owned(), Value::String(format!("{}", self)));
-        HttpResponse::InternalServerError().json(response)
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            code: self.code(),
+            message: self.to_string(),
+            request_id: current_request_id(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HandlerError;
+    use crate::middleware::RequestId;
+    use actix_web::{body::AnyBody, http::StatusCode, test, web, App, HttpResponse, ResponseError};
+    use serde_json::{json, Value};
+
+    /// Get the JSON body of `response`.
+    fn json_body(response: &HttpResponse) -> Value {
+        match response.body() {
+            AnyBody::Bytes(bytes) => serde_json::from_slice(bytes).expect("body is not JSON"),
+            body => panic!("unexpected body {:?}", body),
+        }
+    }
+
+    #[test]
+    fn errors_have_matching_status_codes() {
+        let cases = vec![
+            (HandlerError::Internal, StatusCode::INTERNAL_SERVER_ERROR),
+            (
+                HandlerError::MalformedHeader("Accept-Language"),
+                StatusCode::BAD_REQUEST,
+            ),
+            (HandlerError::MissingQuery, StatusCode::BAD_REQUEST),
+            (HandlerError::QueryTooLong(500), StatusCode::BAD_REQUEST),
+            (HandlerError::UpstreamTimeout, StatusCode::GATEWAY_TIMEOUT),
+            (HandlerError::MalformedBody, StatusCode::BAD_REQUEST),
+            (
+                HandlerError::InvalidField("locale"),
+                StatusCode::BAD_REQUEST,
+            ),
+            (HandlerError::BatchTooLarge(50), StatusCode::BAD_REQUEST),
+            (
+                HandlerError::UnknownProvider("nope".to_string()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                HandlerError::ProviderNotAllowed("adm".to_string()),
+                StatusCode::FORBIDDEN,
+            ),
+            (HandlerError::RateLimited, StatusCode::TOO_MANY_REQUESTS),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status_code(), status, "{:?}", error);
+            assert_eq!(error.error_response().status(), status, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn error_responses_are_structured() {
+        let response = HandlerError::QueryTooLong(500).error_response();
+        assert_eq!(
+            json_body(&response),
+            json!({
+                "code": "query-too-long",
+                "message": "The query is longer than the maximum of 500 characters",
+                "request_id": null,
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn error_responses_include_the_request_id() {
+        let app = test::init_service(App::new().wrap(RequestId).route(
+            "/",
+            web::get().to(|| async { Err::<HttpResponse, _>(HandlerError::MissingQuery) }),
+        ))
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("X-Request-Id", "test-request"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "missing-query");
+        assert_eq!(body["request_id"], "test-request");
     }
 }
@@ -0,0 +1,183 @@
+//! Assigning requests to the variants of server side experiments.
+
+use actix_web::{http::header, HttpRequest};
+use merino_settings::ExperimentSettings;
+use std::{collections::HashMap, net::SocketAddr};
+
+/// The number of buckets requests are divided into. Traffic percentages are
+/// honored to a hundredth of a percent.
+const BUCKETS: u64 = 10_000;
+
+/// A variant of an experiment that a request was assigned to.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Assignment {
+    /// The name of the experiment.
+    pub experiment: String,
+
+    /// The name of the variant.
+    pub variant: String,
+}
+
+/// The key to assign `request` to experiment variants by.
+///
+/// A client can supply `bucket_id` to be assigned consistently across
+/// requests. Otherwise, the key is derived from the client's address and user
+/// agent, so that a client is usually assigned to the same variants.
+pub(crate) fn bucket_key(request: &HttpRequest, bucket_id: Option<&str>) -> String {
+    if let Some(bucket_id) = bucket_id {
+        return format!("id:{}", bucket_id);
+    }
+
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let connection_info = request.connection_info();
+    let address = connection_info.realip_remote_addr().unwrap_or_default();
+    // The peer address includes a port, which varies between connections.
+    let address = address
+        .parse::<SocketAddr>()
+        .map_or_else(|_| address.to_string(), |address| address.ip().to_string());
+    format!("request:{}|{}", address, user_agent)
+}
+
+/// Assign the request with `bucket_key` to at most one variant of each
+/// experiment, ordered by experiment name.
+///
+/// Assignments are deterministic: the same key is always assigned to the same
+/// variants, as long as the experiments don't change. Each experiment buckets
+/// independently, so being in a variant of one experiment doesn't influence
+/// the variants of others.
+pub(crate) fn assign(
+    experiments: &HashMap<String, ExperimentSettings>,
+    bucket_key: &str,
+) -> Vec<Assignment> {
+    let mut names: Vec<&String> = experiments.keys().collect();
+    names.sort();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let bucket = bucket(name, bucket_key);
+            let mut upper_bound = 0;
+            experiments[name].variants.iter().find_map(|variant| {
+                upper_bound += (variant.traffic_percentage * (BUCKETS / 100) as f64).round() as u64;
+                (bucket < upper_bound).then(|| Assignment {
+                    experiment: name.clone(),
+                    variant: variant.name.clone(),
+                })
+            })
+        })
+        .collect()
+}
+
+/// The bucket, from 0 to [`BUCKETS`], of `bucket_key` in `experiment`.
+fn bucket(experiment: &str, bucket_key: &str) -> u64 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(experiment.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(bucket_key.as_bytes());
+    let hash = hasher.finalize();
+
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_le_bytes(prefix) % BUCKETS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assign, bucket_key, Assignment};
+    use actix_web::test::TestRequest;
+    use merino_settings::{ExperimentSettings, ExperimentVariantSettings};
+    use std::collections::HashMap;
+
+    /// An experiment with variants of the given names and traffic percentages.
+    fn experiment(variants: &[(&str, f64)]) -> ExperimentSettings {
+        ExperimentSettings {
+            variants: variants
+                .iter()
+                .map(|(name, traffic_percentage)| ExperimentVariantSettings {
+                    name: name.to_string(),
+                    traffic_percentage: *traffic_percentage,
+                    suggestion_providers: HashMap::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn assignments_are_deterministic() {
+        let experiments: HashMap<_, _> = vec![
+            ("b".to_string(), experiment(&[("b1", 50.0), ("b2", 50.0)])),
+            ("a".to_string(), experiment(&[("a1", 100.0)])),
+        ]
+        .into_iter()
+        .collect();
+
+        let assignments = assign(&experiments, "some key");
+        assert_eq!(assignments.len(), 2);
+        assert_eq!(
+            assignments[0],
+            Assignment {
+                experiment: "a".to_string(),
+                variant: "a1".to_string()
+            }
+        );
+        assert_eq!(assignments[1].experiment, "b");
+        for _ in 0..10 {
+            assert_eq!(assign(&experiments, "some key"), assignments);
+        }
+    }
+
+    #[test]
+    fn traffic_is_split_by_percentage() {
+        let experiments: HashMap<_, _> = vec![(
+            "split".to_string(),
+            experiment(&[("ten", 10.0), ("thirty", 30.0)]),
+        )]
+        .into_iter()
+        .collect();
+
+        let mut counts: HashMap<Option<String>, usize> = HashMap::new();
+        for i in 0..10_000 {
+            let variant = assign(&experiments, &i.to_string())
+                .pop()
+                .map(|assignment| assignment.variant);
+            *counts.entry(variant).or_default() += 1;
+        }
+
+        // Allow some leeway, since the split is by hash, not by count.
+        let count = |variant: Option<&str>| counts[&variant.map(ToString::to_string)];
+        assert!((800..1200).contains(&count(Some("ten"))), "{:?}", counts);
+        assert!(
+            (2700..3300).contains(&count(Some("thirty"))),
+            "{:?}",
+            counts
+        );
+        assert!((5700..6300).contains(&count(None)), "{:?}", counts);
+    }
+
+    #[test]
+    fn bucket_ids_override_request_details() {
+        let request = TestRequest::default()
+            .insert_header(("User-Agent", "Firefox"))
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .to_http_request();
+        let same_client = TestRequest::default()
+            .insert_header(("User-Agent", "Firefox"))
+            .peer_addr("10.0.0.1:5678".parse().unwrap())
+            .to_http_request();
+        let other_request = TestRequest::default()
+            .insert_header(("User-Agent", "Firefox"))
+            .peer_addr("10.0.0.2:1234".parse().unwrap())
+            .to_http_request();
+
+        assert_eq!(bucket_key(&request, None), bucket_key(&same_client, None));
+        assert_ne!(bucket_key(&request, None), bucket_key(&other_request, None));
+        assert_eq!(
+            bucket_key(&request, Some("abc")),
+            bucket_key(&other_request, Some("abc"))
+        );
+    }
+}
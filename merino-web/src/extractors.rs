@@ -2,11 +2,11 @@
 
 use std::str::FromStr;
 
-use crate::errors::HandlerError;
+use crate::{errors::HandlerError, normalization::normalize_query, suggest::SuggestionProviderRef};
 use actix_web::{
     dev::Payload,
     http::{header, HeaderValue},
-    web::Query,
+    web::{Data, Query},
     Error as ActixError, FromRequest, HttpRequest,
 };
 use actix_web_location::Location;
@@ -15,6 +15,7 @@ use futures_util::{
     FutureExt,
 };
 use lazy_static::lazy_static;
+use merino_settings::Settings;
 use merino_suggest::{
     device_info::{Browser, DeviceInfo, FormFactor, OsFamily},
     Language, LanguageIdentifier, SuggestionRequest, SupportedLanguages,
@@ -28,7 +29,17 @@ lazy_static! {
 }
 
 /// An extractor for a [`merino_suggest::SuggestionRequest`].
-pub struct SuggestionRequestWrapper(pub SuggestionRequest);
+///
+/// The query in the extracted request has been normalized according to the
+/// query normalization settings.
+pub struct SuggestionRequestWrapper {
+    /// The extracted request, with a normalized query.
+    pub request: SuggestionRequest,
+
+    /// The query as the client sent it, before normalization. This should only
+    /// be used for logging.
+    pub original_query: String,
+}
 
 impl FromRequest for SuggestionRequestWrapper {
     type Config = ();
@@ -37,6 +48,101 @@ impl FromRequest for SuggestionRequestWrapper {
 
     type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        async move {
+            // Retrieve all parts needed to make a SuggestionRequest concurrently.
+            // `try_join` implicitly `.await`s.
+            let (Query(SuggestQuery { q: original_query }), context) =
+                try_join!(Query::extract(&req), SuggestionContext::extract(&req))?;
+
+            let startup_settings = req.app_data::<Data<Settings>>().ok_or_else(|| {
+                tracing::error!(
+                    r#type = "web.extractors.missing-settings",
+                    "Settings were not available while extracting a suggestion request"
+                );
+                HandlerError::Internal
+            })?;
+            // Use the settings of the current providers, which may have been
+            // reloaded since startup.
+            let settings = match req.app_data::<Data<SuggestionProviderRef>>() {
+                Some(providers) => providers.current_settings(startup_settings),
+                None => startup_settings.clone().into_inner(),
+            };
+            let original_query = original_query.ok_or(HandlerError::MissingQuery)?;
+            let query = checked_query(&original_query, &settings)?;
+
+            Ok(Self {
+                request: context.request_for(query),
+                original_query,
+            })
+        }
+        .boxed_local()
+    }
+}
+
+/// Check that `original_query` is acceptable to provide suggestions for, and
+/// normalize it according to the query normalization settings.
+pub(crate) fn checked_query(
+    original_query: &str,
+    settings: &Settings,
+) -> Result<String, HandlerError> {
+    let max_query_length = settings.suggest_api.max_query_length;
+    if original_query.chars().count() > max_query_length {
+        return Err(HandlerError::QueryTooLong(max_query_length));
+    }
+    Ok(normalize_query(
+        original_query,
+        &settings.query_normalization,
+    ))
+}
+
+/// An extractor for the parts of a [`SuggestionRequest`] that come from the
+/// request's headers instead of its query.
+#[derive(Clone, Debug)]
+pub struct SuggestionContext {
+    /// The locales the request indicated support for, ranked from most to
+    /// least preferred.
+    pub accepted_locales: Vec<LanguageIdentifier>,
+
+    /// The country the request came from.
+    pub country: Option<String>,
+
+    /// The region the request came from.
+    pub region: Option<String>,
+
+    /// The Designated Market Area the request came from.
+    pub dma: Option<u16>,
+
+    /// The city the request came from.
+    pub city: Option<String>,
+
+    /// The user agent of the request.
+    pub device_info: DeviceInfo,
+}
+
+impl SuggestionContext {
+    /// Make a request for suggestions for `query` in this context.
+    pub fn request_for(&self, query: String) -> SuggestionRequest {
+        SuggestionRequest {
+            query,
+            accepted_locales: self.accepted_locales.clone(),
+            country: self.country.clone(),
+            region: self.region.clone(),
+            dma: self.dma,
+            city: self.city.clone(),
+            device_info: self.device_info.clone(),
+        }
+    }
+}
+
+impl FromRequest for SuggestionContext {
+    type Config = ();
+
+    type Error = ActixError;
+
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         let req = req.clone();
         async move {
@@ -46,29 +152,25 @@ impl FromRequest for SuggestionRequestWrapper {
                 Location::extract(request).await.map_err(ActixError::from)
             }
 
-            // Retrieve all parts needed to make a SuggestionRequest concurrently.
             // `try_join` implicitly `.await`s.
             let (
-                Query(SuggestQuery { q: query }),
                 SupportedLanguagesWrapper(supported_languages),
                 location,
                 DeviceInfoWrapper(device_info),
             ) = try_join!(
-                Query::extract(&req),
                 SupportedLanguagesWrapper::extract(&req),
                 loc_mapped_error(&req),
                 DeviceInfoWrapper::extract(&req),
             )?;
 
-            Ok(Self(SuggestionRequest {
-                query,
-                accepts_english: supported_languages.includes("en", None),
+            Ok(Self {
+                accepted_locales: supported_languages.ranked(),
                 country: location.country,
                 region: location.region,
                 dma: location.dma,
                 city: location.city,
                 device_info,
-            }))
+            })
         }
         .boxed_local()
     }
@@ -77,8 +179,9 @@ impl FromRequest for SuggestionRequestWrapper {
 /// A query passed to the API.
 #[derive(Debug, Deserialize)]
 struct SuggestQuery {
-    /// The query to generate suggestions for.
-    q: String,
+    /// The query to generate suggestions for. This is optional here so that
+    /// a missing query is reported as [`HandlerError::MissingQuery`].
+    q: Option<String>,
 }
 
 /// A wrapper around [`SupportedLanguages`].
@@ -91,81 +194,82 @@ impl FromRequest for SupportedLanguagesWrapper {
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        /// Parse the quality value from a string of the form q=`<quality value>`.
-        fn parse_quality_value(quality_value: &str) -> Result<f64, HandlerError> {
-            let (_, weight_as_string) = quality_value
-                .split_once('=')
-                .ok_or(HandlerError::MalformedHeader("Accept-Language"))?;
-
-            let weight = weight_as_string
-                .parse::<f64>()
-                .map_err(|_| HandlerError::MalformedHeader("Accept-Language"))?;
-
-            if (0.0..=1.0).contains(&weight) {
-                Ok(weight)
-            } else {
-                Err(HandlerError::MalformedHeader("Accept-Language"))
-            }
+        let result = match req.headers().get(header::ACCEPT_LANGUAGE) {
+            Some(header) => header
+                .to_str()
+                .map_err(|_| HandlerError::MalformedHeader("Accept-Language"))
+                .and_then(parse_accept_language)
+                .map(Self),
+            None => Ok(Self(SupportedLanguages::wildcard())),
+        };
+
+        future::ready(result.map_err(ActixError::from))
+    }
+}
+
+/// Parse languages in the format of the `Accept-Language` header.
+pub(crate) fn parse_accept_language(header: &str) -> Result<SupportedLanguages, HandlerError> {
+    /// Parse the quality value from a string of the form q=`<quality value>`.
+    fn parse_quality_value(quality_value: &str) -> Result<f64, HandlerError> {
+        let (_, weight_as_string) = quality_value
+            .split_once('=')
+            .ok_or(HandlerError::MalformedHeader("Accept-Language"))?;
+
+        let weight = weight_as_string
+            .parse::<f64>()
+            .map_err(|_| HandlerError::MalformedHeader("Accept-Language"))?;
+
+        if (0.0..=1.0).contains(&weight) {
+            Ok(weight)
+        } else {
+            Err(HandlerError::MalformedHeader("Accept-Language"))
         }
+    }
 
-        /// Parse the Accept-Language HTTP header.
-        fn parse_language(raw_language: &str) -> Result<Language, ActixError> {
-            let (locale_or_wildcard, quality_value) =
-                if let Some((language, quality_value)) = raw_language.split_once(';') {
-                    let quality_value = Some(parse_quality_value(quality_value)?);
-
-                    (language, quality_value)
-                } else {
-                    (raw_language, None)
-                };
-
-            let language = if locale_or_wildcard == "*" {
-                Language {
-                    language_identifier: LanguageIdentifier::Wildcard,
-                    quality_value,
-                }
-            } else if let Some((language, region)) = locale_or_wildcard.split_once("-") {
-                Language {
-                    language_identifier: LanguageIdentifier::Locale {
-                        language: language.to_lowercase(),
-                        region: Some(region.to_lowercase()),
-                    },
-                    quality_value,
-                }
+    /// Parse a single language from the header.
+    fn parse_language(raw_language: &str) -> Result<Language, HandlerError> {
+        let (locale_or_wildcard, quality_value) =
+            if let Some((language, quality_value)) = raw_language.split_once(';') {
+                let quality_value = Some(parse_quality_value(quality_value)?);
+
+                (language, quality_value)
             } else {
-                Language {
-                    language_identifier: LanguageIdentifier::Locale {
-                        language: locale_or_wildcard.to_lowercase(),
-                        region: None,
-                    },
-                    quality_value,
-                }
+                (raw_language, None)
             };
 
-            Ok(language)
-        }
-
-        // A closure is used here to enable the usage of the `?` operator, making error handling
-        // more ergonomic.
-        let parse_header = || {
-            let header = match req.headers().get(header::ACCEPT_LANGUAGE) {
-                Some(header) => header.to_str().map_err::<Self::Error, _>(|_| {
-                    HandlerError::MalformedHeader("Accept-Language").into()
-                }),
-                None => return Ok(Self(SupportedLanguages::wildcard())),
-            }?;
-
-            let languages = header
-                .split(',')
-                .map(str::trim)
-                .map(parse_language)
-                .collect::<Result<Vec<Language>, _>>()?;
-
-            Ok(Self(SupportedLanguages(languages)))
+        let language = if locale_or_wildcard == "*" {
+            Language {
+                language_identifier: LanguageIdentifier::Wildcard,
+                quality_value,
+            }
+        } else if let Some((language, region)) = locale_or_wildcard.split_once("-") {
+            Language {
+                language_identifier: LanguageIdentifier::Locale {
+                    language: language.to_lowercase(),
+                    region: Some(region.to_lowercase()),
+                },
+                quality_value,
+            }
+        } else {
+            Language {
+                language_identifier: LanguageIdentifier::Locale {
+                    language: locale_or_wildcard.to_lowercase(),
+                    region: None,
+                },
+                quality_value,
+            }
         };
 
-        future::ready(parse_header())
+        Ok(language)
     }
+
+    let languages = header
+        .split(',')
+        .map(str::trim)
+        .map(parse_language)
+        .collect::<Result<Vec<Language>, _>>()?;
+
+    Ok(SupportedLanguages(languages))
 }
 
 /// A wrapper around [`DeviceInfo`].
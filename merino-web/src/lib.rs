@@ -5,9 +5,14 @@
 mod debug;
 mod dockerflow;
 mod errors;
+mod experiments;
 mod extractors;
 mod middleware;
+mod normalization;
+mod prometheus_metrics;
+mod reload;
 mod suggest;
+mod telemetry;
 
 use actix_cors::Cors;
 use actix_web::{
@@ -19,10 +24,13 @@ use actix_web::{
 use actix_web_location::{providers::FallbackProvider, Location};
 use anyhow::Context;
 use cadence::StatsdClient;
-use merino_settings::Settings;
+use merino_settings::{MetricsMode, Settings};
 use std::net::TcpListener;
 use tracing_actix_web_mozlog::MozLog;
 
+pub use prometheus_metrics::PrometheusSink;
+pub use telemetry::SpanExporter;
+
 /// Run the web server
 ///
 /// The returned server is a `Future` that must either be `.await`ed, or run it
@@ -39,9 +47,16 @@ use tracing_actix_web_mozlog::MozLog;
 /// have been set. Logs are emitted via [`tracing`], and metrics via
 /// [`cadence`].
 ///
+/// This must be called from within a Tokio runtime, since it spawns tasks that
+/// build the suggestion providers at startup, if [`Settings::provider_startup`]
+/// enables it, and rebuild them on `SIGHUP` or when the config files change, as
+/// configured in [`Settings::provider_reload`].
+///
 /// # Errors
 ///
-/// Returns an error if the server cannot be started on the provided listener.
+/// Returns an error if the server cannot be started on the provided listener,
+/// or if the settings for location lookup, rate limiting, or provider
+/// reloading are invalid.
 ///
 /// # Examples
 ///
@@ -108,22 +123,47 @@ pub fn run(
         config.with_provider(FallbackProvider::new(Location::build()))
     });
 
+    let providers = Data::new(suggest::SuggestionProviderRef::new());
+    if settings.provider_startup.eager {
+        suggest::spawn_eager_setup(providers.clone(), &settings, metrics_client.clone());
+    }
+    reload::spawn_provider_reloader(providers.clone(), &settings, metrics_client.clone())
+        .context("Could not set up provider reloading")?;
+
+    let serve_prometheus_metrics = settings.metrics.mode == MetricsMode::Prometheus;
+    let rate_limit =
+        middleware::RateLimit::new(&settings).context("Could not set up rate limiting")?;
+
     let mut server = HttpServer::new(move || {
         App::new()
             // App state
             .app_data(Data::new((&settings).clone()))
             .app_data(location_config.clone())
             .app_data(Data::new(metrics_client.clone()))
+            .app_data(providers.clone())
             // Middlewares
             .wrap(moz_log.clone())
             .wrap(middleware::Metrics)
             .wrap(middleware::Sentry)
+            .wrap(middleware::TraceContext)
             .wrap(Cors::permissive())
-            // The core functionality of Merino
-            .service(web::scope("api/v1/suggest").configure(suggest::configure))
+            .wrap(middleware::RequestId)
+            // The core functionality of Merino. Only suggestions are rate
+            // limited, so that health checks are never rejected.
+            .service(
+                web::scope("api/v1/suggest")
+                    .wrap(rate_limit.clone())
+                    .configure(suggest::configure),
+            )
             // Add some debugging views
             .service(web::scope("debug").configure(debug::configure))
             .service(root_info)
+            // Serve metrics, if they are not sent to statsd.
+            .configure(|config| {
+                if serve_prometheus_metrics {
+                    prometheus_metrics::configure(config);
+                }
+            })
             // Add the behavior necessary to satisfy Dockerflow.
             .service(web::scope("").configure(dockerflow::configure))
     })
@@ -1,7 +1,13 @@
 //! Middlewares specific to Merino.
 
 mod metrics;
+mod rate_limit;
+mod request_id;
 mod sentry;
+mod trace_context;
 
 pub use self::metrics::Metrics;
+pub use self::rate_limit::RateLimit;
+pub use self::request_id::{current_request_id, RequestId};
 pub use self::sentry::Sentry;
+pub use self::trace_context::TraceContext;
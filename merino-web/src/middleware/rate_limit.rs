@@ -0,0 +1,481 @@
+//! Middleware to limit how many requests each client can make.
+
+use crate::errors::HandlerError;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, HeaderValue},
+    web::Data,
+    Error as ActixError, ResponseError,
+};
+use anyhow::{anyhow, Context as _};
+use cadence::{CountedExt, StatsdClient};
+use ipnet::IpNet;
+use merino_settings::{RateLimitStorage, Settings};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::{ready, Future, Ready},
+    net::{IpAddr, Ipv6Addr},
+    pin::Pin,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    task::Context,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How long to wait for Redis before letting a request through unlimited.
+const REDIS_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// The fewest buckets to keep in memory before removing those of idle clients.
+const MIN_PRUNE_THRESHOLD: usize = 1024;
+
+/// Atomically refill the bucket in `KEYS[1]`, and take a token from it if one
+/// is available. `ARGV` is the burst, the refill rate per second, and the
+/// current time in seconds. Returns 0 if a token was taken, or otherwise the
+/// number of milliseconds until one will be available.
+const TAKE_TOKEN_SCRIPT: &str = r"
+    local burst = tonumber(ARGV[1])
+    local rate = tonumber(ARGV[2])
+    local now = tonumber(ARGV[3])
+    local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+    local tokens = tonumber(bucket[1]) or burst
+    local updated = tonumber(bucket[2]) or now
+    tokens = math.min(burst, tokens + math.max(0, now - updated) * rate)
+    local retry_after_ms = 0
+    if tokens >= 1 then
+        tokens = tokens - 1
+    else
+        retry_after_ms = math.ceil((1 - tokens) / rate * 1000)
+    end
+    redis.call('HSET', KEYS[1], 'tokens', tokens, 'updated', now)
+    redis.call('PEXPIRE', KEYS[1], math.ceil(burst / rate * 1000))
+    return retry_after_ms
+";
+
+/// Factory for [`RateLimitMiddleware`].
+#[derive(Clone)]
+pub struct RateLimit {
+    /// The limiter shared by all workers, or `None` if rate limiting is
+    /// disabled.
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimit {
+    /// Create the rate limiter configured in `settings.rate_limit`.
+    ///
+    /// # Errors
+    /// If the settings are invalid, or the Redis URL can't be used.
+    pub fn new(settings: &Settings) -> anyhow::Result<Self> {
+        let config = &settings.rate_limit;
+        if !config.enabled {
+            return Ok(Self { limiter: None });
+        }
+        if config.burst < 1 {
+            return Err(anyhow!("rate_limit.burst must be at least 1"));
+        }
+        if config.requests_per_second.is_nan() || config.requests_per_second <= 0.0 {
+            return Err(anyhow!("rate_limit.requests_per_second must be positive"));
+        }
+
+        let buckets = match config.storage {
+            RateLimitStorage::Memory => Buckets::Memory(Mutex::new(MemoryBuckets::default())),
+            RateLimitStorage::Redis => Buckets::Redis {
+                client: redis::Client::open(settings.redis.url.clone())
+                    .context("Setting up Redis client for rate limiting")?,
+                connection: tokio::sync::Mutex::new(None),
+            },
+        };
+
+        Ok(Self {
+            limiter: Some(Arc::new(RateLimiter {
+                burst: f64::from(config.burst),
+                refill_rate: config.requests_per_second,
+                trusted_proxies: config.trusted_proxies.clone(),
+                buckets,
+            })),
+        })
+    }
+}
+
+/// Middleware that rejects requests from clients that have made too many
+/// requests recently, with a `429 Too Many Requests` response.
+///
+/// Clients are identified by their IP address. IPv6 clients are identified by
+/// the /64 network of their address, since that is usually assigned to a
+/// single client.
+pub struct RateLimitMiddleware<S> {
+    /// The wrapped service.
+    service: Rc<S>,
+
+    /// The limiter shared by all workers, or `None` if rate limiting is
+    /// disabled.
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<S> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse> + 'static,
+    S::Future: 'static,
+    S::Error: fmt::Debug,
+{
+    type Response = ServiceResponse;
+
+    type Error = ActixError;
+
+    type Transform = RateLimitMiddleware<S>;
+
+    type InitError = ();
+
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+        }))
+    }
+}
+
+impl<S> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse> + 'static,
+    S::Future: 'static,
+    S::Error: fmt::Debug,
+{
+    type Response = ServiceResponse;
+
+    type Error = ActixError;
+
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx).map_err(|error| {
+            tracing::error!(?error, "Error polling service from rate limit middleware");
+            HandlerError::Internal.into()
+        })
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let limited = match (&limiter, req.peer_addr()) {
+                (Some(limiter), Some(peer_addr)) => {
+                    let forwarded_for = req.headers().get_all("x-forwarded-for").collect();
+                    let client = client_ip(peer_addr.ip(), forwarded_for, &limiter.trusted_proxies);
+                    limiter.check(client).await
+                }
+                _ => None,
+            };
+
+            if let Some(retry_after) = limited {
+                if let Some(metrics_client) = req.app_data::<Data<StatsdClient>>() {
+                    metrics_client.incr("request.rate-limited").ok();
+                }
+                let mut response = HandlerError::RateLimited.error_response();
+                let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+                return Ok(req.into_response(response));
+            }
+
+            service.call(req).await.map_err(|error| {
+                tracing::error!(?error, "Error handling request");
+                HandlerError::Internal.into()
+            })
+        })
+    }
+}
+
+/// The address of the client that made a request, as used for rate limiting.
+///
+/// Unlike `ConnectionInfo::realip_remote_addr`, `X-Forwarded-For` is only
+/// respected if the request came from a trusted proxy, since otherwise clients
+/// could choose their own address. The header is read from right to left, and
+/// the first address that isn't a trusted proxy is the client.
+fn client_ip(
+    peer_ip: IpAddr,
+    forwarded_for: Vec<&HeaderValue>,
+    trusted_proxies: &[IpNet],
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let mut client = peer_ip;
+    if is_trusted(&client) {
+        let hops = forwarded_for
+            .into_iter()
+            .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+            .collect::<Vec<_>>();
+        for hop in hops.into_iter().rev() {
+            match hop.trim().parse() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+            if !is_trusted(&client) {
+                break;
+            }
+        }
+    }
+
+    match client {
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                segments[2],
+                segments[3],
+                0,
+                0,
+                0,
+                0,
+            ))
+        }
+        IpAddr::V4(_) => client,
+    }
+}
+
+/// Token buckets for each client.
+struct RateLimiter {
+    /// The capacity of each bucket.
+    burst: f64,
+
+    /// How many tokens are added to each bucket per second.
+    refill_rate: f64,
+
+    /// Networks of proxies trusted to report the client address.
+    trusted_proxies: Vec<IpNet>,
+
+    /// Where the buckets are kept.
+    buckets: Buckets,
+}
+
+/// Where the buckets of a [`RateLimiter`] are kept.
+enum Buckets {
+    /// Buckets in the memory of this instance.
+    Memory(Mutex<MemoryBuckets>),
+
+    /// Buckets in Redis, shared with other instances.
+    Redis {
+        /// The client to connect to Redis with.
+        client: redis::Client,
+
+        /// The connection to Redis, once it has been made.
+        connection: tokio::sync::Mutex<Option<redis::aio::ConnectionManager>>,
+    },
+}
+
+impl RateLimiter {
+    /// Take a token from the bucket of `client`. Returns how long the client
+    /// should wait before trying again if the bucket is empty.
+    ///
+    /// If Redis is used and can't be reached, requests are not limited, so that
+    /// a Redis outage doesn't become an outage of Merino.
+    async fn check(&self, client: IpAddr) -> Option<Duration> {
+        match &self.buckets {
+            Buckets::Memory(buckets) => {
+                let mut buckets = buckets.lock().expect("rate limit buckets were poisoned");
+                buckets.take(client, Instant::now(), self.burst, self.refill_rate)
+            }
+            Buckets::Redis {
+                client: redis_client,
+                connection,
+            } => {
+                let result = tokio::time::timeout(REDIS_TIMEOUT, async {
+                    let mut connection = {
+                        let mut connection = connection.lock().await;
+                        if connection.is_none() {
+                            *connection = Some(
+                                redis::aio::ConnectionManager::new(redis_client.clone()).await?,
+                            );
+                        }
+                        connection.clone().expect("connection was just set")
+                    };
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs_f64();
+                    redis::Script::new(TAKE_TOKEN_SCRIPT)
+                        .key(format!("rate_limit:{}", client))
+                        .arg(self.burst)
+                        .arg(self.refill_rate)
+                        .arg(now)
+                        .invoke_async::<_, u64>(&mut connection)
+                        .await
+                })
+                .await;
+
+                match result {
+                    Ok(Ok(0)) => None,
+                    Ok(Ok(retry_after_ms)) => Some(Duration::from_millis(retry_after_ms)),
+                    Ok(Err(error)) => {
+                        tracing::error!(%error, r#type = "web.rate-limit.redis-error", "Could not check rate limit in Redis");
+                        None
+                    }
+                    Err(_) => {
+                        tracing::error!(
+                            r#type = "web.rate-limit.redis-timeout",
+                            "Redis did not respond to rate limit check in time"
+                        );
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Token buckets kept in memory.
+#[derive(Default)]
+struct MemoryBuckets {
+    /// The bucket of each client.
+    buckets: HashMap<IpAddr, TokenBucket>,
+
+    /// How many buckets to keep before removing the full ones, which belong to
+    /// clients that have been idle long enough to not need them.
+    prune_threshold: usize,
+}
+
+impl MemoryBuckets {
+    /// Take a token from the bucket of `client`, as of `now`. Returns how long
+    /// the client should wait before trying again if the bucket is empty.
+    fn take(&mut self, client: IpAddr, now: Instant, burst: f64, rate: f64) -> Option<Duration> {
+        if self.buckets.len() >= self.prune_threshold.max(MIN_PRUNE_THRESHOLD) {
+            self.buckets
+                .retain(|_, bucket| bucket.tokens_at(now, burst, rate) < burst);
+            self.prune_threshold = self.buckets.len() * 2;
+        }
+
+        let bucket = self.buckets.entry(client).or_insert(TokenBucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.tokens_at(now, burst, rate);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// The tokens of a single client.
+struct TokenBucket {
+    /// The number of tokens as of `updated`.
+    tokens: f64,
+
+    /// When the number of tokens was last calculated.
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// The number of tokens in the bucket at `now`, after refilling.
+    fn tokens_at(&self, now: Instant, burst: f64, rate: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_ip, MemoryBuckets, RateLimit};
+    use actix_web::{http::HeaderValue, http::StatusCode, test, web, App};
+    use merino_settings::Settings;
+    use serde_json::Value;
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    /// Parse `s` as an IP address.
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_proxies() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let header = HeaderValue::from_static("1.1.1.1, 2.2.2.2, 10.0.0.2");
+
+        assert_eq!(
+            client_ip(ip("3.3.3.3"), vec![&header], &trusted),
+            ip("3.3.3.3")
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), vec![&header], &trusted),
+            ip("2.2.2.2")
+        );
+        assert_eq!(client_ip(ip("10.0.0.1"), vec![], &trusted), ip("10.0.0.1"));
+
+        let malformed = HeaderValue::from_static("1.1.1.1, garbage, 10.0.0.2");
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), vec![&malformed], &trusted),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn ipv6_clients_are_grouped_by_network() {
+        assert_eq!(
+            client_ip(ip("2001:db8:1:2:3:4:5:6"), vec![], &[]),
+            ip("2001:db8:1:2::")
+        );
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut buckets = MemoryBuckets::default();
+        let client = ip("1.1.1.1");
+        let start = Instant::now();
+
+        assert_eq!(buckets.take(client, start, 2.0, 1.0), None);
+        assert_eq!(buckets.take(client, start, 2.0, 1.0), None);
+        assert_eq!(
+            buckets.take(client, start, 2.0, 1.0),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(buckets.take(ip("2.2.2.2"), start, 2.0, 1.0), None);
+
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(buckets.take(client, later, 2.0, 1.0), None);
+        assert_eq!(
+            buckets.take(client, later, 2.0, 1.0),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn limited_requests_are_rejected() {
+        let mut settings = Settings::load_for_tests();
+        settings.rate_limit.enabled = true;
+        settings.rate_limit.burst = 2;
+        settings.rate_limit.requests_per_second = 0.1;
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(&settings).unwrap())
+                .route("/", web::get().to(|| async { "ok" })),
+        )
+        .await;
+        let request = || {
+            test::TestRequest::get()
+                .uri("/")
+                .peer_addr("1.1.1.1:1234".parse().unwrap())
+                .to_request()
+        };
+
+        for _ in 0..2 {
+            let response = test::call_service(&app, request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = test::call_service(&app, request()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get("retry-after").unwrap(), "10");
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "rate-limited");
+    }
+}
@@ -0,0 +1,184 @@
+//! Middleware to identify each request, so that error responses can be matched
+//! up with logs.
+
+use crate::errors::HandlerError;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{HeaderName, HeaderValue},
+    Error as ActixError,
+};
+use std::{
+    fmt,
+    future::{ready, Future, Ready},
+    pin::Pin,
+    task::Context,
+};
+use uuid::Uuid;
+
+/// The header that carries request IDs, both from callers and in responses.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The longest request ID that will be accepted from a caller.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    /// The ID of the request currently being handled.
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request currently being handled, if any.
+///
+/// This is only available while handling requests that passed through
+/// [`RequestIdMiddleware`].
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Factory for [`RequestIdMiddleware`].
+pub struct RequestId;
+
+/// Middleware that assigns each request an ID, and includes it in the
+/// `X-Request-Id` response header.
+///
+/// If the request has a well formed `X-Request-Id` header, that ID is used.
+/// Otherwise a random one is generated.
+pub struct RequestIdMiddleware<S> {
+    /// The wrapped service.
+    service: S,
+}
+
+impl<S> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse>,
+    S::Future: 'static,
+    S::Error: fmt::Debug,
+{
+    type Response = ServiceResponse;
+
+    type Error = ActixError;
+
+    type Transform = RequestIdMiddleware<S>;
+
+    type InitError = ();
+
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+impl<S> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse>,
+    S::Future: 'static,
+    S::Error: fmt::Debug,
+{
+    type Response = ServiceResponse;
+
+    type Error = ActixError;
+
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx).map_err(|error| {
+            tracing::error!(?error, "Error polling service from request ID middleware");
+            HandlerError::Internal.into()
+        })
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| is_valid_request_id(value))
+            .map_or_else(|| Uuid::new_v4().to_string(), ToString::to_string);
+
+        let fut = self.service.call(req);
+        Box::pin(REQUEST_ID.scope(request_id.clone(), async move {
+            let mut response = fut.await.map_err(|error| {
+                tracing::error!(?error, "Error handling request");
+                ActixError::from(HandlerError::Internal)
+            })?;
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(response)
+        }))
+    }
+}
+
+/// Whether `id` is acceptable as a request ID from a caller. IDs are limited in
+/// length and characters, so that they are safe to log and echo back.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_request_id, is_valid_request_id, RequestId};
+    use actix_web::{test, web, App};
+    use uuid::Uuid;
+
+    /// Make an app that responds with the current request ID.
+    macro_rules! echo_app {
+        () => {
+            test::init_service(App::new().wrap(RequestId).route(
+                "/",
+                web::get().to(|| async { current_request_id().unwrap_or_default() }),
+            ))
+            .await
+        };
+    }
+
+    #[actix_rt::test]
+    async fn valid_request_ids_are_used() {
+        let app = echo_app!();
+        let request = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("X-Request-Id", "abc-123"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(response.headers().get("x-request-id").unwrap(), "abc-123");
+        assert_eq!(test::read_body(response).await, "abc-123");
+    }
+
+    #[actix_rt::test]
+    async fn request_ids_are_generated() {
+        let app = echo_app!();
+        let request = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("X-Request-Id", "not a valid id"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+
+        let header = response
+            .headers()
+            .get("x-request-id")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(Uuid::parse_str(&header).is_ok());
+        assert_eq!(test::read_body(response).await, header);
+    }
+
+    #[test]
+    fn request_id_validation() {
+        assert!(is_valid_request_id("a1-b2_c3.d4"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("has spaces"));
+        assert!(!is_valid_request_id("<script>"));
+        assert!(!is_valid_request_id(&"a".repeat(129)));
+        assert!(current_request_id().is_none());
+    }
+}
@@ -0,0 +1,160 @@
+//! Middleware to continue traces started by Merino's callers.
+
+use crate::errors::HandlerError;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::HeaderMap,
+    Error as ActixError,
+};
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    sdk::propagation::TraceContextPropagator,
+};
+use std::{
+    fmt,
+    future::{ready, Future, Ready},
+    pin::Pin,
+    task::Context,
+};
+use tracing_futures::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Factory for [`TraceContextMiddleware`].
+pub struct TraceContext;
+
+/// Middleware that records each request in a span. If the request has a W3C
+/// `traceparent` header, the span is exported as a child of the caller's span.
+pub struct TraceContextMiddleware<S> {
+    /// The wrapped service.
+    service: S,
+
+    /// Reads the caller's trace from request headers.
+    propagator: TraceContextPropagator,
+}
+
+impl<S> Transform<S, ServiceRequest> for TraceContext
+where
+    S: Service<ServiceRequest, Response = ServiceResponse>,
+    S::Future: 'static,
+    S::Error: fmt::Debug,
+{
+    type Response = ServiceResponse;
+
+    type Error = ActixError;
+
+    type Transform = TraceContextMiddleware<S>;
+
+    type InitError = ();
+
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TraceContextMiddleware {
+            service,
+            propagator: TraceContextPropagator::new(),
+        }))
+    }
+}
+
+impl<S> Service<ServiceRequest> for TraceContextMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse>,
+    S::Future: 'static,
+    S::Error: fmt::Debug,
+{
+    type Response = ServiceResponse;
+
+    type Error = ActixError;
+
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx).map_err(|error| {
+            tracing::error!(
+                ?error,
+                "Error polling service from trace context middleware"
+            );
+            HandlerError::Internal.into()
+        })
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let span = tracing::info_span!(
+            "request",
+            method = %req.method(),
+            path = %req.path(),
+        );
+        let parent = self.propagator.extract(&HeaderExtractor(req.headers()));
+        span.set_parent(parent);
+
+        let fut = span.in_scope(|| self.service.call(req));
+        Box::pin(
+            async move { fut.await.map_err(|_err| HandlerError::Internal.into()) }.instrument(span),
+        )
+    }
+}
+
+/// Reads propagated trace context from Actix request headers.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeaderExtractor;
+    use actix_web::{http::HeaderMap, test::TestRequest};
+    use opentelemetry::{
+        propagation::TextMapPropagator, sdk::propagation::TraceContextPropagator,
+        trace::TraceContextExt,
+    };
+
+    /// Get the trace ID and parent span ID that `headers` propagate, if any.
+    fn propagated_ids(headers: &HeaderMap) -> Option<(String, String)> {
+        let context = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+        context
+            .remote_span_context()
+            .filter(|span_context| span_context.is_valid())
+            .map(|span_context| {
+                (
+                    span_context.trace_id().to_hex(),
+                    span_context.span_id().to_hex(),
+                )
+            })
+    }
+
+    #[test]
+    fn traceparent_header_is_extracted() {
+        let request = TestRequest::default()
+            .insert_header((
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            ))
+            .to_http_request();
+
+        assert_eq!(
+            propagated_ids(request.headers()),
+            Some((
+                "0af7651916cd43dd8448eb211c80319c".to_string(),
+                "b7ad6b7169203331".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn requests_without_traceparent_start_new_traces() {
+        let request = TestRequest::default()
+            .insert_header(("traceparent", "not a trace"))
+            .to_http_request();
+        assert_eq!(propagated_ids(request.headers()), None);
+        assert_eq!(propagated_ids(&HeaderMap::new()), None);
+    }
+}
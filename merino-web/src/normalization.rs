@@ -0,0 +1,113 @@
+//! Normalization of query text, so that queries that differ only in ways the
+//! user wouldn't care about are treated the same by providers and caches.
+
+use merino_settings::QueryNormalizationSettings;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Normalize `query` by applying each of the steps enabled in `settings`.
+pub fn normalize_query(query: &str, settings: &QueryNormalizationSettings) -> String {
+    let mut normalized = if settings.unicode_nfkc {
+        query.nfkc().collect()
+    } else {
+        query.to_string()
+    };
+
+    if settings.lowercase {
+        normalized = normalized.to_lowercase();
+    }
+
+    if settings.fold_diacritics {
+        // Decompose characters so that diacritics become separate combining
+        // marks, drop the marks, and then recompose whatever is left.
+        normalized = normalized
+            .nfd()
+            .filter(|c| !is_combining_mark(*c))
+            .nfc()
+            .collect();
+    }
+
+    if settings.collapse_whitespace {
+        let mut collapsed = String::with_capacity(normalized.len());
+        let mut previous_was_whitespace = false;
+        for c in normalized.chars() {
+            if c.is_whitespace() {
+                if !previous_was_whitespace {
+                    collapsed.push(' ');
+                }
+                previous_was_whitespace = true;
+            } else {
+                collapsed.push(c);
+                previous_was_whitespace = false;
+            }
+        }
+        normalized = collapsed;
+    }
+
+    if settings.trim {
+        normalized = normalized.trim().to_string();
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_query;
+    use merino_settings::QueryNormalizationSettings;
+
+    fn all_steps() -> QueryNormalizationSettings {
+        QueryNormalizationSettings {
+            unicode_nfkc: true,
+            lowercase: true,
+            fold_diacritics: true,
+            collapse_whitespace: true,
+            trim: true,
+        }
+    }
+
+    fn no_steps() -> QueryNormalizationSettings {
+        QueryNormalizationSettings {
+            unicode_nfkc: false,
+            lowercase: false,
+            fold_diacritics: false,
+            collapse_whitespace: false,
+            trim: false,
+        }
+    }
+
+    #[test]
+    fn equivalent_queries_are_normalized_the_same() {
+        let settings = all_steps();
+        for query in &["amazon", "Amazon ", "AMAZON", "  amazon\t", "ａｍａｚｏｎ"] {
+            assert_eq!(normalize_query(query, &settings), "amazon", "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn whitespace_is_collapsed() {
+        let settings = all_steps();
+        assert_eq!(normalize_query("new  \t york\n", &settings), "new york");
+    }
+
+    #[test]
+    fn diacritics_are_folded() {
+        let settings = all_steps();
+        assert_eq!(normalize_query("Café Crème", &settings), "cafe creme");
+        assert_eq!(normalize_query("Ǆemal", &settings), "dzemal");
+    }
+
+    #[test]
+    fn diacritics_are_kept_when_folding_is_disabled() {
+        let settings = QueryNormalizationSettings {
+            fold_diacritics: false,
+            ..all_steps()
+        };
+        assert_eq!(normalize_query("Café", &settings), "café");
+    }
+
+    #[test]
+    fn disabled_steps_leave_query_unchanged() {
+        let query = "  Café\tＣrème ";
+        assert_eq!(normalize_query(query, &no_steps()), query);
+    }
+}
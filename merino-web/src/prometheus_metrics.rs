@@ -0,0 +1,322 @@
+//! Serving metrics in the Prometheus text format, as an alternative to sending
+//! them to statsd.
+//!
+//! Metrics are still recorded with [`cadence`]. A [`PrometheusSink`] parses the
+//! statsd lines that cadence produces, and aggregates them in process. Tags
+//! become labels, so every metric with the same name must always be sent with
+//! the same set of tags.
+
+use actix_web::{get, web::ServiceConfig, HttpResponse};
+use cadence::MetricSink;
+use lazy_static::lazy_static;
+use prometheus::{
+    CounterVec, Encoder, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry, TextEncoder,
+};
+use std::{collections::HashMap, io, sync::Mutex};
+
+lazy_static! {
+    /// The registry that [`PrometheusSink::default`] records into, and that
+    /// the `/__metrics__` route serves.
+    static ref REGISTRY: MetricsRegistry = MetricsRegistry::new();
+}
+
+/// The buckets for histograms of counts, such as the number of suggestions per
+/// request. Timers use Prometheus' default buckets, which are in seconds.
+const COUNT_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0, 100.0];
+
+/// Serve the metrics recorded by [`PrometheusSink`]s in the Prometheus text
+/// format.
+pub fn configure(config: &mut ServiceConfig) {
+    config.service(metrics);
+}
+
+/// Render every metric recorded so far.
+#[get("__metrics__")]
+fn metrics() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    match REGISTRY.render(&encoder) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(body),
+        Err(error) => {
+            tracing::error!(r#type = "web.metrics.render-error", %error, "Could not render metrics");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// A cadence sink that records metrics in process, to be served by the
+/// `/__metrics__` route.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PrometheusSink;
+
+impl MetricSink for PrometheusSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        REGISTRY.record(metric)?;
+        Ok(metric.len())
+    }
+}
+
+/// The kind of a Prometheus metric, along with the metric itself.
+#[derive(Clone)]
+enum Collector {
+    /// Made from statsd counters.
+    Counter(CounterVec),
+    /// Made from statsd gauges.
+    Gauge(GaugeVec),
+    /// Made from statsd timers and histograms.
+    Histogram(HistogramVec),
+}
+
+/// Prometheus metrics that are created as cadence sends them.
+struct MetricsRegistry {
+    /// The registry that is rendered.
+    registry: Registry,
+
+    /// The metrics that have been created, by their Prometheus name.
+    collectors: Mutex<HashMap<String, Collector>>,
+}
+
+/// A single metric parsed from a statsd line.
+#[derive(Debug, PartialEq)]
+struct StatsdMetric<'a> {
+    /// The Prometheus name of the metric.
+    name: String,
+    /// The recorded value, in seconds for timers.
+    value: f64,
+    /// The statsd type of the metric, such as `c` or `ms`.
+    metric_type: &'a str,
+    /// The tags of the metric, with names converted to Prometheus label names.
+    labels: HashMap<String, &'a str>,
+}
+
+impl MetricsRegistry {
+    /// Create an empty registry.
+    fn new() -> Self {
+        Self {
+            registry: Registry::new(),
+            collectors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record the metric in the statsd line `line`.
+    ///
+    /// # Errors
+    /// If the line cannot be parsed, or the metric was previously recorded as a
+    /// different type or with different tags.
+    fn record(&self, line: &str) -> io::Result<()> {
+        let metric = match StatsdMetric::parse(line)? {
+            Some(metric) => metric,
+            // Sets and other types Prometheus has no equivalent for.
+            None => return Ok(()),
+        };
+        let labels: HashMap<&str, &str> = metric
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+
+        let mut collectors = self.collectors.lock().expect("mutex was poisoned");
+        let collector = match collectors.get(&metric.name) {
+            Some(collector) => collector.clone(),
+            None => {
+                let collector = self.create(&metric).map_err(invalid_data)?;
+                collectors.insert(metric.name.clone(), collector.clone());
+                collector
+            }
+        };
+        drop(collectors);
+
+        match (collector, metric.metric_type) {
+            (Collector::Counter(counter), "c") if metric.value >= 0.0 => counter
+                .get_metric_with(&labels)
+                .map_err(invalid_data)?
+                .inc_by(metric.value),
+            (Collector::Gauge(gauge), "g") => gauge
+                .get_metric_with(&labels)
+                .map_err(invalid_data)?
+                .set(metric.value),
+            (Collector::Histogram(histogram), "ms") | (Collector::Histogram(histogram), "h") => {
+                histogram
+                    .get_metric_with(&labels)
+                    .map_err(invalid_data)?
+                    .observe(metric.value)
+            }
+            _ => {
+                return Err(invalid_data(format!(
+                    "metric {} cannot be recorded as type {}",
+                    metric.name, metric.metric_type
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Create and register a collector that can record `metric`.
+    fn create(&self, metric: &StatsdMetric) -> prometheus::Result<Collector> {
+        let mut label_names: Vec<&str> = metric.labels.keys().map(String::as_str).collect();
+        label_names.sort_unstable();
+        let help = format!("{} (statsd type {})", metric.name, metric.metric_type);
+
+        let collector = match metric.metric_type {
+            "c" => {
+                let opts = Opts::new(metric.name.clone(), help);
+                let counter = CounterVec::new(opts, &label_names)?;
+                self.registry.register(Box::new(counter.clone()))?;
+                Collector::Counter(counter)
+            }
+            "g" => {
+                let opts = Opts::new(metric.name.clone(), help);
+                let gauge = GaugeVec::new(opts, &label_names)?;
+                self.registry.register(Box::new(gauge.clone()))?;
+                Collector::Gauge(gauge)
+            }
+            metric_type => {
+                let mut opts = HistogramOpts::new(metric.name.clone(), help);
+                if metric_type == "h" {
+                    opts = opts.buckets(COUNT_BUCKETS.to_vec());
+                }
+                let histogram = HistogramVec::new(opts, &label_names)?;
+                self.registry.register(Box::new(histogram.clone()))?;
+                Collector::Histogram(histogram)
+            }
+        };
+        Ok(collector)
+    }
+
+    /// Render every metric with `encoder`.
+    fn render<E: Encoder>(&self, encoder: &E) -> prometheus::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        encoder.encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+impl<'a> StatsdMetric<'a> {
+    /// Parse a statsd line such as `merino.request.duration:12|ms|#path:/`.
+    ///
+    /// Returns `None` for metric types that are not recorded.
+    fn parse(line: &'a str) -> io::Result<Option<Self>> {
+        let mut sections = line.split('|');
+        let (key, value) = sections
+            .next()
+            .and_then(|section| section.rsplit_once(':'))
+            .ok_or_else(|| invalid_data(format!("malformed metric {:?}", line)))?;
+        let metric_type = sections
+            .next()
+            .ok_or_else(|| invalid_data(format!("metric {:?} has no type", line)))?;
+        let value: f64 = value
+            .parse()
+            .map_err(|_| invalid_data(format!("metric {:?} has an invalid value", line)))?;
+
+        let (name, value) = match metric_type {
+            "c" => (format!("{}_total", prometheus_name(key)), value),
+            "g" | "h" => (prometheus_name(key), value),
+            "ms" => (format!("{}_seconds", prometheus_name(key)), value / 1000.0),
+            _ => return Ok(None),
+        };
+
+        let mut labels = HashMap::new();
+        for section in sections {
+            if let Some(tags) = section.strip_prefix('#') {
+                for tag in tags.split(',') {
+                    let (tag_name, tag_value) = tag.split_once(':').unwrap_or((tag, ""));
+                    labels.insert(prometheus_name(tag_name), tag_value);
+                }
+            }
+        }
+
+        Ok(Some(Self {
+            name,
+            value,
+            metric_type,
+            labels,
+        }))
+    }
+}
+
+/// Convert a statsd metric or tag name to a valid Prometheus name.
+fn prometheus_name(statsd_name: &str) -> String {
+    statsd_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Make an I/O error for metrics that cannot be recorded.
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MetricsRegistry, StatsdMetric};
+    use prometheus::TextEncoder;
+
+    /// Render `registry` as text.
+    fn render(registry: &MetricsRegistry) -> String {
+        String::from_utf8(registry.render(&TextEncoder::new()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn statsd_lines_are_parsed() {
+        let metric = StatsdMetric::parse("merino.request.duration:250|ms|#path:/api/v1/suggest")
+            .unwrap()
+            .unwrap();
+        assert_eq!(metric.name, "merino_request_duration_seconds");
+        assert!((metric.value - 0.25).abs() < f64::EPSILON);
+        assert_eq!(metric.metric_type, "ms");
+        assert_eq!(metric.labels.get("path"), Some(&"/api/v1/suggest"));
+
+        assert_eq!(StatsdMetric::parse("merino.users:42|s").unwrap(), None);
+        assert!(StatsdMetric::parse("merino.broken").is_err());
+    }
+
+    #[test]
+    fn counters_are_rendered_with_labels() {
+        let registry = MetricsRegistry::new();
+        registry
+            .record("merino.client_variants:1|c|#variant:treatment")
+            .unwrap();
+        registry
+            .record("merino.client_variants:1|c|#variant:treatment")
+            .unwrap();
+        registry
+            .record("merino.client_variants:1|c|#variant:control")
+            .unwrap();
+
+        let text = render(&registry);
+        assert!(text.contains("# TYPE merino_client_variants_total counter"));
+        assert!(text.contains(r#"merino_client_variants_total{variant="treatment"} 2"#));
+        assert!(text.contains(r#"merino_client_variants_total{variant="control"} 1"#));
+    }
+
+    #[test]
+    fn histograms_are_rendered() {
+        let registry = MetricsRegistry::new();
+        registry
+            .record("merino.request.suggestion-per:2|h")
+            .unwrap();
+        registry
+            .record("merino.request.duration:20|ms|#path:/")
+            .unwrap();
+
+        let text = render(&registry);
+        assert!(text.contains(r#"merino_request_suggestion_per_bucket{le="2"} 1"#));
+        assert!(text.contains("merino_request_suggestion_per_count 1"));
+        assert!(text.contains(r#"merino_request_duration_seconds_count{path="/"} 1"#));
+    }
+
+    #[test]
+    fn mismatched_tags_and_types_are_rejected() {
+        let registry = MetricsRegistry::new();
+        registry.record("merino.startup:1|c").unwrap();
+        assert!(registry.record("merino.startup:1|c|#extra:tag").is_err());
+
+        registry.record("merino.cache.size:1|g").unwrap();
+        assert!(registry.record("merino.cache.size:1|h").is_err());
+    }
+}
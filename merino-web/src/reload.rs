@@ -0,0 +1,180 @@
+//! Rebuilding the suggestion providers when Merino's configuration changes.
+
+use crate::suggest::SuggestionProviderRef;
+use actix_web::web::Data;
+use cadence::{CountedExt, StatsdClient};
+use merino_settings::Settings;
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// The directory that settings are loaded from.
+const CONFIG_DIR: &str = "config";
+
+/// Spawn a task that rebuilds `providers` from freshly loaded settings whenever
+/// Merino receives `SIGHUP`, or, if enabled, a config file changes.
+///
+/// If the settings cannot be loaded, or the new provider tree cannot be built,
+/// the previous providers keep serving requests.
+///
+/// # Errors
+/// If the configured poll interval is zero.
+pub(crate) fn spawn_provider_reloader(
+    providers: Data<SuggestionProviderRef>,
+    settings: &Settings,
+    metrics_client: StatsdClient,
+) -> anyhow::Result<()> {
+    let reload_settings = settings.provider_reload.clone();
+    if reload_settings.poll_interval.is_zero() {
+        anyhow::bail!("provider_reload.poll_interval_sec must be greater than zero");
+    }
+
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => Some(hangups),
+            Err(error) => {
+                tracing::error!(
+                    r#type = "web.reload.signal-error",
+                    %error,
+                    "Could not listen for SIGHUP, providers will not be rebuilt on SIGHUP"
+                );
+                None
+            }
+        };
+        let mut timer = reload_settings
+            .watch_config_files
+            .then(|| tokio::time::interval(reload_settings.poll_interval));
+        let mut last_fingerprint = config_fingerprint(Path::new(CONFIG_DIR));
+
+        loop {
+            tokio::select! {
+                Some(()) = async { hangups.as_mut()?.recv().await } => {
+                    tracing::info!(r#type = "web.reload.sighup", "Received SIGHUP");
+                }
+                Some(_) = async { Some(timer.as_mut()?.tick().await) } => {
+                    let fingerprint = config_fingerprint(Path::new(CONFIG_DIR));
+                    if fingerprint == last_fingerprint {
+                        continue;
+                    }
+                    last_fingerprint = fingerprint;
+                    tracing::info!(r#type = "web.reload.config-changed", "Config files changed");
+                }
+                else => break,
+            }
+
+            reload(&providers, &metrics_client).await;
+        }
+    });
+    Ok(())
+}
+
+/// Load settings and rebuild `providers` from them, reporting the outcome.
+async fn reload(providers: &SuggestionProviderRef, metrics_client: &StatsdClient) {
+    let result = match Settings::load() {
+        Ok(settings) => providers.reload(&settings, metrics_client).await,
+        Err(error) => Err(error),
+    };
+
+    match result {
+        Ok(()) => {
+            tracing::info!(
+                r#type = "web.reload.success",
+                "Rebuilt suggestion providers"
+            );
+            metrics_client
+                .incr_with_tags("providers.reload")
+                .with_tag("status", "success")
+                .send();
+        }
+        Err(error) => {
+            tracing::error!(
+                r#type = "web.reload.error",
+                ?error,
+                "Could not rebuild suggestion providers, keeping previous providers"
+            );
+            metrics_client
+                .incr_with_tags("providers.reload")
+                .with_tag("status", "error")
+                .send();
+        }
+    }
+}
+
+/// The details of a config file that change when it is added, removed,
+/// replaced, or modified.
+#[derive(Debug, PartialEq, Eq)]
+struct FileFingerprint {
+    /// The path of the file in the config directory.
+    path: PathBuf,
+    /// The file that `path` resolves to, after following symlinks.
+    target: Option<PathBuf>,
+    /// When the file was last modified.
+    modified: Option<SystemTime>,
+    /// The size of the file in bytes.
+    len: u64,
+}
+
+/// A fingerprint of every file in `dir`, sorted by path, if it can be read.
+///
+/// Comparing whole fingerprints, rather than only the newest modification
+/// time, also notices deleted files and files replaced with older ones, such
+/// as by `cp -p` or a swapped symlink.
+fn config_fingerprint(dir: &Path) -> Option<Vec<FileFingerprint>> {
+    let mut files: Vec<FileFingerprint> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let metadata = std::fs::metadata(&path).ok()?;
+            Some(FileFingerprint {
+                target: std::fs::canonicalize(&path).ok(),
+                modified: metadata.modified().ok(),
+                len: metadata.len(),
+                path,
+            })
+        })
+        .collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Some(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::config_fingerprint;
+    use std::path::Path;
+
+    #[test]
+    fn missing_config_dir_has_no_fingerprint() {
+        assert_eq!(config_fingerprint(Path::new("does/not/exist")), None);
+    }
+
+    #[test]
+    fn config_dir_has_fingerprint() {
+        assert!(!config_fingerprint(Path::new("../config"))
+            .expect("config dir should be readable")
+            .is_empty());
+    }
+
+    #[test]
+    fn fingerprint_changes_when_files_are_added_changed_or_removed() {
+        let dir = std::env::temp_dir().join(format!("merino-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("could not create temp dir");
+        std::fs::write(dir.join("a.yaml"), "a: 1").expect("could not write file");
+        let original = config_fingerprint(&dir);
+
+        std::fs::write(dir.join("b.yaml"), "b: 1").expect("could not write file");
+        let added = config_fingerprint(&dir);
+        assert_ne!(added, original);
+
+        std::fs::write(dir.join("a.yaml"), "a: 12").expect("could not write file");
+        let changed = config_fingerprint(&dir);
+        assert_ne!(changed, added);
+
+        std::fs::remove_file(dir.join("b.yaml")).expect("could not remove file");
+        let removed = config_fingerprint(&dir);
+        assert_ne!(removed, changed);
+
+        std::fs::remove_dir_all(&dir).expect("could not remove temp dir");
+    }
+}
@@ -1,30 +1,59 @@
 //! Web handlers for the suggestions API.
 
-use crate::{errors::HandlerError, extractors::SuggestionRequestWrapper};
+use crate::{
+    errors::HandlerError,
+    experiments::{self, Assignment},
+    extractors::{
+        checked_query, parse_accept_language, SuggestionContext, SuggestionRequestWrapper,
+    },
+};
 use actix_web::{
     get,
-    web::{self, Data, ServiceConfig},
-    HttpResponse,
+    http::header,
+    post,
+    web::{self, Data, JsonConfig, ServiceConfig},
+    HttpRequest, HttpResponse,
 };
 use anyhow::Result;
+use arc_swap::ArcSwapOption;
 use async_recursion::async_recursion;
 use cadence::{CountedExt, Histogrammed, StatsdClient};
-use merino_adm::remote_settings::RemoteSettingsSuggester;
+use futures_util::future::join_all;
+use merino_adm::{remote_settings::RemoteSettingsSuggester, server_side::AdmServerSideSuggester};
 use merino_cache::{MemoryCacheSuggester, RedisCacheSuggester};
 use merino_settings::{providers::SuggestionProviderConfig, Settings};
 use merino_suggest::{
-    DebugProvider, Multi, NullProvider, Suggestion, SuggestionProvider, WikiFruit,
+    DebugProvider, DeviceTargeting, Instrumented, LocationGranularity, Multi, NullProvider,
+    SuggestError, Suggestion, SuggestionProvider, SuggestionRequest, SuggestionResponse, WikiFruit,
 };
 use serde::{Deserialize, Serialize};
 use serde_with::{rust::StringWithSeparator, serde_as, CommaSeparator};
-use tokio::sync::OnceCell;
+use std::{collections::HashMap, ops::Deref, sync::Arc};
+use tokio::sync::Mutex;
 use tracing_futures::Instrument;
 
+/// The largest batch request body that will be read, in bytes.
+const MAX_BATCH_BODY_SIZE: usize = 1024 * 1024;
+
 /// Configure a route to use the Suggest service.
+///
+/// The app must also provide a `Data<SuggestionProviderRef>`.
 pub fn configure(config: &mut ServiceConfig) {
     config
-        .app_data(Data::new(SuggestionProviderRef(OnceCell::new())))
-        .service(suggest);
+        .app_data(
+            JsonConfig::default()
+                .limit(MAX_BATCH_BODY_SIZE)
+                .error_handler(|error, _request| {
+                    tracing::debug!(
+                        %error,
+                        r#type = "web.suggest.malformed-body",
+                        "Malformed request body"
+                    );
+                    HandlerError::MalformedBody.into()
+                }),
+        )
+        .service(suggest)
+        .service(suggest_batch);
 }
 
 /// The response the API generates.
@@ -34,7 +63,7 @@ struct SuggestResponse<'a> {
     suggestions: Vec<SuggestionWrapper<'a>>,
     /// A list of taken from the request query
     client_variants: Vec<String>,
-    /// An empty list
+    /// The server side experiment variants the request was assigned to
     server_variants: Vec<String>,
 }
 /// Query parameters
@@ -45,6 +74,14 @@ struct SuggestQueryParameters {
     #[serde(default)]
     /// Query Paramater for client_variants
     client_variants: Vec<String>,
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, String>")]
+    #[serde(default)]
+    /// Query parameter to only get suggestions from the named top level
+    /// providers
+    providers: Vec<String>,
+    /// Query parameter to assign the request to server side experiment
+    /// variants by, instead of by details of the request
+    bucket_id: Option<String>,
 }
 /// Customizes the output format of [`Suggestion`].
 #[derive(Debug)]
@@ -52,33 +89,39 @@ struct SuggestionWrapper<'a>(&'a Suggestion);
 
 /// Suggest content in response to the queried text.
 #[get("")]
-#[tracing::instrument(skip(suggestion_request, provider, settings))]
+#[tracing::instrument(skip(suggestion_request, original_query, http_request, provider, settings))]
 async fn suggest(
-    SuggestionRequestWrapper(suggestion_request): SuggestionRequestWrapper,
+    SuggestionRequestWrapper {
+        request: suggestion_request,
+        original_query,
+    }: SuggestionRequestWrapper,
+    http_request: HttpRequest,
     provider: Data<SuggestionProviderRef>,
     settings: Data<Settings>,
     metrics_client: Data<StatsdClient>,
     query_parameters: web::Query<SuggestQueryParameters>,
 ) -> Result<HttpResponse, HandlerError> {
     let provider = provider
-        .get_or_try_init(settings.as_ref())
-        .await
-        .map_err(|error| {
-            tracing::error!(
-                ?error,
-                r#type = "web.suggest.setup-error",
-                "suggester error"
-            );
-            HandlerError::Internal
-        })?;
+        .get_or_init_for_request(settings.as_ref(), metrics_client.as_ref())
+        .await?;
+    let settings = provider.settings();
+
+    tracing::debug!(
+        r#type = "web.suggest.query",
+        %original_query,
+        query = %suggestion_request.query,
+        "Normalized query"
+    );
 
+    let selected = provider.select(&query_parameters.providers)?;
+    let assignments = experiments::assign(
+        &settings.experiments,
+        &experiments::bucket_key(&http_request, query_parameters.bucket_id.as_deref()),
+    );
     let response = provider
-        .suggest(suggestion_request)
+        .suggest_from(suggestion_request, selected, &assignments)
         .await
-        .map_err(|error| {
-            tracing::error!(%error, r#type="web.suggest.error", "Error providing suggestions");
-            HandlerError::Internal
-        })?;
+        .map_err(suggest_error)?;
 
     tracing::debug!(
         r#type = "web.suggest.provided-count",
@@ -91,84 +134,623 @@ async fn suggest(
 
     for client_variant in &query_parameters.client_variants {
         metrics_client
-            .incr(&format!("client_variants.{}", client_variant))
-            .ok();
+            .incr_with_tags("client_variants")
+            .with_tag("variant", client_variant)
+            .send();
+    }
+    let server_variants: Vec<String> = assignments
+        .into_iter()
+        .map(|assignment| assignment.variant)
+        .collect();
+    for server_variant in &server_variants {
+        metrics_client
+            .incr_with_tags("server_variants")
+            .with_tag("variant", server_variant)
+            .send();
     }
 
-    let res = HttpResponse::Ok()
-        .append_header(("X-Cache", response.cache_status.to_string()))
-        .json(SuggestResponse {
-            suggestions: response.suggestions.iter().map(SuggestionWrapper).collect(),
-            client_variants: query_parameters.client_variants.clone(),
-            server_variants: Vec::new(),
-        });
+    let body = serde_json::to_vec(&SuggestResponse {
+        suggestions: response.suggestions.iter().map(SuggestionWrapper).collect(),
+        client_variants: query_parameters.client_variants.clone(),
+        server_variants,
+    })
+    .map_err(|error| {
+        tracing::error!(
+            %error,
+            r#type = "web.suggest.serialize-error",
+            "Error serializing suggestions"
+        );
+        HandlerError::Internal
+    })?;
+    let etag = format!("\"{}\"", &blake3::hash(&body).to_hex()[..32]);
+
+    // Location and experiment assignment aren't headers that shared caches can
+    // vary on, so responses that may depend on them are only cached by the
+    // client.
+    let shareable = provider.location_granularity() == LocationGranularity::None
+        && settings.experiments.is_empty();
+    let cache_control = match response.cache_ttl {
+        Some(ttl) if shareable => format!("max-age={}", ttl.as_secs()),
+        Some(ttl) => format!("private, max-age={}", ttl.as_secs()),
+        None => "no-cache".to_string(),
+    };
+
+    let not_modified = http_request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|if_none_match| etag_matches(if_none_match, &etag))
+        .unwrap_or_default();
+    let mut res = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    res.append_header(("X-Cache", response.cache_status.to_string()))
+        .append_header((header::CACHE_CONTROL, cache_control))
+        .append_header((header::VARY, "Accept-Language, User-Agent"))
+        .append_header((header::ETAG, etag));
+
+    if not_modified {
+        Ok(res.finish())
+    } else {
+        Ok(res.content_type("application/json").body(body))
+    }
+}
+
+/// Check if the `If-None-Match` header value `if_none_match` matches `etag`.
+///
+/// Comparison is weak, as required for `If-None-Match`, so a `W/` prefix is
+/// ignored.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Map an error from the provider tree to the error to respond with.
+fn suggest_error(error: SuggestError) -> HandlerError {
+    tracing::error!(%error, r#type="web.suggest.error", "Error providing suggestions");
+    match error {
+        SuggestError::Timeout(_) => HandlerError::UpstreamTimeout,
+        _ => HandlerError::Internal,
+    }
+}
+
+/// A request for suggestions for several queries at once.
+#[derive(Debug, Deserialize)]
+struct BatchSuggestRequest {
+    /// The queries to provide suggestions for.
+    requests: Vec<BatchSuggestItem>,
+}
 
-    Ok(res)
+/// A single query in a [`BatchSuggestRequest`].
+///
+/// The locale and location of the query default to those of the batch
+/// request, like they would for a single query.
+#[derive(Debug, Deserialize)]
+struct BatchSuggestItem {
+    /// The query to provide suggestions for.
+    q: String,
+
+    /// Overrides the locales of the query, in the format of the
+    /// `Accept-Language` header.
+    #[serde(default)]
+    locale: Option<String>,
+
+    /// Overrides the location of the query.
+    #[serde(default)]
+    location: Option<LocationOverride>,
+}
+
+/// The location of a [`BatchSuggestItem`]. Fields that are left out are
+/// treated as unknown, rather than taken from the batch request.
+#[derive(Debug, Deserialize)]
+struct LocationOverride {
+    /// Country in ISO 3166-1 alpha-2 format.
+    country: Option<String>,
+
+    /// Region in ISO 3166-2 format.
+    region: Option<String>,
+
+    /// The Designated Market Area code.
+    dma: Option<u16>,
+
+    /// City name.
+    city: Option<String>,
+}
+
+impl BatchSuggestItem {
+    /// Make a request for this item's query, in `context` unless overridden.
+    fn to_request(
+        &self,
+        context: &SuggestionContext,
+        settings: &Settings,
+    ) -> Result<SuggestionRequest, HandlerError> {
+        let mut request = context.request_for(checked_query(&self.q, settings)?);
+        if let Some(locale) = &self.locale {
+            request.accepted_locales = parse_accept_language(locale)
+                .map_err(|_| HandlerError::InvalidField("locale"))?
+                .ranked();
+        }
+        if let Some(location) = &self.location {
+            request.country = location.country.clone();
+            request.region = location.region.clone();
+            request.dma = location.dma;
+            request.city = location.city.clone();
+        }
+        Ok(request)
+    }
+}
+
+/// The response to a [`BatchSuggestRequest`].
+#[derive(Debug, Serialize)]
+struct BatchSuggestResponse<'a> {
+    /// The result of each query, in the same order as the request.
+    results: Vec<BatchSuggestResult<'a>>,
 }
 
-/// The SuggestionProvider stored in Actix's app_data.
-struct SuggestionProviderRef(OnceCell<merino_suggest::Multi>);
+/// The result of a single query in a batch.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BatchSuggestResult<'a> {
+    /// Suggestions were provided for the query.
+    Suggestions {
+        /// A list of suggestions from the service.
+        suggestions: Vec<SuggestionWrapper<'a>>,
+    },
+
+    /// The query could not be handled.
+    Error {
+        /// Why the query could not be handled.
+        error: BatchSuggestError,
+    },
+}
+
+/// Describes why a query in a batch could not be handled.
+#[derive(Debug, Serialize)]
+struct BatchSuggestError {
+    /// See [`HandlerError::code`].
+    code: &'static str,
+
+    /// A human readable description of the error.
+    message: String,
+}
+
+impl From<&HandlerError> for BatchSuggestError {
+    fn from(error: &HandlerError) -> Self {
+        Self {
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Suggest content for several queries at once.
+///
+/// The queries are handled concurrently, and their results are returned in
+/// order. A query that fails doesn't fail the others.
+#[post("/batch")]
+#[tracing::instrument(skip(context, batch, provider, settings, metrics_client))]
+async fn suggest_batch(
+    context: SuggestionContext,
+    batch: web::Json<BatchSuggestRequest>,
+    provider: Data<SuggestionProviderRef>,
+    settings: Data<Settings>,
+    metrics_client: Data<StatsdClient>,
+) -> Result<HttpResponse, HandlerError> {
+    let provider = provider
+        .get_or_init_for_request(settings.as_ref(), metrics_client.as_ref())
+        .await?;
+    let settings = provider.settings();
+
+    let max_batch_size = settings.suggest_api.max_batch_size;
+    if batch.requests.len() > max_batch_size {
+        return Err(HandlerError::BatchTooLarge(max_batch_size));
+    }
+
+    metrics_client
+        .histogram("request.batch-size", batch.requests.len() as u64)
+        .ok();
+
+    let provider = &provider;
+    let responses = join_all(batch.requests.iter().map(|item| {
+        let request = item.to_request(&context, settings);
+        async move {
+            provider
+                .suggest_from(request?, None, &[])
+                .await
+                .map_err(suggest_error)
+        }
+    }))
+    .await;
+
+    let results = responses
+        .iter()
+        .map(|response| match response {
+            Ok(response) => BatchSuggestResult::Suggestions {
+                suggestions: response.suggestions.iter().map(SuggestionWrapper).collect(),
+            },
+            Err(error) => BatchSuggestResult::Error {
+                error: error.into(),
+            },
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(BatchSuggestResponse { results }))
+}
+
+/// The top level providers, along with the names they are configured with in
+/// `Settings::suggestion_providers`, and the providers that experiment
+/// variants use instead.
+///
+/// This dereferences to the [`Multi`] that aggregates every top level provider.
+pub(crate) struct ProviderTree {
+    /// Aggregates suggestions from every top level provider.
+    multi: Multi,
+
+    /// Describes each top level provider, in the same order as the providers
+    /// of `multi`.
+    entries: Vec<ProviderEntry>,
+
+    /// The settings the tree was built from. Requests handled with this tree
+    /// use these settings, so that they are replaced along with the providers.
+    settings: Arc<Settings>,
+}
+
+/// A top level provider in a [`ProviderTree`].
+struct ProviderEntry {
+    /// The name the provider is configured with.
+    name: String,
+
+    /// The experiment variant that uses this provider, or `None` for the
+    /// providers in `Settings::suggestion_providers`.
+    variant: Option<Assignment>,
+}
+
+impl ProviderTree {
+    /// The settings the tree was built from.
+    pub(crate) fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Get suggestions for a request assigned to the experiment variants in
+    /// `assignments`, from the top level providers named in `selected`, or from
+    /// all of them if it is `None`.
+    ///
+    /// For each name, the provider of the first assigned variant that replaces
+    /// it is used. Otherwise the provider from `Settings::suggestion_providers`
+    /// is used.
+    async fn suggest_from(
+        &self,
+        request: SuggestionRequest,
+        selected: Option<&[String]>,
+        assignments: &[Assignment],
+    ) -> Result<SuggestionResponse, SuggestError> {
+        self.multi
+            .suggest_from_some(request, |index| {
+                let entry = &self.entries[index];
+                if let Some(selected) = selected {
+                    if !selected.contains(&entry.name) {
+                        return false;
+                    }
+                }
+                let replacement = assignments.iter().find(|assignment| {
+                    self.entries.iter().any(|other| {
+                        other.name == entry.name && other.variant.as_ref() == Some(*assignment)
+                    })
+                });
+                entry.variant.as_ref() == replacement
+            })
+            .await
+    }
+
+    /// Check that a client may select each of the providers named in
+    /// `requested`, and return the selection. Requesting no providers selects
+    /// all of them.
+    ///
+    /// # Errors
+    /// If a provider is not configured, or is not in the selectable providers
+    /// allowlist.
+    fn select<'a>(&self, requested: &'a [String]) -> Result<Option<&'a [String]>, HandlerError> {
+        if requested.is_empty() {
+            return Ok(None);
+        }
+        for name in requested {
+            if !self.entries.iter().any(|entry| &entry.name == name) {
+                return Err(HandlerError::UnknownProvider(name.clone()));
+            }
+            if !self
+                .settings
+                .suggest_api
+                .selectable_providers
+                .contains(name)
+            {
+                return Err(HandlerError::ProviderNotAllowed(name.clone()));
+            }
+        }
+        Ok(Some(requested))
+    }
+}
+
+impl Deref for ProviderTree {
+    type Target = Multi;
+
+    fn deref(&self) -> &Self::Target {
+        &self.multi
+    }
+}
+
+/// The SuggestionProvider stored in Actix's app_data, shared by every worker.
+///
+/// The provider tree is built when it is first needed, and can be replaced
+/// while Merino is running with [`SuggestionProviderRef::reload`]. The tree
+/// holds the settings it was built from, so requests see the providers and
+/// settings of a reload change together.
+pub(crate) struct SuggestionProviderRef {
+    /// The provider tree that is serving requests, once one has been built.
+    current: ArcSwapOption<ProviderTree>,
+
+    /// Held while a provider tree is being built, so that only one is built at
+    /// a time.
+    build_lock: Mutex<()>,
+
+    /// Whether each top level provider, by name, has been built.
+    readiness: std::sync::Mutex<HashMap<String, bool>>,
+}
 
 impl SuggestionProviderRef {
+    /// Create a reference with no provider tree built yet.
+    pub(crate) fn new() -> Self {
+        Self {
+            current: ArcSwapOption::empty(),
+            build_lock: Mutex::new(()),
+            readiness: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check if a provider tree has been built and is serving requests.
+    pub(crate) fn is_ready(&self) -> bool {
+        self.current.load().is_some()
+    }
+
+    /// The provider tree that is serving requests, without building one if
+    /// there is none yet.
+    pub(crate) fn current(&self) -> Option<Arc<ProviderTree>> {
+        self.current.load_full()
+    }
+
+    /// The settings of the provider tree that is serving requests, or
+    /// `startup` if none has been built yet.
+    pub(crate) fn current_settings(&self, startup: &Data<Settings>) -> Arc<Settings> {
+        match self.current.load().as_ref() {
+            Some(provider) => provider.settings.clone(),
+            None => startup.clone().into_inner(),
+        }
+    }
+
+    /// Whether each top level provider, by name, has been built.
+    ///
+    /// While the first provider tree is being built, this shows its progress.
+    /// Afterwards, it lists the providers of the tree serving requests.
+    pub(crate) fn provider_readiness(&self) -> HashMap<String, bool> {
+        self.readiness.lock().expect("mutex was poisoned").clone()
+    }
+
     /// Get the provider, or create a new one if it doesn't exist.
-    async fn get_or_try_init(&self, settings: &Settings) -> anyhow::Result<&merino_suggest::Multi> {
+    async fn get_or_try_init(
+        &self,
+        settings: &Settings,
+        metrics_client: &StatsdClient,
+    ) -> anyhow::Result<Arc<ProviderTree>> {
+        if let Some(provider) = self.current.load_full() {
+            return Ok(provider);
+        }
+
+        let _build_guard = self.build_lock.lock().await;
+        // Another request may have built the tree while this one waited.
+        if let Some(provider) = self.current.load_full() {
+            return Ok(provider);
+        }
+
+        let provider = Arc::new(self.build(settings, metrics_client).await?);
+        self.current.store(Some(provider.clone()));
+        Ok(provider)
+    }
+
+    /// Like [`SuggestionProviderRef::get_or_try_init`], but with errors
+    /// logged and mapped to the error to respond to the request with.
+    async fn get_or_init_for_request(
+        &self,
+        settings: &Settings,
+        metrics_client: &StatsdClient,
+    ) -> Result<Arc<ProviderTree>, HandlerError> {
+        self.get_or_try_init(settings, metrics_client)
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    ?error,
+                    r#type = "web.suggest.setup-error",
+                    "suggester error"
+                );
+                HandlerError::Internal
+            })
+    }
+
+    /// Build a new provider tree from `settings` and swap it in for the
+    /// current one, along with `settings`. Requests that are already in
+    /// progress finish with the previous tree and settings.
+    ///
+    /// # Errors
+    /// If the new tree cannot be built. The current tree is kept in that case.
+    pub(crate) async fn reload(
+        &self,
+        settings: &Settings,
+        metrics_client: &StatsdClient,
+    ) -> anyhow::Result<()> {
+        let _build_guard = self.build_lock.lock().await;
+        let provider = self.build(settings, metrics_client).await?;
+        self.current.store(Some(Arc::new(provider)));
+        Ok(())
+    }
+
+    /// Build the tree of every provider in `settings.suggestion_providers`.
+    ///
+    /// Readiness is updated as each provider is built if no tree is serving
+    /// requests yet, and for every provider once the whole tree is built.
+    async fn build(
+        &self,
+        settings: &Settings,
+        metrics_client: &StatsdClient,
+    ) -> anyhow::Result<ProviderTree> {
         let setup_span = tracing::info_span!("suggestion_provider_setup");
-        self.0
-            .get_or_try_init(|| {
-                async {
-                    tracing::info!(
-                        r#type = "web.configuring-suggesters",
-                        "Setting up suggestion providers"
-                    );
+        async {
+            tracing::info!(
+                r#type = "web.configuring-suggesters",
+                "Setting up suggestion providers"
+            );
 
-                    let mut providers: Vec<Box<dyn SuggestionProvider>> =
-                        Vec::with_capacity(settings.suggestion_providers.len());
-                    for config in settings.suggestion_providers.values() {
-                        providers.push(make_provider_tree(settings, config).await?);
-                    }
+            if !settings.multiplexer.providers.is_empty() {
+                anyhow::bail!(
+                    "multiplexer.providers must be empty, use suggestion_providers instead"
+                );
+            }
 
-                    let multi = merino_suggest::Multi::new(providers);
-                    Ok(multi)
+            let track_progress = !self.is_ready();
+            if track_progress {
+                self.set_readiness(settings, false);
+            }
+
+            let mut providers: Vec<Box<dyn SuggestionProvider>> =
+                Vec::with_capacity(settings.suggestion_providers.len());
+            let mut entries = Vec::with_capacity(settings.suggestion_providers.len());
+            for (name, config) in &settings.suggestion_providers {
+                providers.push(make_provider_tree(settings, config, metrics_client).await?);
+                entries.push(ProviderEntry {
+                    name: name.clone(),
+                    variant: None,
+                });
+                if track_progress {
+                    self.readiness
+                        .lock()
+                        .expect("mutex was poisoned")
+                        .insert(name.clone(), true);
                 }
-                .instrument(setup_span)
+            }
+
+            for (experiment, experiment_settings) in &settings.experiments {
+                for variant in &experiment_settings.variants {
+                    for (name, config) in &variant.suggestion_providers {
+                        providers.push(make_provider_tree(settings, config, metrics_client).await?);
+                        entries.push(ProviderEntry {
+                            name: name.clone(),
+                            variant: Some(Assignment {
+                                experiment: experiment.clone(),
+                                variant: variant.name.clone(),
+                            }),
+                        });
+                    }
+                }
+            }
+            self.set_readiness(settings, true);
+
+            Ok(ProviderTree {
+                multi: Multi::new(&settings.multiplexer, providers, metrics_client.clone()),
+                entries,
+                settings: Arc::new(settings.clone()),
             })
-            .await
+        }
+        .instrument(setup_span)
+        .await
+    }
+
+    /// Mark every provider in `settings.suggestion_providers` as `ready` or not,
+    /// forgetting any other providers.
+    fn set_readiness(&self, settings: &Settings, ready: bool) {
+        *self.readiness.lock().expect("mutex was poisoned") = settings
+            .suggestion_providers
+            .keys()
+            .map(|name| (name.clone(), ready))
+            .collect();
     }
 }
 
+/// Spawn a task that builds the provider tree in `providers`, so that it is
+/// ready before the first suggestion request. If building fails, it is retried
+/// after `settings.provider_startup.retry_interval`.
+pub(crate) fn spawn_eager_setup(
+    providers: Data<SuggestionProviderRef>,
+    settings: &Settings,
+    metrics_client: StatsdClient,
+) {
+    let settings = settings.clone();
+    tokio::spawn(async move {
+        while let Err(error) = providers.get_or_try_init(&settings, &metrics_client).await {
+            tracing::error!(
+                ?error,
+                r#type = "web.suggest.setup-error",
+                "Could not set up suggestion providers at startup, retrying"
+            );
+            tokio::time::sleep(settings.provider_startup.retry_interval).await;
+        }
+    });
+}
+
 /// Recursive helper to build a tree of providers.
+///
+/// Every provider in the tree is wrapped in [`Instrumented`], so that each
+/// layer reports its own latency, errors, and cache status.
 #[async_recursion]
 async fn make_provider_tree(
     settings: &Settings,
     config: &SuggestionProviderConfig,
+    metrics_client: &StatsdClient,
 ) -> Result<Box<dyn SuggestionProvider>> {
     let provider: Box<dyn SuggestionProvider> = match config {
         SuggestionProviderConfig::RemoteSettings(rs_config) => {
             RemoteSettingsSuggester::new_boxed(settings, rs_config).await?
         }
 
+        SuggestionProviderConfig::AdmServerSide(adm_config) => {
+            AdmServerSideSuggester::new_boxed(adm_config)?
+        }
+
         SuggestionProviderConfig::MemoryCache(memory_config) => {
-            let inner = make_provider_tree(settings, memory_config.inner.as_ref()).await?;
-            MemoryCacheSuggester::new_boxed(memory_config, inner)
+            let inner =
+                make_provider_tree(settings, memory_config.inner.as_ref(), metrics_client).await?;
+            MemoryCacheSuggester::new_boxed(memory_config, inner, metrics_client.clone())
         }
 
         SuggestionProviderConfig::RedisCache(redis_config) => {
-            let inner = make_provider_tree(settings, redis_config.inner.as_ref()).await?;
-            RedisCacheSuggester::new_boxed(settings, redis_config, inner).await?
+            let inner =
+                make_provider_tree(settings, redis_config.inner.as_ref(), metrics_client).await?;
+            RedisCacheSuggester::new_boxed(settings, redis_config, inner, metrics_client.clone())
+                .await?
         }
 
         SuggestionProviderConfig::Multiplexer(multi_config) => {
             let mut providers = Vec::new();
             for config in &multi_config.providers {
-                providers.push(make_provider_tree(settings, config).await?);
+                providers.push(make_provider_tree(settings, config, metrics_client).await?);
             }
-            Multi::new_boxed(providers)
+            Multi::new_boxed(multi_config, providers, metrics_client.clone())
+        }
+
+        SuggestionProviderConfig::DeviceTargeting(targeting_config) => {
+            let inner =
+                make_provider_tree(settings, targeting_config.inner.as_ref(), metrics_client)
+                    .await?;
+            DeviceTargeting::new_boxed(targeting_config, inner)?
         }
 
         SuggestionProviderConfig::Debug => DebugProvider::new_boxed(settings)?,
         SuggestionProviderConfig::WikiFruit => WikiFruit::new_boxed(settings)?,
         SuggestionProviderConfig::Null => Box::new(NullProvider),
     };
+    let provider: Box<dyn SuggestionProvider> =
+        Instrumented::new_boxed(provider, metrics_client.clone());
     Ok(provider)
 }
 
@@ -215,20 +797,35 @@ impl<'a> Serialize for SuggestionWrapper<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::make_provider_tree;
+    use super::{
+        configure, etag_matches, make_provider_tree, BatchSuggestItem, SuggestionProviderRef,
+    };
+    use crate::extractors::SuggestionContext;
+    use actix_web::{http::StatusCode, test, web, web::Data, App};
+    use actix_web_location::LocationConfig;
     use anyhow::Result;
+    use cadence::{NopMetricSink, StatsdClient};
     use merino_settings::{
         providers::{
-            MemoryCacheConfig, MultiplexerConfig, RedisCacheConfig, SuggestionProviderConfig,
+            AdmServerSideConfig, DeviceTargetingConfig, DeviceTargetingRule, MemoryCacheConfig,
+            MultiplexerConfig, ProviderFailurePolicy, RedisCacheConfig, SuggestionProviderConfig,
+            SuggestionSelector, TargetingAction,
         },
-        Settings,
+        ExperimentSettings, ExperimentVariantSettings, Settings,
     };
+    use merino_suggest::{
+        device_info::{Browser, DeviceInfo, FormFactor, OsFamily},
+        LanguageIdentifier, SuggestError, SuggestionProvider, SuggestionRequest,
+    };
+    use serde_json::{json, Value};
+    use std::{collections::HashMap, time::Duration};
 
     #[tokio::test]
     async fn test_providers_single() -> Result<()> {
         let settings = Settings::load_for_tests();
         let config = SuggestionProviderConfig::Null;
-        let provider_tree = make_provider_tree(&settings, &config).await?;
+        let metrics_client = StatsdClient::from_sink("merino", NopMetricSink);
+        let provider_tree = make_provider_tree(&settings, &config, &metrics_client).await?;
         assert_eq!(provider_tree.name(), "NullProvider");
         Ok(())
     }
@@ -250,13 +847,516 @@ mod tests {
                 }),
                 SuggestionProviderConfig::Null,
             ],
+            ..Default::default()
         });
 
-        let provider_tree = make_provider_tree(&settings, &config).await?;
+        let metrics_client = StatsdClient::from_sink("merino", NopMetricSink);
+        let provider_tree = make_provider_tree(&settings, &config, &metrics_client).await?;
         assert_eq!(
             provider_tree.name(),
             "Multi(NullProvider, RedisCache(MemoryCache(WikiFruit)), NullProvider)"
         );
         Ok(())
     }
+
+    /// Configure an app to serve the suggest API at `api/v1/suggest` with
+    /// `settings`. Location lookup and metrics use defaults that do nothing,
+    /// and the providers are built by the first request.
+    fn suggest_api(settings: Settings) -> impl FnOnce(&mut web::ServiceConfig) {
+        move |config| {
+            config
+                .app_data(Data::new(settings))
+                .app_data(Data::new(LocationConfig::default()))
+                .app_data(Data::new(StatsdClient::from_sink("merino", NopMetricSink)))
+                .app_data(Data::new(SuggestionProviderRef::new()))
+                .service(web::scope("api/v1/suggest").configure(configure));
+        }
+    }
+
+    /// Settings with a single top level provider.
+    fn settings_with_provider(config: SuggestionProviderConfig) -> Settings {
+        let mut settings = Settings::load_for_tests();
+        settings.debug = true;
+        settings.suggestion_providers = vec![("test".to_string(), config)].into_iter().collect();
+        settings
+    }
+
+    /// A provider config that fails to build.
+    fn invalid_provider() -> SuggestionProviderConfig {
+        SuggestionProviderConfig::DeviceTargeting(DeviceTargetingConfig {
+            rules: vec![DeviceTargetingRule {
+                action: TargetingAction::Drop,
+                applies_to: SuggestionSelector::All,
+                min_firefox_version: None,
+                form_factors: Some(vec!["smartwatch".to_string()]),
+                os_families: None,
+            }],
+            inner: Box::new(SuggestionProviderConfig::WikiFruit),
+        })
+    }
+
+    #[tokio::test]
+    async fn reload_replaces_providers() -> Result<()> {
+        let metrics_client = StatsdClient::from_sink("merino", NopMetricSink);
+        let providers = SuggestionProviderRef::new();
+
+        let settings = settings_with_provider(SuggestionProviderConfig::Null);
+        let before = providers
+            .get_or_try_init(&settings, &metrics_client)
+            .await?;
+        assert_eq!(before.name(), "Multi(NullProvider)");
+
+        let settings = settings_with_provider(SuggestionProviderConfig::WikiFruit);
+        providers.reload(&settings, &metrics_client).await?;
+        let after = providers
+            .get_or_try_init(&settings, &metrics_client)
+            .await?;
+        assert_eq!(after.name(), "Multi(WikiFruit)");
+
+        // Requests that already had the previous tree can keep using it.
+        assert_eq!(before.name(), "Multi(NullProvider)");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_replaces_settings_with_providers() -> Result<()> {
+        let metrics_client = StatsdClient::from_sink("merino", NopMetricSink);
+        let providers = SuggestionProviderRef::new();
+        let startup = Data::new(settings_with_provider(SuggestionProviderConfig::Null));
+        assert_eq!(
+            providers
+                .current_settings(&startup)
+                .suggest_api
+                .max_batch_size,
+            startup.suggest_api.max_batch_size
+        );
+
+        providers.get_or_try_init(&startup, &metrics_client).await?;
+        let mut settings = settings_with_provider(SuggestionProviderConfig::WikiFruit);
+        settings.suggest_api.max_batch_size = startup.suggest_api.max_batch_size + 1;
+        settings.suggest_api.selectable_providers = vec!["test".to_string()];
+        providers.reload(&settings, &metrics_client).await?;
+
+        let current = providers.current().expect("providers were built");
+        assert_eq!(current.name(), "Multi(WikiFruit)");
+        assert_eq!(
+            current.settings().suggest_api.max_batch_size,
+            settings.suggest_api.max_batch_size
+        );
+        assert_eq!(
+            providers
+                .current_settings(&startup)
+                .suggest_api
+                .max_batch_size,
+            settings.suggest_api.max_batch_size
+        );
+        assert_eq!(
+            current.select(&["test".to_string()])?,
+            Some(&["test".to_string()][..])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_reload_keeps_previous_providers() -> Result<()> {
+        let metrics_client = StatsdClient::from_sink("merino", NopMetricSink);
+        let providers = SuggestionProviderRef::new();
+
+        let settings = settings_with_provider(SuggestionProviderConfig::Null);
+        providers
+            .get_or_try_init(&settings, &metrics_client)
+            .await?;
+
+        let invalid = settings_with_provider(invalid_provider());
+        assert!(providers.reload(&invalid, &metrics_client).await.is_err());
+
+        let current = providers.get_or_try_init(&invalid, &metrics_client).await?;
+        assert_eq!(current.name(), "Multi(NullProvider)");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn readiness_tracks_built_providers() -> Result<()> {
+        let metrics_client = StatsdClient::from_sink("merino", NopMetricSink);
+        let providers = SuggestionProviderRef::new();
+        assert!(!providers.is_ready());
+
+        let mut settings = settings_with_provider(SuggestionProviderConfig::Null);
+        settings
+            .suggestion_providers
+            .insert("broken".to_string(), invalid_provider());
+        assert!(providers
+            .get_or_try_init(&settings, &metrics_client)
+            .await
+            .is_err());
+        assert!(!providers.is_ready());
+        assert_eq!(providers.provider_readiness().get("broken"), Some(&false));
+
+        settings.suggestion_providers.remove("broken");
+        providers
+            .get_or_try_init(&settings, &metrics_client)
+            .await?;
+        assert!(providers.is_ready());
+        assert_eq!(
+            providers.provider_readiness(),
+            vec![("test".to_string(), true)].into_iter().collect()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn top_level_provider_timeout_is_enforced() -> Result<()> {
+        // Accepts connections, but never responds to requests.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let mut settings = settings_with_provider(SuggestionProviderConfig::AdmServerSide(
+            AdmServerSideConfig {
+                endpoint_url: Some(format!("http://{}", listener.local_addr()?).parse()?),
+                partner: "test_partner".to_string(),
+                timeout: Duration::from_secs(60),
+                ..AdmServerSideConfig::default()
+            },
+        ));
+        settings.multiplexer.provider_timeout = Some(Duration::from_millis(50));
+        settings.multiplexer.on_provider_failure = ProviderFailurePolicy::Fail;
+
+        let metrics_client = StatsdClient::from_sink("merino", NopMetricSink);
+        let providers = SuggestionProviderRef::new();
+        let tree = providers
+            .get_or_try_init(&settings, &metrics_client)
+            .await?;
+        let request = SuggestionRequest {
+            query: "apple".to_string(),
+            accepted_locales: vec!["en-US".parse()?],
+            country: Some("US".to_string()),
+            region: Some("OR".to_string()),
+            dma: None,
+            city: None,
+            device_info: DeviceInfo {
+                os_family: OsFamily::Linux,
+                form_factor: FormFactor::Desktop,
+                browser: Browser::Firefox(91),
+            },
+        };
+
+        let result = tokio::time::timeout(Duration::from_secs(5), tree.suggest(request))
+            .await
+            .expect("the top level provider timeout was not enforced");
+        assert!(matches!(result, Err(SuggestError::Timeout(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn top_level_multiplexer_may_not_have_providers() {
+        let mut settings = settings_with_provider(SuggestionProviderConfig::Null);
+        settings.multiplexer.providers = vec![SuggestionProviderConfig::Null];
+        let metrics_client = StatsdClient::from_sink("merino", NopMetricSink);
+        assert!(SuggestionProviderRef::new()
+            .get_or_try_init(&settings, &metrics_client)
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn batch_results_are_in_order() {
+        let mut settings = settings_with_provider(SuggestionProviderConfig::WikiFruit);
+        settings.suggest_api.max_query_length = 10;
+        let app = test::init_service(App::new().configure(suggest_api(settings))).await;
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/suggest/batch")
+            .set_json(&json!({
+                "requests": [
+                    {"q": "cherry"},
+                    {"q": "apple", "locale": "fr-CA", "location": {"country": "CA"}},
+                    {"q": "a very long query"},
+                    {"q": "banana", "locale": "en;q=nope"},
+                ]
+            }))
+            .to_request();
+        let body: Value = test::read_response_json(&app, request).await;
+
+        let results = body["results"].as_array().unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0]["suggestions"][0]["title"], "Wikipedia - Cherry");
+        assert_eq!(results[1]["suggestions"][0]["title"], "Wikipedia - Apple");
+        assert_eq!(results[2]["error"]["code"], "query-too-long");
+        assert_eq!(results[3]["error"]["code"], "invalid-field");
+    }
+
+    #[actix_rt::test]
+    async fn oversized_and_malformed_batches_are_rejected() {
+        let mut settings = settings_with_provider(SuggestionProviderConfig::WikiFruit);
+        settings.suggest_api.max_batch_size = 1;
+        let app = test::init_service(App::new().configure(suggest_api(settings))).await;
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/suggest/batch")
+            .set_json(&json!({"requests": [{"q": "apple"}, {"q": "banana"}]}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "batch-too-large");
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/suggest/batch")
+            .set_json(&json!({"requests": [{"query": "apple"}]}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "malformed-body");
+    }
+
+    #[test]
+    fn batch_items_override_the_request_context() -> Result<()> {
+        let settings = Settings::load_for_tests();
+        let context = SuggestionContext {
+            accepted_locales: vec![LanguageIdentifier::Wildcard],
+            country: Some("US".to_string()),
+            region: Some("OR".to_string()),
+            dma: Some(820),
+            city: Some("Portland".to_string()),
+            device_info: DeviceInfo {
+                os_family: OsFamily::Linux,
+                form_factor: FormFactor::Desktop,
+                browser: Browser::Firefox(91),
+            },
+        };
+
+        let item: BatchSuggestItem = serde_json::from_value(json!({"q": "  Apple"}))?;
+        let request = item.to_request(&context, &settings)?;
+        assert_eq!(request.query, "apple");
+        assert_eq!(request.accepted_locales, context.accepted_locales);
+        assert_eq!(request.city, context.city);
+
+        let item: BatchSuggestItem = serde_json::from_value(json!({
+            "q": "apple",
+            "locale": "fr-CA",
+            "location": {"country": "CA", "region": "QC"},
+        }))?;
+        let request = item.to_request(&context, &settings)?;
+        assert_eq!(
+            request.accepted_locales,
+            vec![LanguageIdentifier::Locale {
+                language: "fr".to_string(),
+                region: Some("ca".to_string())
+            }]
+        );
+        assert_eq!(request.country.as_deref(), Some("CA"));
+        assert_eq!(request.region.as_deref(), Some("QC"));
+        assert_eq!(request.dma, None);
+        assert_eq!(request.city, None);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn requests_can_select_providers() {
+        let mut settings = settings_with_provider(SuggestionProviderConfig::WikiFruit);
+        settings
+            .suggestion_providers
+            .insert("debug".to_string(), SuggestionProviderConfig::Debug);
+        settings.suggest_api.selectable_providers = vec!["test".to_string()];
+        let app = test::init_service(App::new().configure(suggest_api(settings))).await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=apple")
+            .to_request();
+        let body: Value = test::read_response_json(&app, request).await;
+        assert_eq!(body["suggestions"].as_array().unwrap().len(), 2);
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=apple&providers=test")
+            .to_request();
+        let body: Value = test::read_response_json(&app, request).await;
+        let suggestions = body["suggestions"].as_array().unwrap();
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0]["title"], "Wikipedia - Apple");
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=apple&providers=test,debug")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "provider-not-allowed");
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=apple&providers=unknown")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "unknown-provider");
+    }
+
+    #[actix_rt::test]
+    async fn experiment_variants_replace_providers() {
+        let mut settings = settings_with_provider(SuggestionProviderConfig::WikiFruit);
+        settings.experiments = vec![
+            (
+                "debugging".to_string(),
+                ExperimentSettings {
+                    variants: vec![ExperimentVariantSettings {
+                        name: "debug-treatment".to_string(),
+                        traffic_percentage: 100.0,
+                        suggestion_providers: vec![(
+                            "test".to_string(),
+                            SuggestionProviderConfig::Debug,
+                        )]
+                        .into_iter()
+                        .collect(),
+                    }],
+                },
+            ),
+            (
+                "disabled".to_string(),
+                ExperimentSettings {
+                    variants: vec![ExperimentVariantSettings {
+                        name: "never".to_string(),
+                        traffic_percentage: 0.0,
+                        suggestion_providers: HashMap::new(),
+                    }],
+                },
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let app = test::init_service(App::new().configure(suggest_api(settings))).await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=apple&bucket_id=abc")
+            .to_request();
+        let body: Value = test::read_response_json(&app, request).await;
+        assert_eq!(body["server_variants"], json!(["debug-treatment"]));
+        let suggestions = body["suggestions"].as_array().unwrap();
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0]["provider"], "Merino::Debug");
+
+        // Batches don't take part in experiments.
+        let request = test::TestRequest::post()
+            .uri("/api/v1/suggest/batch")
+            .set_json(&json!({"requests": [{"q": "apple"}]}))
+            .to_request();
+        let body: Value = test::read_response_json(&app, request).await;
+        assert_eq!(
+            body["results"][0]["suggestions"][0]["title"],
+            "Wikipedia - Apple"
+        );
+    }
+
+    #[test]
+    fn etags_are_compared_weakly() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"xyz\", \"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"xyz\"", "\"abc\""));
+    }
+
+    #[actix_rt::test]
+    async fn responses_are_cacheable() {
+        let settings =
+            settings_with_provider(SuggestionProviderConfig::MemoryCache(MemoryCacheConfig {
+                default_ttl: Duration::from_secs(300),
+                inner: Box::new(SuggestionProviderConfig::WikiFruit),
+                ..Default::default()
+            }));
+        let app = test::init_service(App::new().configure(suggest_api(settings))).await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=apple")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let max_age = response
+            .headers()
+            .get("cache-control")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .strip_prefix("max-age=")
+            .expect("response should have a max-age")
+            .parse::<u64>()
+            .unwrap();
+        assert!(max_age > 0 && max_age <= 300);
+        assert_eq!(
+            response.headers().get("vary").unwrap(),
+            "Accept-Language, User-Agent"
+        );
+        let etag = response.headers().get("etag").unwrap().clone();
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=apple")
+            .insert_header(("If-None-Match", etag.clone()))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get("etag").unwrap(), etag);
+        assert!(test::read_body(response).await.is_empty());
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=banana")
+            .insert_header(("If-None-Match", etag.clone()))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers().get("etag").unwrap(), etag);
+    }
+
+    #[actix_rt::test]
+    async fn responses_with_experiments_are_private() {
+        let mut settings =
+            settings_with_provider(SuggestionProviderConfig::MemoryCache(MemoryCacheConfig {
+                default_ttl: Duration::from_secs(300),
+                inner: Box::new(SuggestionProviderConfig::WikiFruit),
+                ..Default::default()
+            }));
+        settings.experiments = vec![(
+            "everyone".to_string(),
+            ExperimentSettings {
+                variants: vec![ExperimentVariantSettings {
+                    name: "treatment".to_string(),
+                    traffic_percentage: 100.0,
+                    suggestion_providers: HashMap::new(),
+                }],
+            },
+        )]
+        .into_iter()
+        .collect();
+        let app = test::init_service(App::new().configure(suggest_api(settings))).await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=apple&bucket_id=abc")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cache_control = response
+            .headers()
+            .get("cache-control")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(
+            cache_control.starts_with("private, max-age="),
+            "unexpected cache-control {}",
+            cache_control
+        );
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["server_variants"], json!(["treatment"]));
+    }
+
+    #[actix_rt::test]
+    async fn responses_without_a_ttl_are_revalidated() {
+        let settings = settings_with_provider(SuggestionProviderConfig::WikiFruit);
+        let app = test::init_service(App::new().configure(suggest_api(settings))).await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/suggest?q=apple")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.headers().get("cache-control").unwrap(), "no-cache");
+        assert!(response.headers().contains_key("etag"));
+    }
 }
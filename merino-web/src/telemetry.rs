@@ -0,0 +1,107 @@
+//! Exporting the spans Merino records to an OpenTelemetry collector.
+
+use anyhow::{Context, Result};
+use merino_settings::TracingSettings;
+use opentelemetry::{
+    runtime::Tokio,
+    sdk::{
+        self,
+        trace::{BatchSpanProcessor, Tracer, TracerProvider},
+        Resource,
+    },
+    trace::TracerProvider as _,
+    KeyValue,
+};
+use opentelemetry_otlp::{ExporterConfig, TonicConfig, TraceExporter};
+use tokio::runtime::Runtime;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Exports spans to an OpenTelemetry collector over OTLP/gRPC.
+///
+/// Spans are only exported while this is alive. When it is dropped, any spans
+/// that have not been exported yet are sent before it returns.
+pub struct SpanExporter {
+    /// The provider of the tracer that [`SpanExporter::layer`] records spans
+    /// with. Tracers do not keep their provider alive.
+    provider: Option<TracerProvider>,
+
+    /// The runtime that spans are sent from.
+    ///
+    /// The exporter gets its own runtime, so that shutting it down from a
+    /// single threaded runtime, such as Actix's, can't block the export.
+    runtime: Option<Runtime>,
+}
+
+impl SpanExporter {
+    /// Create an exporter for the collector in `settings`.
+    ///
+    /// Returns `None` if no collector is configured.
+    ///
+    /// # Errors
+    /// If the collector endpoint is not a valid URI, or the runtime to send
+    /// spans from can't be started.
+    pub fn from_settings(settings: &TracingSettings) -> Result<Option<Self>> {
+        let endpoint = match &settings.otlp_endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => return Ok(None),
+        };
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("span-exporter")
+            .enable_all()
+            .build()
+            .context("starting span exporter runtime")?;
+        let _runtime_guard = runtime.enter();
+
+        let exporter = TraceExporter::new_tonic(
+            ExporterConfig {
+                endpoint,
+                ..ExporterConfig::default()
+            },
+            TonicConfig::default(),
+        )
+        .context("configuring OTLP exporter")?;
+        let processor = BatchSpanProcessor::builder(exporter, Tokio)
+            .with_scheduled_delay(settings.export_interval)
+            .build();
+        let provider =
+            TracerProvider::builder()
+                .with_batch_exporter(processor)
+                .with_config(sdk::trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", settings.service_name.clone()),
+                ])))
+                .build();
+
+        Ok(Some(Self {
+            provider: Some(provider),
+            runtime: Some(runtime),
+        }))
+    }
+
+    /// Make a layer that records spans for this exporter to export.
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, Tracer>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let tracer = self
+            .provider
+            .as_ref()
+            .expect("span exporter was shut down")
+            .get_tracer("merino", Some(env!("CARGO_PKG_VERSION")));
+        tracing_opentelemetry::layer().with_tracer(tracer)
+    }
+}
+
+impl Drop for SpanExporter {
+    fn drop(&mut self) {
+        // Dropping the provider exports the remaining spans, which needs the
+        // runtime to still be running.
+        drop(self.provider.take());
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
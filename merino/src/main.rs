@@ -18,7 +18,8 @@ mod sentry;
 
 use anyhow::{Context, Result};
 use cadence::{BufferedUdpMetricSink, CountedExt, QueuingMetricSink, StatsdClient};
-use merino_settings::{LogFormat, Settings};
+use merino_settings::{LogFormat, MetricsMode, Settings};
+use merino_web::{PrometheusSink, SpanExporter};
 use std::net::{TcpListener, UdpSocket};
 use tracing::Level;
 use tracing_actix_web_mozlog::{JsonStorageLayer, MozLogFormatLayer};
@@ -31,7 +32,7 @@ use viaduct_reqwest::ReqwestBackend;
 async fn main() -> Result<()> {
     let settings = merino_settings::Settings::load().context("Loading settings")?;
     let _sentry_guard = crate::sentry::init_sentry(&settings).context("initializing sentry")?;
-    init_logging(&settings).context("initializing logging")?;
+    let _span_exporter = init_logging(&settings).context("initializing logging")?;
     let metrics_client = init_metrics(&settings).context("initializing metrics")?;
 
     viaduct::set_backend(&ReqwestBackend).context("setting viaduct backend")?;
@@ -46,9 +47,14 @@ async fn main() -> Result<()> {
 }
 
 /// Set up logging for Merino, based on settings and the `RUST_LOG` environment variable.
-fn init_logging(settings: &Settings) -> Result<()> {
+///
+/// If a collector is configured, spans are also exported to it for as long as
+/// the returned exporter is alive.
+fn init_logging(settings: &Settings) -> Result<Option<SpanExporter>> {
     LogTracer::init()?;
     let env_filter: EnvFilter = (&settings.logging.levels).into();
+    let span_exporter =
+        SpanExporter::from_settings(&settings.tracing).context("setting up span exporter")?;
 
     match settings.logging.format {
         LogFormat::Pretty => {
@@ -56,6 +62,7 @@ fn init_logging(settings: &Settings) -> Result<()> {
                 .pretty()
                 .with_max_level(Level::TRACE)
                 .finish()
+                .with(span_exporter.as_ref().map(SpanExporter::layer))
                 .with(env_filter);
             tracing::subscriber::set_global_default(subscriber)?;
         }
@@ -64,6 +71,7 @@ fn init_logging(settings: &Settings) -> Result<()> {
                 .with_level(true)
                 .with_max_level(Level::TRACE)
                 .finish()
+                .with(span_exporter.as_ref().map(SpanExporter::layer))
                 .with(env_filter);
             tracing::subscriber::set_global_default(subscriber)?;
         }
@@ -71,6 +79,7 @@ fn init_logging(settings: &Settings) -> Result<()> {
             let subscriber = tracing_subscriber::registry()
                 .with(JsonStorageLayer)
                 .with(MozLogFormatLayer::new("merino", std::io::stdout))
+                .with(span_exporter.as_ref().map(SpanExporter::layer))
                 .with(env_filter);
             tracing::subscriber::set_global_default(subscriber)?;
         }
@@ -79,12 +88,30 @@ fn init_logging(settings: &Settings) -> Result<()> {
     let _span_guard = tracing::debug_span!("init_logging").entered();
     tracing::debug!("logging set up");
 
-    Ok(())
+    Ok(span_exporter)
 }
 
 #[tracing::instrument(level = "DEBUG", skip(settings))]
 /// Set up metrics for Merino, based on settings.
 fn init_metrics(settings: &Settings) -> Result<StatsdClient> {
+    let client = match settings.metrics.mode {
+        MetricsMode::Statsd => init_statsd_metrics(settings)?,
+        MetricsMode::Prometheus => {
+            tracing::debug!("metrics set up, served at /__metrics__");
+            StatsdClient::from_sink("merino", PrometheusSink)
+        }
+    };
+
+    // Test the newly made metrics client
+    client
+        .incr("startup")
+        .context("Sending startup metrics ping")?;
+
+    Ok(client)
+}
+
+/// Set up a metrics client that sends metrics to statsd.
+fn init_statsd_metrics(settings: &Settings) -> Result<StatsdClient> {
     // We'll only be sending on this socket, so the host and port don't matter.
     let socket = UdpSocket::bind("0.0.0.0:0").context("creating metrics socket")?;
     socket
@@ -108,13 +135,6 @@ fn init_metrics(settings: &Settings) -> Result<StatsdClient> {
         QueuingMetricSink::with_capacity(udp_sink, queue_size)
     };
 
-    let client = StatsdClient::from_sink("merino", sink);
-
-    // Test the newly made metrics client
-    client
-        .incr("startup")
-        .context("Sending startup metrics ping")?;
-
     tracing::debug!(sink_address=?settings.metrics.sink_address, ?queue_size, "metrics set up");
-    Ok(client)
+    Ok(StatsdClient::from_sink("merino", sink))
 }